@@ -2,6 +2,8 @@
 // CSS class.
 
 let wt, streamNumber, datagramWriter;
+// send times of the datagrams of a measurement on /stamped, by sequence number.
+let probes = null;
 
 function disconnect() {
   if (wt) {
@@ -68,12 +70,36 @@ async function sendData() {
 
         break;
       }
+      case "rpc": {
+        // {"method": ..., "params": ...} with its length (4 bytes, big endian) on /rpc.
+        const message = new Uint8Array(4 + data.byteLength);
+        new DataView(message.buffer).setUint32(0, data.byteLength);
+        message.set(data, 4);
+        const duplexStream = await wt.createBidirectionalStream();
+        const writer = duplexStream.writable.getWriter();
+        await writer.write(message);
+        await writer.close();
+        addToEventLog(`Called: ${rawData}`);
+        readReply(duplexStream.readable);
+        break;
+      }
     }
   } catch (e) {
     addToEventLog(`Error while sending data: ${e}`, 'error');
   }
 }
 
+async function readReply(readable) {
+  try {
+    const reply = await new Response(readable).arrayBuffer();
+    const length = new DataView(reply).getUint32(0);
+    const body = new TextDecoder().decode(new Uint8Array(reply, 4, length));
+    addToEventLog(`Reply: ${body}`);
+  } catch (e) {
+    addToEventLog(`Error while reading the reply: ${e}`, 'error');
+  }
+}
+
 async function readDatagrams() {
   try {
     /*
@@ -90,6 +116,10 @@ async function readDatagrams() {
         addToEventLog('Done reading datagrams!');
         return;
       }
+      if (probes) {
+        receiveProbe(value);
+        continue;
+      }
       let data = decoder.decode(value);
       addToEventLog(`Datagram received: ${data}`);
     }
@@ -98,6 +128,57 @@ async function readDatagrams() {
   }
 }
 
+// Sends numbered datagrams to /stamped, then asks the server for its counters.
+async function measure() {
+  const count = 100;
+  probes = {sent: new Map(), rtts: [], lastServerSeq: -1, reordered: 0};
+  addToEventLog(`Sending ${count} datagrams...`);
+  try {
+    for (let seq = 0; seq < count; seq++) {
+      const data = new Uint8Array(12);
+      const view = new DataView(data.buffer);
+      view.setUint32(0, seq);
+      view.setFloat64(4, performance.now());
+      probes.sent.set(seq, true);
+      await datagramWriter.write(data);
+      await new Promise(resolve => setTimeout(resolve, 10));
+    }
+    // wait for the last echoes.
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    const rtts = probes.rtts.sort((a, b) => a - b);
+    const median = rtts.length ? rtts[Math.floor(rtts.length / 2)].toFixed(2) : '-';
+    addToEventLog(`Echoed ${rtts.length}/${count}, median RTT ${median} ms, reordered on the way back ${probes.reordered}`);
+    probes = null;
+
+    const duplexStream = await wt.createBidirectionalStream();
+    readFromIncomingStream(duplexStream.readable, streamNumber++);
+    const writer = duplexStream.writable.getWriter();
+    await writer.write(new TextEncoder().encode('stats'));
+    await writer.close();
+  } catch (e) {
+    probes = null;
+    addToEventLog(`Error while measuring: ${e}`, 'error');
+  }
+}
+
+// server sequence(4) + server receive time(8) + the datagram sent.
+function receiveProbe(value) {
+  if (value.byteLength < 24) {
+    return;
+  }
+  const view = new DataView(value.buffer, value.byteOffset, value.byteLength);
+  const serverSeq = view.getUint32(0);
+  const seq = view.getUint32(12);
+  if (!probes.sent.delete(seq)) {
+    return;
+  }
+  if (serverSeq < probes.lastServerSeq) {
+    probes.reordered++;
+  }
+  probes.lastServerSeq = Math.max(probes.lastServerSeq, serverSeq);
+  probes.rtts.push(performance.now() - view.getFloat64(16));
+}
+
 async function acceptUnidirectionalStreams() {
   try {
     /*
@@ -166,11 +247,13 @@ function addToEventLog(text, severity = 'info') {
 }
 function setUIStart() {
   document.forms.sending.elements.send.disabled = true;
+  document.getElementById('measure').disabled = true;
   document.getElementById('connect').style.display = 'inline';
   document.getElementById('close').style.display = 'none';
 }
 function setUIConnected() {
   document.forms.sending.elements.send.disabled = false;
+  document.getElementById('measure').disabled = false;
   document.getElementById('connect').style.display = 'none';
   document.getElementById('close').style.display = 'inline';
 }
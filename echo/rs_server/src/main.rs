@@ -7,36 +7,50 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
+mod rpc;
+
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
 use std::mem;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use mio::net::UdpSocket;
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::{Builder, Timeout, Timer};
 use structopt::StructOpt;
 
-use neqo_common::{hex, qdebug, qinfo, Datagram, Header};
+use neqo_common::{hex, qdebug, qinfo, qwarn, Datagram, Header};
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256},
     generate_ech_keys, init_db, random, AntiReplay, Cipher,
 };
 use neqo_http3::{
-    Error, Http3Parameters, Http3Server, Http3ServerEvent, WebTransportRequest,
+    Error, Http3Parameters, Http3Server, Http3ServerEvent, Http3State, WebTransportRequest,
     WebTransportServerEvent,
 };
 use neqo_transport::{
-    server::ValidateAddress, tparams::PreferredAddress, CongestionControlAlgorithm,
-    ConnectionParameters, Output, RandomConnectionIdGenerator, StreamType,
+    server::{ActiveConnectionRef, ValidateAddress},
+    tparams::PreferredAddress,
+    CongestionControlAlgorithm, ConnectionParameters, Output, RandomConnectionIdGenerator,
+    StreamId, StreamType,
 };
 
+use rpc::{Codec, RpcError, RpcServer, INVALID_PARAMS};
+
 const TIMER_TOKEN: Token = Token(0xffff_ffff);
 const ANTI_REPLAY_WINDOW: Duration = Duration::from_secs(10);
+/// Largest DATAGRAM frame accepted.
+const DATAGRAM_FRAME_SIZE: u64 = 1500;
+
+/// Sequence numbers below the highest one remembered to tell the duplicates.
+const DUPLICATE_WINDOW: u32 = 1024;
+
+/// Longest request on a bidi stream of a stamped echo session.
+const MAX_REQUEST_SIZE: usize = 4096;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "neqo-server", about = "A basic HTTP3 server.")]
@@ -185,6 +199,7 @@ impl QuicParameters {
             .max_streams(StreamType::BiDi, self.max_streams_bidi)
             .max_streams(StreamType::UniDi, self.max_streams_uni)
             .cc_algorithm(self.congestion_control)
+            .datagram_size(DATAGRAM_FRAME_SIZE)
     }
 }
 
@@ -197,18 +212,149 @@ fn emit_packet(socket: &mut UdpSocket, out_dgram: Datagram) {
     }
 }
 
+/// Loss and reordering of the datagrams of a stamped echo session (`/stamped`).
+///
+/// Every datagram starts with the client's sequence number (4 bytes, big endian) and is
+/// echoed after `sequence(4) + receive time(8)` of the server: the number of datagrams the
+/// server echoed before, and microseconds since the UNIX epoch. The client gets the RTT from
+/// its own send time in the datagram, and the server counters with `stats` on a bidi stream.
+/// A datagram whose sequence number was seen in the last `DUPLICATE_WINDOW` is a duplicate.
+#[derive(Debug, Default)]
+struct EchoStats {
+    // datagrams echoed, the next server sequence number.
+    received: u64,
+    // lowest and highest client sequence numbers seen.
+    first: Option<u32>,
+    highest: Option<u32>,
+    // datagrams arriving after one with a higher sequence number.
+    reordered: u64,
+    // datagrams with a sequence number seen already, and the recent sequence numbers.
+    duplicated: u64,
+    recent: BTreeSet<u32>,
+    // request of each bidi stream, until its end.
+    requests: HashMap<StreamId, Vec<u8>>,
+}
+
+impl EchoStats {
+    fn stamp(&mut self, datagram: &[u8]) -> Vec<u8> {
+        if datagram.len() >= 4 {
+            let seq = u32::from_be_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]);
+            if self.recent.insert(seq) {
+                self.first = Some(self.first.map_or(seq, |first| first.min(seq)));
+                match self.highest {
+                    Some(highest) if seq < highest => self.reordered += 1,
+                    _ => self.highest = Some(seq),
+                }
+                let floor = self.highest.map_or(0, |h| h.saturating_sub(DUPLICATE_WINDOW));
+                self.recent = self.recent.split_off(&floor);
+            } else {
+                self.duplicated += 1;
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let mut out = Vec::with_capacity(12 + datagram.len());
+        out.extend_from_slice(&(self.received as u32).to_be_bytes());
+        out.extend_from_slice(&now.to_be_bytes());
+        out.extend_from_slice(datagram);
+        self.received += 1;
+        out
+    }
+
+    /// Datagrams missing between the lowest and the highest sequence numbers.
+    fn lost(&self) -> u64 {
+        match (self.first, self.highest) {
+            (Some(first), Some(highest)) => {
+                let unique = self.received - self.duplicated;
+                (u64::from(highest - first) + 1).saturating_sub(unique)
+            }
+            _ => 0,
+        }
+    }
+
+    fn report(&self, rtt: Duration) -> String {
+        format!(
+            "received {}\nlost {}\nreordered {}\nduplicated {}\nrtt_ms {:.3}\n",
+            self.received,
+            self.lost(),
+            self.reordered,
+            self.duplicated,
+            rtt.as_secs_f64() * 1000.0
+        )
+    }
+}
+
 #[derive(Debug)]
 struct EchoHandler {
     session: WebTransportRequest,
     buf: Vec<u8>,
+    // set for a stamped echo session.
+    stats: Option<EchoStats>,
+    // set for a RPC session, answers the calls on bidi streams.
+    rpc: Option<RpcServer>,
 }
 impl EchoHandler {
     pub fn new(session: WebTransportRequest) -> Self {
-        Self { session, buf: Vec::new()}
+        Self { session, buf: Vec::new(), stats: None, rpc: None }
+    }
+    pub fn stamped(session: WebTransportRequest) -> Self {
+        Self { stats: Some(EchoStats::default()), ..Self::new(session) }
+    }
+    pub fn rpc(session: WebTransportRequest, codec: Codec) -> Self {
+        let mut rpc = RpcServer::new(codec);
+        rpc.register("echo", |text: String| Ok(text));
+        rpc.register("sum", |numbers: Vec<f64>| Ok(numbers.iter().sum::<f64>()));
+        rpc.register("divide", |(a, b): (f64, f64)| {
+            if b == 0.0 {
+                Err(RpcError::new(INVALID_PARAMS, "division by zero"))
+            } else {
+                Ok(a / b)
+            }
+        });
+        rpc.register("time", |()| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .map_err(|e| RpcError::new(rpc::INTERNAL_ERROR, e.to_string()))
+        });
+        Self { rpc: Some(rpc), ..Self::new(session) }
+    }
+    fn is_session(&self, session: &WebTransportRequest) -> bool {
+        session.conn == self.session.conn && session.stream_id() == self.session.stream_id()
     }
     pub fn process_events(&mut self, event: Http3ServerEvent) {
         println!("event!");
+        if let Some(rpc) = &mut self.rpc {
+            if rpc.process_event(&event) {
+                return;
+            }
+        }
         match event {
+            Http3ServerEvent::Data { mut stream, data, fin }
+                if self.stats.is_some() && stream.stream_id().is_bidi() =>
+            {
+                let stats = self.stats.as_mut().unwrap();
+                let mut request = stats.requests.remove(&stream.stream_id()).unwrap_or_default();
+                request.extend(data);
+                if request.len() > MAX_REQUEST_SIZE {
+                    qwarn!("stamped echo request longer than {} bytes", MAX_REQUEST_SIZE);
+                    let _ = stream.stream_stop_sending(Error::HttpRequestCancelled.code());
+                    let _ = stream.stream_reset_send(Error::HttpRequestCancelled.code());
+                    return;
+                }
+                if !fin {
+                    stats.requests.insert(stream.stream_id(), request);
+                    return;
+                }
+                if request == b"stats" {
+                    let rtt = self.session.conn.borrow().stats().rtt;
+                    let _ = stream.send_data(stats.report(rtt).as_bytes());
+                } else {
+                    let _ = stream.send_data(&request);
+                }
+                let _ = stream.stream_close_send();
+            }
             Http3ServerEvent::Data { stream, data, fin } => {
                 if fin {
                     let mut res_stream = if stream.stream_info.stream_id().is_uni() {
@@ -227,21 +373,58 @@ impl EchoHandler {
                 WebTransportServerEvent::SessionClosed { .. } => {}
                 WebTransportServerEvent::NewStream(_stream) => {
                 }
+                WebTransportServerEvent::Datagram { mut session, datagram } => {
+                    if !self.is_session(&session) {
+                        return;
+                    }
+                    let reply = match &mut self.stats {
+                        Some(stats) => stats.stamp(&datagram),
+                        None => datagram,
+                    };
+                    if let Err(e) = session.send_datagram(&reply, None) {
+                        qdebug!("send datagram error. {:?}", e);
+                    }
+                }
             },
             _ => {}
         }
     }
 }
 
+/// A WebTransport session: its connection and the stream id of its CONNECT request.
+type SessionKey = (ActiveConnectionRef, StreamId);
+
+/// The session an event is about, if any.
+fn session_of(event: &Http3ServerEvent) -> Option<SessionKey> {
+    match event {
+        Http3ServerEvent::Data { stream, .. }
+        | Http3ServerEvent::DataWritable { stream }
+        | Http3ServerEvent::StreamReset { stream, .. }
+        | Http3ServerEvent::StreamStopSending { stream, .. }
+        | Http3ServerEvent::WebTransport(WebTransportServerEvent::NewStream(stream)) => {
+            Some((stream.conn.clone(), stream.stream_info.session_id()?))
+        }
+        Http3ServerEvent::WebTransport(WebTransportServerEvent::NewSession { session, .. })
+        | Http3ServerEvent::WebTransport(WebTransportServerEvent::SessionClosed {
+            session, ..
+        })
+        | Http3ServerEvent::WebTransport(WebTransportServerEvent::Datagram { session, .. }) => {
+            Some((session.conn.clone(), session.stream_id()))
+        }
+        _ => None,
+    }
+}
+
 struct WebTransportServer {
     server: Http3Server,
-    handler: Option<EchoHandler>,
+    // the handler of each session, with its counters.
+    handlers: HashMap<SessionKey, EchoHandler>,
 }
 impl WebTransportServer {
     pub fn new(server: Http3Server) -> Self {
         Self {
             server,
-            handler: None,
+            handlers: HashMap::new(),
         }
     }
 
@@ -250,6 +433,7 @@ impl WebTransportServer {
     }
 
     fn process_events(&mut self, _args: &Args, _now: Instant) {
+        let key = |session: &WebTransportRequest| (session.conn.clone(), session.stream_id());
         while let Some(event) = self.server.next_event() {
             // println!("{:#?}", event);
             match event.clone() {
@@ -262,9 +446,34 @@ impl WebTransportServer {
                         match headers.iter().find(|&h| h.name() == ":path") {
                             Some(h) => match h.value() {
                                 "/counter" => {
-                                    self.handler = Some(EchoHandler::new(session.clone()));
+                                    self.handlers.insert(key(&session), EchoHandler::new(session.clone()));
+                                    let _ = session.response(true);
+                                }
+                                "/stamped" => {
+                                    self.handlers.insert(key(&session), EchoHandler::stamped(session.clone()));
                                     let _ = session.response(true);
                                 }
+                                path if path.split('?').next() == Some("/rpc") => {
+                                    // `/rpc?codec=cbor`, JSON by default.
+                                    let codec = path
+                                        .split(|c| c == '?' || c == '&')
+                                        .find_map(|p| p.strip_prefix("codec="))
+                                        .unwrap_or("json")
+                                        .parse::<Codec>();
+                                    match codec {
+                                        Ok(codec) => {
+                                            self.handlers.insert(key(&session), EchoHandler::rpc(session.clone(), codec));
+                                            let _ = session.response(true);
+                                        }
+                                        Err(e) => {
+                                            qwarn!("rpc session refused: {}", e);
+                                            let _ = session.send_headers(&[
+                                                Header::new(":status", "400"),
+                                                Header::new("sec-webtransport-http3-draft", "draft02"),
+                                            ]);
+                                        }
+                                    }
+                                }
                                 _ => {
                                     let _ = session.send_headers(&[
                                         Header::new(":status", "404"),
@@ -277,11 +486,23 @@ impl WebTransportServer {
                             }
                         }
                     }
+                    WebTransportServerEvent::SessionClosed { session, .. } => {
+                        self.handlers.remove(&key(&session));
+                        continue;
+                    }
                     _ => {},
                 },
+                Http3ServerEvent::StateChange {
+                    conn,
+                    state: Http3State::Closing(_) | Http3State::Closed(_),
+                } => {
+                    self.handlers.retain(|(c, _), _| *c != conn);
+                    continue;
+                }
                 _ => {}
             }
-            if let Some(ref mut h) = self.handler {
+            let handler = session_of(&event).and_then(|session| self.handlers.get_mut(&session));
+            if let Some(h) = handler {
                 h.process_events(event)
             }
         }
@@ -432,6 +653,7 @@ impl ServersRunner {
                     .max_table_size_encoder(args.max_table_size_encoder)
                     .max_table_size_decoder(args.max_table_size_decoder)
                     .max_blocked_streams(args.max_blocked_streams)
+                    .connection_parameters(args.quic_parameters.get())
                     .webtransport(true),
                 None,
             )
@@ -570,3 +792,68 @@ fn main() -> Result<(), io::Error> {
     let mut servers_runner = ServersRunner::new(args)?;
     servers_runner.run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(stats: &mut EchoStats, seqs: &[u32]) {
+        for seq in seqs {
+            let echoed = stats.stamp(&seq.to_be_bytes());
+            assert_eq!(echoed.len(), 12 + 4);
+            assert_eq!(&echoed[12..], &seq.to_be_bytes());
+        }
+    }
+
+    fn counters(stats: &EchoStats) -> (u64, u64, u64, u64) {
+        (stats.received, stats.lost(), stats.reordered, stats.duplicated)
+    }
+
+    #[test]
+    fn in_order() {
+        let mut stats = EchoStats::default();
+        push(&mut stats, &[10, 11, 12, 13]);
+        assert_eq!(counters(&stats), (4, 0, 0, 0));
+        // the server sequence numbers count the datagrams echoed.
+        assert_eq!(&stats.stamp(&[0, 0, 0, 14])[..4], &4u32.to_be_bytes());
+    }
+
+    #[test]
+    fn gap() {
+        let mut stats = EchoStats::default();
+        push(&mut stats, &[0, 1, 4, 5]);
+        assert_eq!(counters(&stats), (4, 2, 0, 0));
+        // a datagram of the gap arriving late is reordered, not lost.
+        push(&mut stats, &[2]);
+        assert_eq!(counters(&stats), (5, 1, 1, 0));
+    }
+
+    #[test]
+    fn reorder() {
+        let mut stats = EchoStats::default();
+        push(&mut stats, &[1, 0, 3, 2, 4]);
+        assert_eq!(counters(&stats), (5, 0, 2, 0));
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut stats = EchoStats::default();
+        push(&mut stats, &[0, 1, 3, 3, 1]);
+        // 2 is still lost, and the copies are neither reordered nor make up for it.
+        assert_eq!(counters(&stats), (5, 1, 0, 2));
+        let report = stats.report(Duration::from_millis(5));
+        assert!(report.contains("\nlost 1\nreordered 0\nduplicated 2\n"));
+    }
+
+    #[test]
+    fn duplicate_window() {
+        let mut stats = EchoStats::default();
+        let last = DUPLICATE_WINDOW + 10;
+        for seq in 0..=last {
+            push(&mut stats, &[seq]);
+        }
+        assert_eq!(stats.recent.len(), DUPLICATE_WINDOW as usize + 1);
+        push(&mut stats, &[last, last - DUPLICATE_WINDOW]);
+        assert_eq!(stats.duplicated, 2);
+    }
+}
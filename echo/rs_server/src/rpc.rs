@@ -0,0 +1,370 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Request/response calls on WebTransport bidi streams.
+//!
+//! The client opens a bidi stream per call and sends one message, `length(4, big endian) +
+//! body`, where the body is `{"method": name, "params": params}` in the codec of the session
+//! (JSON or CBOR). The reply on the same stream is a message `{"result": value}` or
+//! `{"error": {"code": code, "message": text}}`, and the stream ends. The client doesn't
+//! have to end its side. Resetting the stream (or STOP_SENDING) cancels the call: a request
+//! still arriving is dropped and so is the part of the reply not sent yet.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use neqo_common::qdebug;
+use neqo_http3::{Http3OrWebTransportStream, Http3ServerEvent};
+use neqo_transport::StreamId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Largest request accepted.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Error codes of JSON-RPC.
+pub const PARSE_ERROR: i32 = -32700;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+
+/// Application error code of the streams reset by the server.
+const RESET_CODE: u64 = 0x10c;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Cbor,
+}
+
+impl Codec {
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, RpcError> {
+        let encoded = match self {
+            Self::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Self::Cbor => serde_cbor::to_vec(value).map_err(|e| e.to_string()),
+        };
+        encoded.map_err(|message| RpcError::new(INTERNAL_ERROR, message))
+    }
+
+    fn decode<T: DeserializeOwned>(self, buf: &[u8], code: i32) -> Result<T, RpcError> {
+        let decoded = match self {
+            Self::Json => serde_json::from_slice(buf).map_err(|e| e.to_string()),
+            Self::Cbor => serde_cbor::from_slice(buf).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|message| RpcError::new(code, message))
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!("unknown codec {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+#[derive(Deserialize)]
+struct Method {
+    method: String,
+}
+
+#[derive(Deserialize)]
+struct Request<T> {
+    params: T,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply<T> {
+    Result(T),
+    Error(RpcError),
+}
+
+type Handler = Box<dyn FnMut(Codec, &[u8]) -> Result<Vec<u8>, RpcError>>;
+
+/// The methods of a session and its calls in progress.
+pub struct RpcServer {
+    codec: Codec,
+    methods: HashMap<String, Handler>,
+    // requests still arriving.
+    requests: HashMap<StreamId, Vec<u8>>,
+    // replies the streams didn't take yet.
+    replies: HashMap<StreamId, (Http3OrWebTransportStream, Vec<u8>)>,
+    // calls answered whose request side is still open.
+    answered: HashSet<StreamId>,
+}
+
+impl fmt::Debug for RpcServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcServer")
+            .field("codec", &self.codec)
+            .field("methods", &self.methods.keys().collect::<Vec<_>>())
+            .field("calls", &self.requests.len())
+            .finish()
+    }
+}
+
+impl RpcServer {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            methods: HashMap::new(),
+            requests: HashMap::new(),
+            replies: HashMap::new(),
+            answered: HashSet::new(),
+        }
+    }
+
+    /// Call `f` with the decoded params of the requests for `method`, its result or error
+    /// is the reply.
+    pub fn register<P, R, F>(&mut self, method: &str, mut f: F)
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: FnMut(P) -> Result<R, RpcError> + 'static,
+    {
+        let call = move |codec: Codec, body: &[u8]| {
+            let request: Request<P> = codec.decode(body, INVALID_PARAMS)?;
+            codec.encode(&Reply::Result(f(request.params)?))
+        };
+        self.methods.insert(method.to_string(), Box::new(call));
+    }
+
+    /// Handle the events of the bidi streams of the session, returns false for the others.
+    pub fn process_event(&mut self, event: &Http3ServerEvent) -> bool {
+        match event {
+            Http3ServerEvent::Data { stream, data, fin } => {
+                let stream_id = stream.stream_id();
+                if !stream_id.is_bidi() {
+                    return false;
+                }
+                if self.answered.contains(&stream_id) {
+                    // nothing is expected after the request.
+                    if *fin {
+                        self.answered.remove(&stream_id);
+                    }
+                    return true;
+                }
+                let mut request = self.requests.remove(&stream_id).unwrap_or_default();
+                request.extend_from_slice(data);
+                match Self::message(&request) {
+                    Ok(None) if !fin => {
+                        self.requests.insert(stream_id, request);
+                        return true;
+                    }
+                    Ok(Some(body)) => {
+                        let reply = self.call(body);
+                        self.reply(stream.clone(), reply);
+                    }
+                    Ok(None) => {
+                        let reply = self.error(RpcError::new(PARSE_ERROR, "truncated message"));
+                        self.reply(stream.clone(), reply);
+                    }
+                    Err(err) => {
+                        let reply = self.error(err);
+                        self.reply(stream.clone(), reply);
+                    }
+                }
+                if !fin {
+                    self.answered.insert(stream_id);
+                }
+                true
+            }
+            Http3ServerEvent::DataWritable { stream } => {
+                match self.replies.remove(&stream.stream_id()) {
+                    Some((stream, rest)) => self.send(stream, rest),
+                    None => return false,
+                }
+                true
+            }
+            Http3ServerEvent::StreamReset { stream, .. } => {
+                self.cancel(stream, false);
+                stream.stream_id().is_bidi()
+            }
+            Http3ServerEvent::StreamStopSending { stream, .. } => {
+                self.cancel(stream, true);
+                stream.stream_id().is_bidi()
+            }
+            _ => false,
+        }
+    }
+
+    // the body of a complete message, `None` while it is arriving.
+    fn message(buf: &[u8]) -> Result<Option<&[u8]>, RpcError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(RpcError::new(
+                PARSE_ERROR,
+                format!("message of {} bytes", len),
+            ));
+        }
+        Ok(buf.get(4..4 + len))
+    }
+
+    fn call(&mut self, body: &[u8]) -> Vec<u8> {
+        let method = match self.codec.decode::<Method>(body, PARSE_ERROR) {
+            Ok(m) => m.method,
+            Err(err) => return self.error(err),
+        };
+        let result = match self.methods.get_mut(&method) {
+            Some(f) => f(self.codec, body),
+            None => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("no method {}", method),
+            )),
+        };
+        qdebug!("rpc {}: {:?}", method, result.as_ref().err());
+        result.unwrap_or_else(|err| self.error(err))
+    }
+
+    fn error(&self, err: RpcError) -> Vec<u8> {
+        self.codec
+            .encode(&Reply::<()>::Error(err))
+            .unwrap_or_default()
+    }
+
+    fn reply(&mut self, stream: Http3OrWebTransportStream, body: Vec<u8>) {
+        let mut message = (body.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(&body);
+        self.send(stream, message);
+    }
+
+    // send the rest of a reply, keeping what the stream doesn't take for `DataWritable`.
+    fn send(&mut self, mut stream: Http3OrWebTransportStream, mut message: Vec<u8>) {
+        let sent = stream.send_data(&message).unwrap_or(message.len());
+        if sent < message.len() {
+            message.drain(..sent);
+            self.replies.insert(stream.stream_id(), (stream, message));
+        } else {
+            let _ = stream.stream_close_send();
+        }
+    }
+
+    // the client gave up a call; answer STOP_SENDING with a reset of the reply.
+    fn cancel(&mut self, stream: &Http3OrWebTransportStream, stop_sending: bool) {
+        let stream_id = stream.stream_id();
+        self.requests.remove(&stream_id);
+        self.answered.remove(&stream_id);
+        let pending = self.replies.remove(&stream_id).is_some();
+        if stop_sending || pending {
+            let _ = stream.clone().stream_reset_send(RESET_CODE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn server(codec: Codec) -> RpcServer {
+        let mut rpc = RpcServer::new(codec);
+        rpc.register("sum", |numbers: Vec<f64>| Ok(numbers.iter().sum::<f64>()));
+        rpc.register("fail", |()| -> Result<(), _> {
+            Err(RpcError::new(7, "failed"))
+        });
+        rpc
+    }
+
+    fn framed(body: &[u8]) -> Vec<u8> {
+        let mut message = (body.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(body);
+        message
+    }
+
+    #[test]
+    fn message_framing() {
+        let message = framed(b"body");
+        for end in 0..message.len() {
+            assert_eq!(RpcServer::message(&message[..end]).unwrap(), None);
+        }
+        assert_eq!(RpcServer::message(&message).unwrap(), Some(&b"body"[..]));
+        // bytes after the message are ignored.
+        let mut longer = message.clone();
+        longer.extend_from_slice(b"tail");
+        assert_eq!(RpcServer::message(&longer).unwrap(), Some(&b"body"[..]));
+        assert_eq!(RpcServer::message(&framed(b"")).unwrap(), Some(&b""[..]));
+
+        let too_large = ((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes();
+        assert_eq!(
+            RpcServer::message(&too_large).unwrap_err().code,
+            PARSE_ERROR
+        );
+    }
+
+    /// `{"method": method, "params": params}` answered, as JSON.
+    fn call(codec: Codec, request: &serde_json::Value) -> serde_json::Value {
+        let body = codec.encode(request).unwrap();
+        let reply = server(codec).call(&body);
+        codec.decode(&reply, PARSE_ERROR).unwrap()
+    }
+
+    #[test]
+    fn json_and_cbor_round_trip() {
+        for &codec in &[Codec::Json, Codec::Cbor] {
+            let reply = call(codec, &json!({"method": "sum", "params": [1, 2.5]}));
+            assert_eq!(reply, json!({"result": 3.5}));
+            let reply = call(codec, &json!({"method": "fail", "params": null}));
+            assert_eq!(reply, json!({"error": {"code": 7, "message": "failed"}}));
+        }
+    }
+
+    #[test]
+    fn call_errors() {
+        for &codec in &[Codec::Json, Codec::Cbor] {
+            let code = |request| call(codec, &request)["error"]["code"].as_i64();
+            assert_eq!(
+                code(json!({"method": "nope", "params": []})),
+                Some(i64::from(METHOD_NOT_FOUND))
+            );
+            assert_eq!(
+                code(json!({"method": "sum", "params": "one"})),
+                Some(i64::from(INVALID_PARAMS))
+            );
+            assert_eq!(code(json!({"params": []})), Some(i64::from(PARSE_ERROR)));
+        }
+        // not even the codec of the session.
+        let reply = server(Codec::Json).call(&[0xa1, 0x61]);
+        let reply: serde_json::Value = Codec::Json.decode(&reply, PARSE_ERROR).unwrap();
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn codec_names() {
+        assert_eq!("json".parse::<Codec>(), Ok(Codec::Json));
+        assert_eq!("cbor".parse::<Codec>(), Ok(Codec::Cbor));
+        assert!("xml".parse::<Codec>().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Automatic simulcast layer selection for viewers that didn't pick a layer.
+//!
+//! Every `SAMPLE_INTERVAL` the connection of each viewer is sampled: the free
+//! congestion window, the smoothed RTT and the bytes that didn't fit into the send
+//! buffers of its streams since the last sample. A viewer goes one layer down after
+//! `DOWNGRADE_AFTER` congested samples in a row, and one layer up only after
+//! `UPGRADE_AFTER` calm samples in a row whose window has room for the extra bitrate,
+//! so a viewer near the limit doesn't bounce between two layers.
+
+use std::time::Duration;
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const DOWNGRADE_AFTER: u32 = 2;
+const UPGRADE_AFTER: u32 = 5;
+
+/// RTT above the lowest one seen that counts as a queue building up.
+const QUEUE_DELAY: Duration = Duration::from_millis(50);
+
+/// The free window must carry this many times the extra bitrate of the next layer.
+const UPGRADE_HEADROOM: f64 = 1.5;
+
+/// State of a viewer's connection at one point.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    // bytes the congestion window can still take.
+    pub cwnd_avail: usize,
+    pub rtt: Duration,
+    // bytes the streams refused since the last sample.
+    pub queued: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Stay,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Default)]
+pub struct Estimator {
+    min_rtt: Option<Duration>,
+    congested: u32,
+    calm: u32,
+}
+
+impl Estimator {
+    /// `extra_bitrate` is how many more bytes per second the next better layer needs,
+    /// `None` when the viewer already gets the best one.
+    pub fn sample(&mut self, sample: &Sample, extra_bitrate: Option<f64>) -> Decision {
+        if sample.rtt.is_zero() {
+            // no RTT sample yet.
+            return Decision::Stay;
+        }
+        let min_rtt = self.min_rtt.map_or(sample.rtt, |m| m.min(sample.rtt));
+        self.min_rtt = Some(min_rtt);
+
+        let congested = sample.queued > 0 || sample.rtt > min_rtt + QUEUE_DELAY;
+        if congested {
+            self.calm = 0;
+            self.congested += 1;
+            if self.congested >= DOWNGRADE_AFTER {
+                self.congested = 0;
+                return Decision::Down;
+            }
+            return Decision::Stay;
+        }
+        self.congested = 0;
+
+        // bytes per second the free window could add.
+        let headroom = sample.cwnd_avail as f64 / sample.rtt.as_secs_f64();
+        match extra_bitrate {
+            Some(extra) if headroom >= extra * UPGRADE_HEADROOM => {
+                self.calm += 1;
+                if self.calm >= UPGRADE_AFTER {
+                    self.calm = 0;
+                    return Decision::Up;
+                }
+            }
+            _ => self.calm = 0,
+        }
+        Decision::Stay
+    }
+
+    /// Start over after a switch, the old samples don't describe the new bitrate.
+    pub fn reset(&mut self) {
+        self.congested = 0;
+        self.calm = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: Duration = Duration::from_millis(40);
+    // the next layer needs 100 kB/s more.
+    const EXTRA: Option<f64> = Some(100_000.0);
+
+    fn calm() -> Sample {
+        // 10 kB per 40 ms, 250 kB/s of headroom.
+        Sample {
+            cwnd_avail: 10_000,
+            rtt: RTT,
+            queued: 0,
+        }
+    }
+
+    fn congested() -> Sample {
+        Sample {
+            queued: 1_200,
+            ..calm()
+        }
+    }
+
+    fn run(estimator: &mut Estimator, samples: &[Sample]) -> Vec<Decision> {
+        samples.iter().map(|s| estimator.sample(s, EXTRA)).collect()
+    }
+
+    #[test]
+    fn downgrade_after_two_congested_samples() {
+        let mut estimator = Estimator::default();
+        let decisions = run(
+            &mut estimator,
+            &[calm(), congested(), congested(), congested()],
+        );
+        assert_eq!(
+            decisions,
+            vec![
+                Decision::Stay,
+                Decision::Stay,
+                Decision::Down,
+                Decision::Stay
+            ]
+        );
+        // a growing RTT is congestion too.
+        let slow = Sample {
+            rtt: RTT + QUEUE_DELAY * 2,
+            ..calm()
+        };
+        assert_eq!(estimator.sample(&slow, EXTRA), Decision::Down);
+    }
+
+    #[test]
+    fn upgrade_after_five_calm_samples() {
+        let mut estimator = Estimator::default();
+        let decisions = run(&mut estimator, &[calm(); 6]);
+        let mut expected = vec![Decision::Stay; 4];
+        expected.extend([Decision::Up, Decision::Stay]);
+        assert_eq!(decisions, expected);
+    }
+
+    #[test]
+    fn no_upgrade_without_headroom_or_better_layer() {
+        let mut estimator = Estimator::default();
+        let tight = Sample {
+            cwnd_avail: 5_000,
+            ..calm()
+        };
+        assert!(run(&mut estimator, &[tight; 10])
+            .iter()
+            .all(|d| *d == Decision::Stay));
+        for _ in 0..10 {
+            assert_eq!(estimator.sample(&calm(), None), Decision::Stay);
+        }
+    }
+
+    #[test]
+    fn mixed_samples_start_over() {
+        let mut estimator = Estimator::default();
+        // a congested sample breaks the calm run, a calm one the congested run.
+        let samples = [
+            calm(),
+            calm(),
+            calm(),
+            calm(),
+            congested(),
+            calm(),
+            congested(),
+            calm(),
+            calm(),
+            calm(),
+            calm(),
+        ];
+        assert!(run(&mut estimator, &samples)
+            .iter()
+            .all(|d| *d == Decision::Stay));
+        assert_eq!(estimator.sample(&calm(), EXTRA), Decision::Up);
+
+        // and so does a switch.
+        run(&mut estimator, &[calm(); 4]);
+        estimator.reset();
+        assert_eq!(estimator.sample(&calm(), EXTRA), Decision::Stay);
+        estimator.sample(&congested(), EXTRA);
+        estimator.reset();
+        assert_eq!(estimator.sample(&congested(), EXTRA), Decision::Stay);
+    }
+
+    #[test]
+    fn no_rtt_yet() {
+        let mut estimator = Estimator::default();
+        let sample = Sample {
+            rtt: Duration::ZERO,
+            ..congested()
+        };
+        assert!(run(&mut estimator, &[sample; 3])
+            .iter()
+            .all(|d| *d == Decision::Stay));
+    }
+}
@@ -0,0 +1,225 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Admin API served on `--admin-addr`.
+//!
+//! - `GET /sessions` : connected sessions.
+//! - `GET /channels` : channels with their publisher and viewer counts, codec, simulcast layers
+//!   and recording directory.
+//! - `GET /topics` : pubsub topics with their delivery and subscriber count.
+//! - `GET /rooms` : rooms with the name, role and join time of their members.
+//! - `POST /sessions/{id}/close?code=N&reason=...` : close a session.
+//! - `POST /channels/{name}/close?code=N&reason=...` : close every session of a channel.
+//!
+//! Every request needs `Authorization: Bearer {--admin-token}`.
+
+use serde_json::json;
+
+use crate::http1::{self, Request, Response};
+use crate::WebTransportServer;
+
+/// Longest close reason sent to a peer, in bytes.
+const MAX_REASON_LEN: usize = 1024;
+
+pub fn handle(server: &mut WebTransportServer, token: &str, req: &Request) -> Response {
+    let authorized = req
+        .header("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map_or(false, |t| constant_time_eq(t.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Response::text(401, "unauthorized");
+    }
+
+    let path: Vec<&str> = req.path.split('/').filter(|p| !p.is_empty()).collect();
+    match (req.method.as_str(), path.as_slice()) {
+        ("GET", ["sessions"]) => json_response(list_sessions(server)),
+        ("GET", ["channels"]) => json_response(list_channels(server)),
+        ("GET", ["topics"]) => json_response(list_topics(server)),
+        ("GET", ["rooms"]) => json_response(list_rooms(server)),
+        ("POST", ["sessions", id, "close"]) => {
+            let id = match id.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => return Response::text(400, "invalid session id"),
+            };
+            let conn = server
+                .sessions
+                .iter()
+                .find(|(_, s)| s.id == id)
+                .map(|(conn, _)| conn.clone());
+            match conn {
+                Some(conn) => {
+                    let (code, reason) = close_args(req);
+                    server.close_session(&conn, code, &reason);
+                    Response::text(200, "closed")
+                }
+                None => Response::text(404, "no such session"),
+            }
+        }
+        ("POST", ["channels", name, "close"]) => match server.channel(name) {
+            Some(channel) => {
+                let (code, reason) = close_args(req);
+                for conn in channel.conns() {
+                    server.close_session(&conn, code, &reason);
+                }
+                Response::text(200, "closed")
+            }
+            None => Response::text(404, "no such channel"),
+        },
+        (_, ["sessions"]) | (_, ["channels"]) => Response::text(405, "method not allowed"),
+        _ => Response::text(404, "not found"),
+    }
+}
+
+// Compare the token without stopping at the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn close_args(req: &Request) -> (u32, String) {
+    let code = req.query("code").and_then(|c| c.parse().ok()).unwrap_or(0);
+    let reason = req
+        .query("reason")
+        .map_or_else(|| "closed by admin".to_string(), http1::percent_decode);
+    (code, truncate(reason, MAX_REASON_LEN))
+}
+
+fn truncate(mut s: String, max_len: usize) -> String {
+    if s.len() > max_len {
+        let mut end = max_len;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s
+}
+
+fn json_response(value: serde_json::Value) -> Response {
+    Response::new(200, "application/json", value.to_string())
+}
+
+fn list_sessions(server: &WebTransportServer) -> serde_json::Value {
+    let mut sessions: Vec<_> = server
+        .sessions
+        .iter()
+        .map(|(conn, s)| {
+            let handler = server.handler.get(conn);
+            json!({
+                "id": s.id,
+                "remote": s.remote.map(|a| a.to_string()),
+                "path": handler.map(|h| h.path()),
+                "role": handler.map(|h| h.role()),
+                "rtt_ms": conn.borrow().stats().rtt.as_secs_f64() * 1000.0,
+                "connected_secs": s.since.elapsed().as_secs(),
+            })
+        })
+        .collect();
+    sessions.sort_by_key(|s| s["id"].as_u64());
+    json!(sessions)
+}
+
+fn list_topics(server: &WebTransportServer) -> serde_json::Value {
+    let topics: Vec<_> = server
+        .topics
+        .list()
+        .into_iter()
+        .map(|(name, delivery, subscribers)| {
+            json!({
+                "name": name,
+                "delivery": delivery.as_str(),
+                "subscribers": subscribers,
+            })
+        })
+        .collect();
+    json!(topics)
+}
+
+fn list_rooms(server: &WebTransportServer) -> serde_json::Value {
+    let rooms: Vec<_> = server
+        .presence
+        .rooms()
+        .map(|room| {
+            let members: Vec<_> = server
+                .presence
+                .roster(room)
+                .into_iter()
+                .map(|m| {
+                    json!({
+                        "id": m.id,
+                        "name": m.name,
+                        "role": m.role,
+                        "joined": m.joined,
+                    })
+                })
+                .collect();
+            json!({ "name": room, "members": members })
+        })
+        .collect();
+    json!(rooms)
+}
+
+fn list_channels(server: &WebTransportServer) -> serde_json::Value {
+    let channels: Vec<_> = ["video", "audio", "av-video", "av-audio"]
+        .iter()
+        .filter_map(|name| server.channel(name))
+        .map(|c| {
+            json!({
+                "name": c.name,
+                "publishers": c.publishers.len(),
+                "viewers": c.members.len(),
+                "warp_viewers": c.warp_members.len(),
+                "datagram_viewers": c.datagram_members.len(),
+                "codec": c.config().map(|config| &config.codec),
+                "layers": c.layer_ids(),
+                "recording": c
+                    .recorder
+                    .as_ref()
+                    .and_then(|r| r.dir())
+                    .map(|dir| dir.display().to_string()),
+            })
+        })
+        .collect();
+    json!(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    fn close_request(query: &[(&str, &str)]) -> Request {
+        Request {
+            method: "POST".to_string(),
+            path: "/sessions/1/close".to_string(),
+            query: query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            headers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn close_reason() {
+        let req = close_request(&[("code", "7"), ("reason", "shutting%20down")]);
+        assert_eq!(close_args(&req), (7, "shutting down".to_string()));
+        assert_eq!(
+            close_args(&close_request(&[])),
+            (0, "closed by admin".to_string())
+        );
+        let long = "%C3%A9".repeat(600);
+        let (_, reason) = close_args(&close_request(&[("reason", &long)]));
+        assert_eq!(reason.len(), MAX_REASON_LEN);
+        assert_eq!(truncate("ab€".to_string(), 3), "ab");
+    }
+}
@@ -0,0 +1,225 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A channel carrying video and audio in one session (`/av/stream`, `/av/view`).
+//!
+//! Every unidirectional stream starts with a track id(1byte) followed by a chunk:
+//! 1 = video, 2 = audio, and 0 = clock (server to viewer only).
+//! Both tracks of a publisher share one media clock, the timestamps of its chunks.
+//! About once a second the server sends a clock chunk mapping the media time of the
+//! last chunk to the wall clock time it was received, so viewers can align the tracks
+//! and measure their delay.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use neqo_common::qwarn;
+use neqo_http3::{Http3OrWebTransportStream, WebTransportRequest};
+use neqo_transport::{server::ActiveConnectionRef, StreamId};
+
+use crate::chunk::{Chunk, ChunkHeader, ChunkType};
+use crate::metrics::Metrics;
+use crate::priority::StreamPriority;
+use crate::publisher::{Publisher, DEFAULT_LAYER};
+use crate::recorder::RecordConfig;
+
+pub const TRACK_CLOCK: u8 = 0;
+pub const TRACK_VIDEO: u8 = 1;
+pub const TRACK_AUDIO: u8 = 2;
+
+const CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct AvChannel {
+    pub video: Publisher,
+    pub audio: Publisher,
+
+    // track of each stream of the publishers, known from its first byte.
+    tracks: HashMap<(ActiveConnectionRef, StreamId), u8>,
+
+    // the last wall clock to media time mapping, sent to new members first.
+    clock: Option<Chunk>,
+    clock_sent_at: Option<Instant>,
+
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+/// The track of a stream, taken from the first byte of its data. `None` until that byte.
+fn take_track<K: Eq + Hash>(
+    tracks: &mut HashMap<K, u8>,
+    key: K,
+    data: &mut Vec<u8>,
+    fin: bool,
+) -> Option<u8> {
+    let track = match tracks.get(&key) {
+        Some(track) => *track,
+        None if data.is_empty() => return None,
+        None => data.remove(0),
+    };
+    if fin {
+        tracks.remove(&key);
+    } else {
+        tracks.insert(key, track);
+    }
+    Some(track)
+}
+
+impl AvChannel {
+    pub fn new(
+        max_chunk_size: usize,
+        record: Option<RecordConfig>,
+        metrics: Rc<RefCell<Metrics>>,
+    ) -> Self {
+        Self {
+            video: Publisher::new("av-video", max_chunk_size, record.clone(), metrics.clone())
+                .with_track(TRACK_VIDEO),
+            audio: Publisher::new("av-audio", max_chunk_size, record, metrics.clone())
+                .with_track(TRACK_AUDIO),
+            tracks: HashMap::new(),
+            clock: None,
+            clock_sent_at: None,
+            metrics,
+        }
+    }
+
+    pub fn subscribe(&mut self, handler: WebTransportRequest) {
+        let mut handler = handler;
+        if let Some(clock) = &self.clock {
+            Publisher::send(
+                &mut handler,
+                clock,
+                Some(TRACK_CLOCK),
+                StreamPriority::audio(clock.header.timestamp),
+                "av",
+                &mut self.metrics.borrow_mut(),
+            );
+        }
+        self.video.subscribe(handler.clone(), Some(DEFAULT_LAYER));
+        self.audio.subscribe(handler, Some(DEFAULT_LAYER));
+    }
+    pub fn leave(&mut self, conn: &ActiveConnectionRef) {
+        self.video.leave(conn);
+        self.audio.leave(conn);
+    }
+    pub fn start(&mut self, conn: &ActiveConnectionRef) {
+        self.video.start(conn);
+        self.audio.start(conn);
+    }
+    pub fn end(&mut self, conn: &ActiveConnectionRef) {
+        self.tracks.retain(|(c, _), _| c != conn);
+        self.video.end(conn);
+        self.audio.end(conn);
+    }
+    pub fn publish(&mut self, stream: &mut Http3OrWebTransportStream, data: Vec<u8>, fin: bool) {
+        let key = (stream.conn.clone(), stream.stream_id());
+        let mut data = data;
+        let track = match take_track(&mut self.tracks, key, &mut data, fin) {
+            Some(track) => track,
+            None => return,
+        };
+        let timestamp = match track {
+            TRACK_VIDEO => self.video.publish(stream, data, fin),
+            TRACK_AUDIO => self.audio.publish(stream, data, fin),
+            t => {
+                if fin {
+                    qwarn!("chunk of unknown track {}", t);
+                    self.metrics
+                        .borrow_mut()
+                        .chunk_rejected("av", "unknown_track");
+                }
+                None
+            }
+        };
+        if let Some(timestamp) = timestamp {
+            self.tick(timestamp, Instant::now());
+        }
+    }
+
+    /// Send a clock chunk for `timestamp` when the last one is old enough.
+    fn tick(&mut self, timestamp: i64, now: Instant) {
+        if self
+            .clock_sent_at
+            .map_or(false, |t| now.saturating_duration_since(t) < CLOCK_INTERVAL)
+        {
+            return;
+        }
+        self.clock_sent_at = Some(now);
+        let wall_clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        let clock = Chunk::new(
+            ChunkHeader {
+                chunk_type: ChunkType::Clock,
+                timestamp,
+                duration: 0,
+            },
+            &wall_clock.to_be_bytes(),
+        );
+        // every member is a member of both tracks.
+        let mut metrics = self.metrics.borrow_mut();
+        let priority = StreamPriority::audio(timestamp);
+        for handler in self.video.members.values_mut() {
+            Publisher::send(handler, &clock, Some(TRACK_CLOCK), priority, "av", &mut metrics);
+        }
+        self.clock = Some(clock);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_tagging() {
+        let mut tracks = HashMap::new();
+        let mut data = Vec::new();
+        assert_eq!(take_track(&mut tracks, 1, &mut data, false), None);
+        assert!(tracks.is_empty());
+
+        let mut data = vec![TRACK_VIDEO, 9, 9];
+        assert_eq!(take_track(&mut tracks, 1, &mut data, false), Some(TRACK_VIDEO));
+        assert_eq!(data, vec![9, 9]);
+        // the same stream id of another publisher.
+        let mut data = vec![TRACK_AUDIO, 8];
+        assert_eq!(take_track(&mut tracks, 2, &mut data, false), Some(TRACK_AUDIO));
+        assert_eq!(data, vec![8]);
+
+        // later data of a stream is all chunk.
+        let mut data = vec![TRACK_AUDIO];
+        assert_eq!(take_track(&mut tracks, 1, &mut data, true), Some(TRACK_VIDEO));
+        assert_eq!(data, vec![TRACK_AUDIO]);
+        assert_eq!(tracks.len(), 1);
+
+        // a whole stream in one piece.
+        let mut data = vec![TRACK_VIDEO, 7];
+        assert_eq!(take_track(&mut tracks, 3, &mut data, true), Some(TRACK_VIDEO));
+        assert_eq!(tracks.len(), 1);
+    }
+
+    #[test]
+    fn clock_cadence() {
+        let metrics = Rc::new(RefCell::new(Metrics::new()));
+        let mut av = AvChannel::new(1024, None, metrics);
+        let start = Instant::now();
+        let clock = |av: &AvChannel| av.clock.as_ref().map(|c| c.header.timestamp);
+        assert_eq!(clock(&av), None);
+
+        av.tick(0, start);
+        let chunk = av.clock.clone().unwrap();
+        assert_eq!(chunk.header.chunk_type, ChunkType::Clock);
+        assert_eq!(chunk.payload().len(), 8);
+        av.tick(500_000, start + Duration::from_millis(500));
+        assert_eq!(clock(&av), Some(0));
+        av.tick(999_000, start + Duration::from_millis(999));
+        assert_eq!(clock(&av), Some(0));
+        av.tick(1_000_000, start + CLOCK_INTERVAL);
+        assert_eq!(clock(&av), Some(1_000_000));
+        av.tick(1_100_000, start + Duration::from_millis(1_100));
+        assert_eq!(clock(&av), Some(1_000_000));
+    }
+}
@@ -0,0 +1,332 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Media chunks framed by stream_worker.js.
+//!
+//! header(17) = type(1byte) + timestamp(8) + duration(8), big endian,
+//! followed by the encoded WebCodecs chunk.
+//!
+//! A config chunk (type 3) carries the decoder configuration instead:
+//! json length(2) + json(codec, codedWidth, ...) + description.
+//!
+//! A clock chunk (type 4) is only sent by the server: its timestamp is a media time
+//! and its payload the unix time in microseconds when that media time was received.
+
+use std::fmt;
+
+use serde_json::Value;
+
+pub const HEADER_LEN: usize = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkType {
+    Key = 1,
+    Delta = 2,
+    Config = 3,
+    Clock = 4,
+}
+
+impl ChunkType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Key => "key",
+            Self::Delta => "delta",
+            Self::Config => "config",
+            Self::Clock => "clock",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub chunk_type: ChunkType,
+    /// microseconds, as EncodedVideoChunk.timestamp.
+    pub timestamp: i64,
+    /// microseconds, as EncodedVideoChunk.duration.
+    pub duration: u64,
+}
+
+impl ChunkHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, ChunkError> {
+        if data.len() < HEADER_LEN {
+            return Err(ChunkError::TooShort(data.len()));
+        }
+        let chunk_type = match data[0] {
+            1 => ChunkType::Key,
+            2 => ChunkType::Delta,
+            3 => ChunkType::Config,
+            t => return Err(ChunkError::UnknownType(t)),
+        };
+        let mut ts = [0u8; 8];
+        ts.copy_from_slice(&data[1..9]);
+        let mut duration = [0u8; 8];
+        duration.copy_from_slice(&data[9..17]);
+        Ok(Self {
+            chunk_type,
+            timestamp: i64::from_be_bytes(ts),
+            duration: u64::from_be_bytes(duration),
+        })
+    }
+
+    /// Check that the chunk doesn't go back in time from `last`, the timestamp of the
+    /// previous chunk of the same publisher.
+    pub fn check_after(&self, last: Option<i64>) -> Result<(), ChunkError> {
+        match last {
+            Some(last) if self.timestamp < last => Err(ChunkError::TimestampWentBack {
+                last,
+                timestamp: self.timestamp,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0] = self.chunk_type as u8;
+        buf[1..9].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[9..17].copy_from_slice(&self.duration.to_be_bytes());
+        buf
+    }
+}
+
+/// A whole chunk as received from a publisher, header included.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub header: ChunkHeader,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn parse(data: Vec<u8>, max_size: usize) -> Result<Self, ChunkError> {
+        if data.len() > max_size {
+            return Err(ChunkError::TooLarge(data.len()));
+        }
+        let header = ChunkHeader::parse(&data)?;
+        Ok(Self { header, data })
+    }
+
+    pub fn new(header: ChunkHeader, payload: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+        data.extend_from_slice(&header.encode());
+        data.extend_from_slice(payload);
+        Self { header, data }
+    }
+
+    pub fn is_key(&self) -> bool {
+        self.header.chunk_type == ChunkType::Key
+    }
+
+    pub fn is_config(&self) -> bool {
+        self.header.chunk_type == ChunkType::Config
+    }
+
+    /// header and payload, as sent to viewers.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The encoded media without the header.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[HEADER_LEN..]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// The VideoDecoderConfig / AudioDecoderConfig of a config chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecoderConfig {
+    pub codec: String,
+    /// every member but `description`, as sent by the publisher.
+    pub json: Value,
+    pub description: Vec<u8>,
+}
+
+impl DecoderConfig {
+    /// `json` is the decoder config without `description`, and must have a `codec`.
+    pub fn new(json: Value, description: Vec<u8>) -> Self {
+        let codec = json
+            .get("codec")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        Self {
+            codec,
+            json,
+            description,
+        }
+    }
+
+    /// The config chunk carrying this config, sent before the frame at `timestamp`.
+    pub fn to_chunk(&self, timestamp: i64) -> Chunk {
+        let json = self.json.to_string();
+        let mut payload = Vec::with_capacity(2 + json.len() + self.description.len());
+        payload.extend_from_slice(&(json.len() as u16).to_be_bytes());
+        payload.extend_from_slice(json.as_bytes());
+        payload.extend_from_slice(&self.description);
+        Chunk::new(
+            ChunkHeader {
+                chunk_type: ChunkType::Config,
+                timestamp,
+                duration: 0,
+            },
+            &payload,
+        )
+    }
+
+    pub fn parse(chunk: &Chunk) -> Result<Self, ChunkError> {
+        let payload = chunk.payload();
+        if payload.len() < 2 {
+            return Err(ChunkError::InvalidConfig);
+        }
+        let json_len = usize::from(u16::from_be_bytes([payload[0], payload[1]]));
+        if payload.len() < 2 + json_len {
+            return Err(ChunkError::InvalidConfig);
+        }
+        let json: Value = serde_json::from_slice(&payload[2..2 + json_len])
+            .map_err(|_| ChunkError::InvalidConfig)?;
+        let codec = match json.get("codec").and_then(Value::as_str) {
+            Some(c) if !c.is_empty() => c.to_string(),
+            _ => return Err(ChunkError::InvalidConfig),
+        };
+        Ok(Self {
+            codec,
+            json,
+            description: payload[2 + json_len..].to_vec(),
+        })
+    }
+
+    pub fn width(&self) -> Option<u64> {
+        self.json.get("codedWidth").and_then(Value::as_u64)
+    }
+
+    pub fn height(&self) -> Option<u64> {
+        self.json.get("codedHeight").and_then(Value::as_u64)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    TooShort(usize),
+    TooLarge(usize),
+    UnknownType(u8),
+    TimestampWentBack { last: i64, timestamp: i64 },
+    InvalidConfig,
+}
+
+impl ChunkError {
+    /// Label used by the metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::TooShort(_) => "too_short",
+            Self::TooLarge(_) => "too_large",
+            Self::UnknownType(_) => "unknown_type",
+            Self::TimestampWentBack { .. } => "timestamp_went_back",
+            Self::InvalidConfig => "invalid_config",
+        }
+    }
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TooShort(n) => write!(f, "chunk of {} bytes is shorter than its header", n),
+            Self::TooLarge(n) => write!(f, "chunk of {} bytes is too large", n),
+            Self::UnknownType(t) => write!(f, "unknown chunk type {}", t),
+            Self::TimestampWentBack { last, timestamp } => {
+                write!(f, "timestamp {} is before {}", timestamp, last)
+            }
+            Self::InvalidConfig => write!(f, "invalid decoder config"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(chunk_type: ChunkType, timestamp: i64) -> ChunkHeader {
+        ChunkHeader {
+            chunk_type,
+            timestamp,
+            duration: 33_333,
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let h = header(ChunkType::Key, -5);
+        assert_eq!(ChunkHeader::parse(&h.encode()), Ok(h));
+        let chunk = Chunk::parse(Chunk::new(h, b"frame").as_bytes().to_vec(), 1024).unwrap();
+        assert_eq!(chunk.header, h);
+        assert_eq!(chunk.payload(), b"frame");
+        assert!(chunk.is_key());
+    }
+
+    #[test]
+    fn short_header() {
+        let data = header(ChunkType::Delta, 0).encode();
+        assert_eq!(
+            ChunkHeader::parse(&data[..HEADER_LEN - 1]),
+            Err(ChunkError::TooShort(HEADER_LEN - 1))
+        );
+        assert_eq!(ChunkHeader::parse(&[]), Err(ChunkError::TooShort(0)));
+    }
+
+    #[test]
+    fn unknown_type() {
+        let mut data = header(ChunkType::Delta, 0).encode();
+        data[0] = 9;
+        assert_eq!(ChunkHeader::parse(&data), Err(ChunkError::UnknownType(9)));
+        // clock chunks come from the server only.
+        data[0] = ChunkType::Clock as u8;
+        assert_eq!(ChunkHeader::parse(&data), Err(ChunkError::UnknownType(4)));
+    }
+
+    #[test]
+    fn size_limit() {
+        let data = Chunk::new(header(ChunkType::Delta, 0), &[0; 100])
+            .as_bytes()
+            .to_vec();
+        let len = data.len();
+        assert!(Chunk::parse(data.clone(), len).is_ok());
+        assert_eq!(
+            Chunk::parse(data, len - 1).map(|c| c.len()),
+            Err(ChunkError::TooLarge(len))
+        );
+    }
+
+    #[test]
+    fn timestamps_never_go_back() {
+        let h = header(ChunkType::Delta, 100);
+        assert_eq!(h.check_after(None), Ok(()));
+        assert_eq!(h.check_after(Some(100)), Ok(()));
+        assert_eq!(h.check_after(Some(99)), Ok(()));
+        assert_eq!(
+            h.check_after(Some(101)),
+            Err(ChunkError::TimestampWentBack {
+                last: 101,
+                timestamp: 100
+            })
+        );
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let config = DecoderConfig::new(
+            json!({"codec": "avc1.42e01f", "codedWidth": 640, "codedHeight": 480}),
+            vec![1, 2, 3],
+        );
+        let chunk = config.to_chunk(7);
+        assert!(chunk.is_config());
+        assert_eq!(DecoderConfig::parse(&chunk), Ok(config));
+        let empty = DecoderConfig::new(json!({"codec": ""}), Vec::new()).to_chunk(0);
+        assert_eq!(DecoderConfig::parse(&empty), Err(ChunkError::InvalidConfig));
+    }
+}
@@ -0,0 +1,540 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Media chunks over WebTransport datagrams, shared by the server (`/video/datagram`,
+//! `/audio/datagram`) and the receivers (the relay and `viewer_worker.js`).
+//!
+//! A chunk, header included as on streams, is a frame split into `count` data fragments
+//! of `ceil(frame_len / count)` bytes, the last one shorter. Every datagram is
+//!
+//! `frame(4) + frame_len(4) + index(2) + count(2) + group(1) + payload`
+//!
+//! big endian, `frame` counting the chunks sent on the channel. With FEC a parity fragment
+//! follows every `group` data fragments: its `index` is `count + n` for the `n`th group and
+//! its payload the XOR of the fragments of the group, padded to the fragment size, so the
+//! receiver can rebuild one lost fragment per group. `group` is 0 without FEC.
+//!
+//! Instead, or on top of it, receivers can ask for lost fragments again with NACK datagrams,
+//! a list of `frame(4) + n(1) + index(2) * n`. A fragment is reported missing once a later
+//! fragment of its frame or of a newer frame arrived, and only once.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Range;
+use std::time::Duration;
+
+pub const HEADER_LEN: usize = 13;
+
+/// Largest datagram sent, so a fragment fits into a packet on most paths.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Incomplete frames a receiver waits for; older ones are given up.
+const MAX_PENDING_FRAMES: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DatagramConfig {
+    // parity fragments per 100 data fragments, 0 without FEC.
+    pub fec_overhead: u32,
+    // percentage of the fragments the server drops on purpose, to try FEC on a lossy link.
+    pub simulated_loss: u32,
+    // how long after a frame was sent its fragments may still arrive, zero disables
+    // retransmissions.
+    pub deadline: Duration,
+}
+
+impl DatagramConfig {
+    /// Data fragments covered by one parity fragment.
+    pub fn group(&self) -> u8 {
+        100u32
+            .checked_div(self.fec_overhead)
+            .map_or(0, |group| group.clamp(1, u32::from(u8::MAX)) as u8)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub frame: u32,
+    pub frame_len: u32,
+    pub index: u16,
+    pub count: u16,
+    pub group: u8,
+}
+
+impl FragmentHeader {
+    pub fn parse(buf: &[u8]) -> Option<(Self, &[u8])> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let header = Self {
+            frame: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            frame_len: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            index: u16::from_be_bytes([buf[8], buf[9]]),
+            count: u16::from_be_bytes([buf[10], buf[11]]),
+            group: buf[12],
+        };
+        if header.count == 0 {
+            return None;
+        }
+        Some((header, &buf[HEADER_LEN..]))
+    }
+
+    fn write(&self, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+        out.extend_from_slice(&self.frame.to_be_bytes());
+        out.extend_from_slice(&self.frame_len.to_be_bytes());
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.count.to_be_bytes());
+        out.push(self.group);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn fragment_size(&self) -> usize {
+        let (len, count) = (self.frame_len as usize, usize::from(self.count));
+        (len + count - 1) / count
+    }
+
+    /// Bytes of the frame carried by data fragment `index`.
+    fn range(&self, index: u16) -> Range<usize> {
+        let size = self.fragment_size();
+        let start = (usize::from(index) * size).min(self.frame_len as usize);
+        start..(start + size).min(self.frame_len as usize)
+    }
+
+    /// Data fragments covered by parity fragment `n`.
+    fn group_range(&self, n: u16) -> Range<u16> {
+        let group = u16::from(self.group);
+        let start = n.saturating_mul(group).min(self.count);
+        start..start.saturating_add(group).min(self.count)
+    }
+
+    pub fn is_parity(&self) -> bool {
+        self.index >= self.count
+    }
+}
+
+/// Datagrams of a frame no larger than `max_size`, with a parity fragment after every
+/// `group` data fragments when `group` isn't 0. None when `max_size` leaves no room for data.
+pub fn fragment(frame: u32, data: &[u8], max_size: usize, group: u8) -> Vec<Vec<u8>> {
+    let room = max_size.saturating_sub(HEADER_LEN);
+    if room == 0 {
+        return Vec::new();
+    }
+    let count = ((data.len() + room - 1) / room).max(1);
+    if count > usize::from(u16::MAX) / 2 {
+        return Vec::new();
+    }
+    let mut header = FragmentHeader {
+        frame,
+        frame_len: data.len() as u32,
+        index: 0,
+        count: count as u16,
+        group,
+    };
+    let size = header.fragment_size();
+    let mut out = Vec::new();
+    let mut parity = vec![0; size];
+    for index in 0..header.count {
+        let payload = &data[header.range(index)];
+        out.push(FragmentHeader { index, ..header }.write(payload));
+        if group == 0 {
+            continue;
+        }
+        xor(&mut parity, payload);
+        let n = index / u16::from(group);
+        if index + 1 == header.group_range(n).end {
+            header.index = header.count + n;
+            out.push(header.write(&parity));
+            parity.iter_mut().for_each(|b| *b = 0);
+        }
+    }
+    out
+}
+
+/// A NACK datagram for the fragments missing of each frame, dropping what doesn't fit
+/// into `MAX_DATAGRAM_SIZE`.
+pub fn nack(missing: &[(u32, Vec<u16>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (frame, indexes) in missing {
+        for part in indexes.chunks(usize::from(u8::MAX)) {
+            if out.len() + 5 + 2 * part.len() > MAX_DATAGRAM_SIZE {
+                return out;
+            }
+            out.extend_from_slice(&frame.to_be_bytes());
+            out.push(part.len() as u8);
+            for index in part {
+                out.extend_from_slice(&index.to_be_bytes());
+            }
+        }
+    }
+    out
+}
+
+/// The fragments listed in a NACK datagram, `None` when it is truncated.
+pub fn parse_nack(buf: &[u8]) -> Option<Vec<(u32, Vec<u16>)>> {
+    let mut missing = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let head = buf.get(pos..pos + 5)?;
+        let frame = u32::from_be_bytes([head[0], head[1], head[2], head[3]]);
+        let n = usize::from(head[4]);
+        pos += 5;
+        let indexes = buf
+            .get(pos..pos + 2 * n)?
+            .chunks(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        pos += 2 * n;
+        missing.push((frame, indexes));
+    }
+    Some(missing)
+}
+
+fn xor(into: &mut [u8], data: &[u8]) {
+    for (a, b) in into.iter_mut().zip(data) {
+        *a ^= b;
+    }
+}
+
+/// The fragments received of one frame.
+struct Partial {
+    header: FragmentHeader,
+    fragments: Vec<Option<Vec<u8>>>,
+    parity: BTreeMap<u16, Vec<u8>>,
+    missing: usize,
+    // highest data fragment received, and the fragments already asked for again.
+    last: u16,
+    nacked: BTreeSet<u16>,
+}
+
+impl Partial {
+    fn new(header: FragmentHeader) -> Self {
+        Self {
+            header,
+            fragments: vec![None; usize::from(header.count)],
+            parity: BTreeMap::new(),
+            missing: usize::from(header.count),
+            last: 0,
+            nacked: BTreeSet::new(),
+        }
+    }
+
+    fn insert(&mut self, index: u16, payload: &[u8]) {
+        if index >= self.header.count {
+            // a shorter parity would rebuild a cut fragment.
+            if payload.len() == self.header.fragment_size() {
+                self.parity
+                    .insert(index - self.header.count, payload.to_vec());
+            }
+            return;
+        }
+        self.last = self.last.max(index);
+        let slot = &mut self.fragments[usize::from(index)];
+        if slot.is_none() && payload.len() == self.header.range(index).len() {
+            *slot = Some(payload.to_vec());
+            self.missing -= 1;
+        }
+    }
+
+    /// Rebuild the fragments that are the only one missing in a group with its parity.
+    /// Returns how many were rebuilt.
+    fn recover(&mut self) -> usize {
+        let mut recovered = 0;
+        for (&n, parity) in &self.parity {
+            let range = self.header.group_range(n);
+            let mut lost = range
+                .clone()
+                .filter(|&i| self.fragments[usize::from(i)].is_none());
+            let index = match (lost.next(), lost.next()) {
+                (Some(index), None) => index,
+                _ => continue,
+            };
+            let mut data = parity.clone();
+            for i in range {
+                if let Some(fragment) = &self.fragments[usize::from(i)] {
+                    xor(&mut data, fragment);
+                }
+            }
+            data.truncate(self.header.range(index).len());
+            self.fragments[usize::from(index)] = Some(data);
+            self.missing -= 1;
+            recovered += 1;
+        }
+        recovered
+    }
+
+    /// Data fragments not received and not asked for yet, up to `end`.
+    fn nack(&mut self, end: u16) -> Vec<u16> {
+        let missing: Vec<u16> = (0..end)
+            .filter(|&i| self.fragments[usize::from(i)].is_none() && !self.nacked.contains(&i))
+            .collect();
+        self.nacked.extend(&missing);
+        missing
+    }
+
+    fn assemble(self) -> Vec<u8> {
+        self.fragments.into_iter().flatten().flatten().collect()
+    }
+}
+
+/// Puts the frames of a session back together from its datagrams.
+#[derive(Default)]
+pub struct Reassembler {
+    frames: BTreeMap<u32, Partial>,
+    // frames completed or given up lately, whose late fragments are ignored.
+    done: VecDeque<u32>,
+    // newest frame seen.
+    newest: Option<u32>,
+    // fragments rebuilt from parity, and frames given up.
+    pub recovered: u64,
+    pub lost: u64,
+}
+
+impl Reassembler {
+    /// Add a datagram, returning the frame it completed if any.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let (header, payload) = FragmentHeader::parse(datagram)?;
+        if self.done.contains(&header.frame) {
+            return None;
+        }
+        if self
+            .frames
+            .keys()
+            .next()
+            .map_or(false, |&oldest| header.frame < oldest)
+        {
+            // older than every frame still pending, it would be given up first.
+            return None;
+        }
+        self.newest = Some(self.newest.map_or(header.frame, |n| n.max(header.frame)));
+        let partial = self
+            .frames
+            .entry(header.frame)
+            .or_insert_with(|| Partial::new(header));
+        if partial.header.count != header.count || partial.header.frame_len != header.frame_len {
+            return None;
+        }
+        partial.insert(header.index, payload);
+        if partial.missing > 0 {
+            self.recovered += partial.recover() as u64;
+        }
+        if partial.missing > 0 {
+            while self.frames.len() > MAX_PENDING_FRAMES {
+                let oldest = *self.frames.keys().next()?;
+                self.frames.remove(&oldest);
+                self.finish(oldest);
+                self.lost += 1;
+            }
+            return None;
+        }
+        let frame = self.frames.remove(&header.frame)?.assemble();
+        self.finish(header.frame);
+        Some(frame)
+    }
+
+    fn finish(&mut self, frame: u32) {
+        self.done.push_back(frame);
+        if self.done.len() > MAX_PENDING_FRAMES {
+            self.done.pop_front();
+        }
+    }
+
+    /// A NACK datagram for the fragments lost since the last call, if any.
+    pub fn nack(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest?;
+        let missing: Vec<_> = self
+            .frames
+            .iter_mut()
+            .filter_map(|(&frame, partial)| {
+                let end = if frame < newest {
+                    partial.header.count
+                } else {
+                    partial.last
+                };
+                let indexes = partial.nack(end);
+                if indexes.is_empty() {
+                    None
+                } else {
+                    Some((frame, indexes))
+                }
+            })
+            .collect();
+        if missing.is_empty() {
+            None
+        } else {
+            Some(nack(&missing))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Push the fragments but those at `lost`, returning the frame completed if any.
+    fn deliver(r: &mut Reassembler, fragments: &[Vec<u8>], lost: &[usize]) -> Option<Vec<u8>> {
+        let mut done = None;
+        for (i, f) in fragments.iter().enumerate() {
+            if !lost.contains(&i) {
+                done = done.or(r.push(f));
+            }
+        }
+        done
+    }
+
+    #[test]
+    fn fragments_fit() {
+        let data = frame(3000);
+        let fragments = fragment(1, &data, 1200, 0);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|f| f.len() <= 1200));
+        let (header, payload) = FragmentHeader::parse(&fragments[2]).unwrap();
+        assert_eq!((header.frame, header.frame_len, header.index), (1, 3000, 2));
+        assert_eq!((header.count, header.group), (3, 0));
+        assert_eq!(payload, &data[2000..]);
+        assert_eq!(
+            deliver(&mut Reassembler::default(), &fragments, &[]),
+            Some(data)
+        );
+        // in any order.
+        let mut r = Reassembler::default();
+        assert_eq!(r.push(&fragments[2]), None);
+        assert_eq!(r.push(&fragments[0]), None);
+        assert_eq!(r.push(&fragments[1]).map(|f| f.len()), Some(3000));
+    }
+
+    #[test]
+    fn no_room_for_data() {
+        assert!(fragment(1, &frame(100), HEADER_LEN, 0).is_empty());
+        assert!(fragment(1, &frame(100), 0, 4).is_empty());
+        assert_eq!(fragment(1, &frame(100), HEADER_LEN + 1, 0).len(), 100);
+        assert_eq!(fragment(1, &[], 1200, 0).len(), 1);
+    }
+
+    #[test]
+    fn one_loss_per_group_is_recovered() {
+        let data = frame(10_000);
+        // 9 data fragments, parity after every 4 and after the last one.
+        let fragments = fragment(7, &data, 1200, 4);
+        assert_eq!(fragments.len(), 9 + 3);
+        let parity: Vec<_> = fragments
+            .iter()
+            .filter_map(|f| FragmentHeader::parse(f))
+            .filter(|(h, _)| h.is_parity())
+            .map(|(h, _)| h.index)
+            .collect();
+        assert_eq!(parity, vec![9, 10, 11]);
+        // fragments 1 and 6 of the groups 0..4 and 4..8, and the short last one, 8.
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[1, 7, 10]), Some(data.clone()));
+        assert_eq!(r.recovered, 3);
+        // a lost parity fragment costs nothing when its group is complete.
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[4, 11]), Some(data));
+        assert_eq!(r.recovered, 0);
+    }
+
+    #[test]
+    fn two_losses_in_a_group_are_not() {
+        let data = frame(10_000);
+        let fragments = fragment(7, &data, 1200, 4);
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[0, 2]), None);
+        assert_eq!(r.recovered, 0);
+        // nor one fragment with the parity of its group.
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[5, 9]), None);
+    }
+
+    #[test]
+    fn nacks() {
+        let data = frame(5000);
+        let fragments = fragment(3, &data, 1200, 0);
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[1, 2, 4]), None);
+        // 4 isn't known to be lost until a later fragment or frame arrives.
+        let missing = parse_nack(&r.nack().unwrap()).unwrap();
+        assert_eq!(missing, vec![(3, vec![1, 2])]);
+        assert_eq!(r.nack(), None);
+        r.push(&fragment(4, &frame(10), 1200, 0)[0]).unwrap();
+        assert_eq!(parse_nack(&r.nack().unwrap()).unwrap(), vec![(3, vec![4])]);
+        // retransmissions complete the frame.
+        assert_eq!(deliver(&mut r, &fragments, &[0, 3]), Some(data));
+    }
+
+    #[test]
+    fn short_parity_is_ignored() {
+        let data = frame(3000);
+        let mut fragments = fragment(2, &data, 1200, 4);
+        // the parity of fragments 0..3, cut.
+        let parity = fragments.pop().unwrap();
+        fragments.push(parity[..parity.len() - 10].to_vec());
+        let mut r = Reassembler::default();
+        assert_eq!(deliver(&mut r, &fragments, &[2]), None);
+        assert_eq!(r.recovered, 0);
+        fragments.push(parity);
+        assert_eq!(r.push(&fragments[4]).map(|f| f.len()), Some(3000));
+        assert_eq!(r.recovered, 1);
+    }
+
+    #[test]
+    fn given_up_frames_stay_lost() {
+        let mut r = Reassembler::default();
+        // two fragments each, only the first arrives.
+        let frames: Vec<_> = (0..=MAX_PENDING_FRAMES as u32 + 1)
+            .map(|f| fragment(f, &frame(2000), 1200, 0))
+            .collect();
+        for fragments in &frames {
+            assert_eq!(r.push(&fragments[0]), None);
+        }
+        assert_eq!(r.lost, 2);
+        assert_eq!(r.frames.len(), MAX_PENDING_FRAMES);
+        // the rest of a frame given up doesn't bring it back, nor count it again.
+        assert_eq!(r.push(&frames[0][1]), None);
+        assert_eq!(r.push(&frames[1][1]), None);
+        assert_eq!(r.lost, 2);
+        assert_eq!(r.frames.len(), MAX_PENDING_FRAMES);
+        assert!(!r.frames.contains_key(&0));
+        // the pending ones still complete.
+        assert_eq!(r.push(&frames[2][1]).map(|f| f.len()), Some(2000));
+    }
+
+    #[test]
+    fn nack_round_trip() {
+        let missing = vec![(1, vec![0, 5]), (u32::MAX, (0..300).collect())];
+        let buf = nack(&missing);
+        // more than 255 fragments of a frame take two entries.
+        assert_eq!(
+            parse_nack(&buf).unwrap(),
+            vec![
+                (1, vec![0, 5]),
+                (u32::MAX, (0..255).collect()),
+                (u32::MAX, (255..300).collect()),
+            ]
+        );
+        assert_eq!(parse_nack(&buf[..buf.len() - 1]), None);
+        // what doesn't fit into a datagram is left out.
+        let many: Vec<_> = (0..10).map(|f| (f, (0..100).collect())).collect();
+        let buf = nack(&many);
+        assert!(buf.len() <= MAX_DATAGRAM_SIZE);
+        assert_eq!(parse_nack(&buf).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn fec_group() {
+        let config = |fec_overhead| DatagramConfig {
+            fec_overhead,
+            ..DatagramConfig::default()
+        };
+        assert_eq!(config(0).group(), 0);
+        assert_eq!(config(25).group(), 4);
+        assert_eq!(config(100).group(), 1);
+        assert_eq!(config(1000).group(), 1);
+    }
+}
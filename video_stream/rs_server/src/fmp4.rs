@@ -0,0 +1,672 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fragmented MP4 muxing of WebCodecs chunks for MSE / Warp style players.
+//!
+//! The init segment (ftyp + moov) is built from the decoder config of the channel,
+//! and every group of pictures becomes one fragment (moof + mdat).
+//! Media time is in microseconds, as the WebCodecs timestamps.
+
+use neqo_common::qwarn;
+
+use crate::chunk::{Chunk, DecoderConfig};
+
+const TRACK_ID: u32 = 1;
+const TIMESCALE: u32 = 1_000_000;
+
+/// Fragments are cut at this many samples even without a keyframe.
+const MAX_FRAGMENT_SAMPLES: usize = 300;
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Writes ISO BMFF boxes, patching their sizes when they end.
+#[derive(Default)]
+struct BoxWriter {
+    buf: Vec<u8>,
+    open: Vec<usize>,
+}
+
+impl BoxWriter {
+    fn begin(&mut self, kind: &[u8; 4]) {
+        self.open.push(self.buf.len());
+        self.u32(0);
+        self.bytes(kind);
+    }
+
+    fn begin_full(&mut self, kind: &[u8; 4], version: u8, flags: u32) {
+        self.begin(kind);
+        self.u32(u32::from(version) << 24 | flags);
+    }
+
+    fn end(&mut self) {
+        let start = self.open.pop().expect("no open box");
+        let size = (self.buf.len() - start) as u32;
+        self.buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    fn zeros(&mut self, n: usize) {
+        self.buf.resize(self.buf.len() + n, 0);
+    }
+
+    fn matrix(&mut self) {
+        for v in &MATRIX {
+            self.u32(*v);
+        }
+    }
+}
+
+/// Sample entry of the track, chosen from the WebCodecs codec string.
+enum Entry {
+    Visual { kind: [u8; 4], config: ConfigBox },
+    Opus,
+    Aac,
+}
+
+enum ConfigBox {
+    // avcC, hvcC or av1C holding the description as is.
+    Description([u8; 4]),
+    // vpcC built from the codec string.
+    Vp {
+        profile: u8,
+        level: u8,
+        bit_depth: u8,
+    },
+}
+
+fn entry(config: &DecoderConfig) -> Option<Entry> {
+    let codec = config.codec.as_str();
+    let prefix = codec.split('.').next().unwrap_or("");
+    let has_description = !config.description.is_empty();
+    match prefix {
+        "avc1" | "avc3" if has_description => Some(Entry::Visual {
+            kind: *b"avc1",
+            config: ConfigBox::Description(*b"avcC"),
+        }),
+        "hvc1" | "hev1" if has_description => Some(Entry::Visual {
+            kind: *b"hvc1",
+            config: ConfigBox::Description(*b"hvcC"),
+        }),
+        "av01" if has_description => Some(Entry::Visual {
+            kind: *b"av01",
+            config: ConfigBox::Description(*b"av1C"),
+        }),
+        "vp8" => Some(Entry::Visual {
+            kind: *b"vp08",
+            config: ConfigBox::Vp {
+                profile: 0,
+                level: 10,
+                bit_depth: 8,
+            },
+        }),
+        "vp09" => {
+            // vp09.PP.LL.DD
+            let mut fields = codec.split('.').skip(1).map(|f| f.parse::<u8>().ok());
+            Some(Entry::Visual {
+                kind: *b"vp09",
+                config: ConfigBox::Vp {
+                    profile: fields.next().flatten().unwrap_or(0),
+                    level: fields.next().flatten().unwrap_or(10),
+                    bit_depth: fields.next().flatten().unwrap_or(8),
+                },
+            })
+        }
+        "opus" => Some(Entry::Opus),
+        "mp4a" if has_description => Some(Entry::Aac),
+        _ => None,
+    }
+}
+
+fn json_u32(config: &DecoderConfig, name: &str) -> Option<u32> {
+    config
+        .json
+        .get(name)
+        .and_then(serde_json::Value::as_u64)
+        .and_then(|v| u32::try_from(v).ok())
+}
+
+/// ftyp + moov for a single track described by `config`,
+/// `None` when the codec can't be carried in MP4 here.
+pub fn init_segment(config: &DecoderConfig) -> Option<Vec<u8>> {
+    let entry = entry(config)?;
+    let is_video = matches!(entry, Entry::Visual { .. });
+    // the sample entries hold 16 bit sizes, and tkhd and the audio sample entry 16.16 values.
+    let width = json_u32(config, "codedWidth").unwrap_or(0).min(0xffff);
+    let height = json_u32(config, "codedHeight").unwrap_or(0).min(0xffff);
+    let channels = json_u32(config, "numberOfChannels").unwrap_or(2);
+    let sample_rate = json_u32(config, "sampleRate").unwrap_or(48000);
+
+    let mut w = BoxWriter::default();
+    w.begin(b"ftyp");
+    w.bytes(b"isom");
+    w.u32(0x200);
+    w.bytes(b"isomiso6mp41");
+    w.end();
+
+    w.begin(b"moov");
+    w.begin_full(b"mvhd", 0, 0);
+    w.u32(0); // creation_time
+    w.u32(0); // modification_time
+    w.u32(1000); // timescale
+    w.u32(0); // duration
+    w.u32(0x0001_0000); // rate
+    w.u16(0x0100); // volume
+    w.zeros(10);
+    w.matrix();
+    w.zeros(24);
+    w.u32(TRACK_ID + 1); // next_track_ID
+    w.end();
+
+    w.begin(b"trak");
+    w.begin_full(b"tkhd", 0, 0x3);
+    w.u32(0);
+    w.u32(0);
+    w.u32(TRACK_ID);
+    w.u32(0);
+    w.u32(0); // duration
+    w.zeros(8);
+    w.u16(0); // layer
+    w.u16(0); // alternate_group
+    w.u16(if is_video { 0 } else { 0x0100 });
+    w.u16(0);
+    w.matrix();
+    w.u32(width << 16);
+    w.u32(height << 16);
+    w.end();
+
+    w.begin(b"mdia");
+    w.begin_full(b"mdhd", 0, 0);
+    w.u32(0);
+    w.u32(0);
+    w.u32(TIMESCALE);
+    w.u32(0);
+    w.u16(0x55c4); // und
+    w.u16(0);
+    w.end();
+    w.begin_full(b"hdlr", 0, 0);
+    w.u32(0);
+    w.bytes(if is_video { b"vide" } else { b"soun" });
+    w.zeros(12);
+    w.bytes(if is_video {
+        b"VideoHandler\0"
+    } else {
+        b"SoundHandler\0"
+    });
+    w.end();
+
+    w.begin(b"minf");
+    if is_video {
+        w.begin_full(b"vmhd", 0, 1);
+        w.zeros(8);
+    } else {
+        w.begin_full(b"smhd", 0, 0);
+        w.zeros(4);
+    }
+    w.end();
+    w.begin(b"dinf");
+    w.begin_full(b"dref", 0, 0);
+    w.u32(1);
+    w.begin_full(b"url ", 0, 1);
+    w.end();
+    w.end();
+    w.end();
+
+    w.begin(b"stbl");
+    w.begin_full(b"stsd", 0, 0);
+    w.u32(1);
+    match entry {
+        Entry::Visual { kind, config: cb } => {
+            w.begin(&kind);
+            w.zeros(6);
+            w.u16(1); // data_reference_index
+            w.zeros(16);
+            w.u16(width as u16);
+            w.u16(height as u16);
+            w.u32(0x0048_0000); // 72 dpi
+            w.u32(0x0048_0000);
+            w.u32(0);
+            w.u16(1); // frame_count
+            w.zeros(32); // compressorname
+            w.u16(0x0018); // depth
+            w.u16(0xffff);
+            match cb {
+                ConfigBox::Description(kind) => {
+                    w.begin(&kind);
+                    w.bytes(&config.description);
+                    w.end();
+                }
+                ConfigBox::Vp {
+                    profile,
+                    level,
+                    bit_depth,
+                } => {
+                    w.begin_full(b"vpcC", 1, 0);
+                    w.u8(profile);
+                    w.u8(level);
+                    // 4:2:0 colocated, limited range.
+                    w.u8(bit_depth << 4 | 1 << 1);
+                    w.u8(2); // colour_primaries: unspecified
+                    w.u8(2); // transfer_characteristics
+                    w.u8(2); // matrix_coefficients
+                    w.u16(0);
+                    w.end();
+                }
+            }
+            w.end();
+        }
+        Entry::Opus | Entry::Aac => {
+            let opus = matches!(entry, Entry::Opus);
+            w.begin(if opus { b"Opus" } else { b"mp4a" });
+            w.zeros(6);
+            w.u16(1);
+            w.zeros(8);
+            w.u16(channels as u16);
+            w.u16(16); // samplesize
+            w.zeros(4);
+            // dOps keeps the exact rate when it doesn't fit.
+            w.u32(sample_rate.min(0xffff) << 16);
+            if opus {
+                // OpusHead description carries the pre-skip, little endian.
+                let d = &config.description;
+                let pre_skip = if d.len() >= 12 && d.starts_with(b"OpusHead") {
+                    u16::from_le_bytes([d[10], d[11]])
+                } else {
+                    0
+                };
+                w.begin(b"dOps");
+                w.u8(0);
+                w.u8(channels as u8);
+                w.u16(pre_skip);
+                w.u32(sample_rate);
+                w.u16(0); // output gain
+                w.u8(0); // channel mapping family
+                w.end();
+            } else {
+                esds(&mut w, &config.description);
+            }
+            w.end();
+        }
+    }
+    w.end(); // stsd
+    // the samples are in the fragments, the tables stay empty.
+    for kind in &[b"stts", b"stsc", b"stsz", b"stco"] {
+        w.begin_full(kind, 0, 0);
+        if *kind == b"stsz" {
+            w.u32(0); // sample_size
+        }
+        w.u32(0);
+        w.end();
+    }
+    w.end(); // stbl
+    w.end(); // minf
+    w.end(); // mdia
+    w.end(); // trak
+
+    w.begin(b"mvex");
+    w.begin_full(b"trex", 0, 0);
+    w.u32(TRACK_ID);
+    w.u32(1); // default_sample_description_index
+    w.u32(0);
+    w.u32(0);
+    w.u32(0);
+    w.end();
+    w.end();
+    w.end(); // moov
+    Some(w.buf)
+}
+
+/// esds holding the AudioSpecificConfig of AAC.
+fn esds(w: &mut BoxWriter, asc: &[u8]) {
+    let asc = &asc[..asc.len().min(100)];
+    let dsi_len = asc.len() as u8;
+    let dcd_len = 13 + 2 + dsi_len;
+    w.begin_full(b"esds", 0, 0);
+    w.u8(0x03); // ES_Descriptor
+    w.u8(3 + 2 + dcd_len + 3);
+    w.u16(0); // ES_ID
+    w.u8(0);
+    w.u8(0x04); // DecoderConfigDescriptor
+    w.u8(dcd_len);
+    w.u8(0x40); // Audio ISO/IEC 14496-3
+    w.u8(0x15); // audio stream
+    w.zeros(3); // bufferSizeDB
+    w.u32(0); // maxBitrate
+    w.u32(0); // avgBitrate
+    w.u8(0x05); // DecoderSpecificInfo
+    w.u8(dsi_len);
+    w.bytes(asc);
+    w.u8(0x06); // SLConfigDescriptor
+    w.u8(1);
+    w.u8(2);
+    w.end();
+}
+
+/// moof + mdat of `samples`.
+pub fn fragment(sequence: u32, samples: &[Chunk]) -> Vec<u8> {
+    let base = samples.first().map_or(0, |c| c.header.timestamp.max(0));
+    let mut w = BoxWriter::default();
+    w.begin(b"moof");
+    w.begin_full(b"mfhd", 0, 0);
+    w.u32(sequence);
+    w.end();
+    w.begin(b"traf");
+    w.begin_full(b"tfhd", 0, 0x02_0000); // default-base-is-moof
+    w.u32(TRACK_ID);
+    w.end();
+    w.begin_full(b"tfdt", 1, 0);
+    w.u64(base as u64);
+    w.end();
+    // data-offset, sample-duration, sample-size and sample-flags present.
+    w.begin_full(b"trun", 0, 0x0701);
+    w.u32(samples.len() as u32);
+    let data_offset_at = w.buf.len();
+    w.u32(0);
+    for (i, chunk) in samples.iter().enumerate() {
+        let duration = match samples.get(i + 1) {
+            // B-frames go back in time, they last nothing rather than a wrapped duration.
+            Some(next) => next
+                .header
+                .timestamp
+                .saturating_sub(chunk.header.timestamp)
+                .max(0) as u64,
+            None => chunk.header.duration,
+        };
+        w.u32(duration.min(u64::from(u32::MAX)) as u32);
+        w.u32(chunk.payload().len() as u32);
+        w.u32(if chunk.is_key() {
+            SAMPLE_FLAGS_SYNC
+        } else {
+            SAMPLE_FLAGS_NON_SYNC
+        });
+    }
+    w.end();
+    w.end(); // traf
+    w.end(); // moof
+    let data_offset = (w.buf.len() + 8) as u32;
+    w.buf[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+    w.begin(b"mdat");
+    for chunk in samples {
+        w.bytes(chunk.payload());
+    }
+    w.end();
+    w.buf
+}
+
+/// Groups the chunks of a channel into fragments.
+#[derive(Default)]
+pub struct Fmp4Muxer {
+    // id of the current init segment, bumped on every config change.
+    pub init_id: u32,
+    pub init: Option<Vec<u8>>,
+    samples: Vec<Chunk>,
+    sequence: u32,
+    // a keyframe was seen since the config, fragments can start.
+    synced: bool,
+}
+
+impl Fmp4Muxer {
+    /// Build the init segment of a new decoder config.
+    /// Samples of the previous config are dropped.
+    pub fn configure(&mut self, config: &DecoderConfig) -> Option<&[u8]> {
+        self.samples.clear();
+        self.synced = false;
+        self.init = init_segment(config);
+        if self.init.is_none() {
+            qwarn!("codec {} is not supported in fMP4", config.codec);
+            return None;
+        }
+        self.init_id += 1;
+        self.init.as_deref()
+    }
+
+    /// Add a chunk, returning the fragment it completed if any:
+    /// a keyframe closes the group of pictures before it.
+    pub fn push(&mut self, chunk: &Chunk) -> Option<(i64, Vec<u8>)> {
+        // nothing can be muxed without an init segment.
+        self.init.as_ref()?;
+        let done = if (chunk.is_key() && !self.samples.is_empty())
+            || self.samples.len() >= MAX_FRAGMENT_SAMPLES
+        {
+            self.sequence += 1;
+            let timestamp = self.samples[0].header.timestamp;
+            let fragment = fragment(self.sequence, &self.samples);
+            self.samples.clear();
+            Some((timestamp, fragment))
+        } else {
+            None
+        };
+        self.synced |= chunk.is_key();
+        if self.synced {
+            self.samples.push(chunk.clone());
+        }
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+    use serde_json::json;
+
+    /// The boxes in `data` as (type, body), checking that their sizes add up.
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            assert!(size >= 8 && size <= rest.len(), "box size {}", size);
+            out.push(([rest[4], rest[5], rest[6], rest[7]], &rest[8..size]));
+            rest = &rest[size..];
+        }
+        out
+    }
+
+    /// Body of the box at `path`, descending through container boxes.
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let (kind, rest) = path.split_first().unwrap();
+        let body = boxes(data)
+            .into_iter()
+            .find(|(k, _)| k == *kind)
+            .unwrap_or_else(|| panic!("no {}", String::from_utf8_lossy(*kind)))
+            .1;
+        if rest.is_empty() {
+            body
+        } else {
+            find(body, rest)
+        }
+    }
+
+    const STSD: [&[u8; 4]; 6] = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+
+    fn be32(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    fn config(json: serde_json::Value, description: &[u8]) -> DecoderConfig {
+        DecoderConfig::new(json, description.to_vec())
+    }
+
+    #[test]
+    fn video_init_segment() {
+        let avcc = [1, 0x64, 0, 0x1f, 0xff];
+        let init = init_segment(&config(
+            json!({"codec": "avc1.64001f", "codedWidth": 1280, "codedHeight": 720}),
+            &avcc,
+        ))
+        .unwrap();
+        let top: Vec<_> = boxes(&init).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(top, vec![*b"ftyp", *b"moov"]);
+        let moov: Vec<_> = boxes(find(&init, &[b"moov"]))
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(moov, vec![*b"mvhd", *b"trak", *b"mvex"]);
+
+        // version/flags, times, track id, reserved, duration, reserved, layer...matrix
+        let tkhd = find(&init, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!(be32(tkhd, 12), TRACK_ID);
+        assert_eq!(be32(tkhd, 76), 1280 << 16);
+        assert_eq!(be32(tkhd, 80), 720 << 16);
+        let hdlr = find(&init, &[b"moov", b"trak", b"mdia", b"hdlr"]);
+        assert_eq!(&hdlr[8..12], b"vide");
+
+        let stsd = find(&init, &STSD);
+        assert_eq!(be32(stsd, 4), 1);
+        let avc1 = find(&stsd[8..], &[b"avc1"]);
+        assert_eq!(u16::from_be_bytes([avc1[24], avc1[25]]), 1280);
+        assert_eq!(u16::from_be_bytes([avc1[26], avc1[27]]), 720);
+        assert_eq!(find(&avc1[78..], &[b"avcC"]), &avcc[..]);
+    }
+
+    #[test]
+    fn audio_init_segment() {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01]);
+        let init = init_segment(&config(
+            json!({"codec": "opus", "numberOfChannels": 2, "sampleRate": 48000}),
+            &head,
+        ))
+        .unwrap();
+        let stsd = find(&init, &STSD);
+        let opus = find(&stsd[8..], &[b"Opus"]);
+        assert_eq!(u16::from_be_bytes([opus[16], opus[17]]), 2);
+        assert_eq!(be32(opus, 24), 48000 << 16);
+        let dops = find(&opus[28..], &[b"dOps"]);
+        assert_eq!(dops[1], 2);
+        assert_eq!(u16::from_be_bytes([dops[2], dops[3]]), 0x0138);
+        assert_eq!(be32(dops, 4), 48000);
+    }
+
+    #[test]
+    fn large_values_are_clamped() {
+        let init = init_segment(&config(
+            json!({"codec": "vp8", "codedWidth": 70000, "codedHeight": 1u64 << 40}),
+            &[],
+        ))
+        .unwrap();
+        let tkhd = find(&init, &[b"moov", b"trak", b"tkhd"]);
+        assert_eq!(be32(tkhd, 76), 0xffff << 16);
+        // not a u32, as if missing.
+        assert_eq!(be32(tkhd, 80), 0);
+
+        let init =
+            init_segment(&config(json!({"codec": "opus", "sampleRate": 96000}), &[])).unwrap();
+        let stsd = find(&init, &STSD);
+        let opus = find(&stsd[8..], &[b"Opus"]);
+        assert_eq!(be32(opus, 24), 0xffff << 16);
+        assert_eq!(be32(find(&opus[28..], &[b"dOps"]), 4), 96000);
+    }
+
+    #[test]
+    fn unsupported_codec() {
+        assert!(init_segment(&config(json!({"codec": "flac"}), &[])).is_none());
+        // avc1 needs its avcC.
+        assert!(init_segment(&config(json!({"codec": "avc1.64001f"}), &[])).is_none());
+    }
+
+    fn chunk(chunk_type: ChunkType, timestamp: i64, payload: &[u8]) -> Chunk {
+        Chunk::new(
+            ChunkHeader {
+                chunk_type,
+                timestamp,
+                duration: 20_000,
+            },
+            payload,
+        )
+    }
+
+    #[test]
+    fn fragment_layout() {
+        let samples = [
+            chunk(ChunkType::Key, 1_000, b"key"),
+            chunk(ChunkType::Delta, 34_333, b"delta1"),
+            chunk(ChunkType::Delta, 67_666, b"d2"),
+        ];
+        let data = fragment(7, &samples);
+        let top: Vec<_> = boxes(&data).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(top, vec![*b"moof", *b"mdat"]);
+        assert_eq!(be32(find(&data, &[b"moof", b"mfhd"]), 4), 7);
+        let tfdt = find(&data, &[b"moof", b"traf", b"tfdt"]);
+        assert_eq!(&tfdt[4..12], &1_000u64.to_be_bytes());
+
+        let trun = find(&data, &[b"moof", b"traf", b"trun"]);
+        assert_eq!(be32(trun, 4), 3);
+        // the data offset, from the start of moof, is the first byte of the mdat payload.
+        let offset = be32(trun, 8) as usize;
+        assert_eq!(&data[offset..offset + 3], b"key");
+        let entries: Vec<_> = trun[12..]
+            .chunks(12)
+            .map(|e| (be32(e, 0), be32(e, 4), be32(e, 8)))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (33_333, 3, SAMPLE_FLAGS_SYNC),
+                (33_333, 6, SAMPLE_FLAGS_NON_SYNC),
+                (20_000, 2, SAMPLE_FLAGS_NON_SYNC),
+            ]
+        );
+        assert_eq!(find(&data, &[b"mdat"]), b"keydelta1d2");
+    }
+
+    #[test]
+    fn decreasing_timestamps() {
+        let samples = [
+            chunk(ChunkType::Key, 0, b"i"),
+            chunk(ChunkType::Delta, 66_666, b"p"),
+            chunk(ChunkType::Delta, 33_333, b"b"),
+            chunk(ChunkType::Delta, i64::MAX, b"x"),
+        ];
+        let data = fragment(1, &samples);
+        let trun = find(&data, &[b"moof", b"traf", b"trun"]);
+        let durations: Vec<_> = trun[12..].chunks(12).map(|e| be32(e, 0)).collect();
+        assert_eq!(durations, vec![66_666, 0, u32::MAX, 20_000]);
+    }
+
+    #[test]
+    fn muxer_cuts_at_keyframes() {
+        let mut muxer = Fmp4Muxer::default();
+        assert!(muxer.push(&chunk(ChunkType::Key, 0, b"k")).is_none());
+        let vp8 = config(
+            json!({"codec": "vp8", "codedWidth": 64, "codedHeight": 64}),
+            &[],
+        );
+        assert!(muxer.configure(&vp8).is_some());
+        assert_eq!(muxer.init_id, 1);
+        // nothing before the first keyframe.
+        assert!(muxer.push(&chunk(ChunkType::Delta, 0, b"d")).is_none());
+        assert!(muxer.push(&chunk(ChunkType::Key, 10, b"k1")).is_none());
+        assert!(muxer.push(&chunk(ChunkType::Delta, 20, b"d1")).is_none());
+        let (timestamp, data) = muxer.push(&chunk(ChunkType::Key, 30, b"k2")).unwrap();
+        assert_eq!(timestamp, 10);
+        assert_eq!(find(&data, &[b"mdat"]), b"k1d1");
+        assert_eq!(be32(find(&data, &[b"moof", b"mfhd"]), 4), 1);
+    }
+}
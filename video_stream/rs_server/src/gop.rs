@@ -0,0 +1,120 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The group of pictures of a layer, sent to new viewers so they can start decoding
+//! without waiting for the next keyframe.
+
+use crate::chunk::Chunk;
+
+/// Upper bound of the chunks kept for new members.
+const MAX_GOP_CHUNKS: usize = 300;
+
+#[derive(Default)]
+pub struct Gop {
+    // the last keyframe and the chunks after it.
+    chunks: Vec<Chunk>,
+    // the last keyframe, kept after the group of pictures is dropped for snapshots.
+    key: Option<Chunk>,
+}
+
+impl Gop {
+    /// Add a chunk, starting a new group at a keyframe. Chunks without a keyframe to start
+    /// from aren't kept, and neither is a group longer than `MAX_GOP_CHUNKS`.
+    pub fn push(&mut self, chunk: Chunk) {
+        if chunk.is_key() {
+            self.chunks.clear();
+            self.key = Some(chunk.clone());
+        } else if self.chunks.is_empty() || self.chunks.len() >= MAX_GOP_CHUNKS {
+            self.chunks.clear();
+            return;
+        }
+        self.chunks.push(chunk);
+    }
+
+    /// Forget everything, when the chunks can't be decoded any more.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.key = None;
+    }
+
+    /// Forget the group unless its keyframe is at `timestamp` or later. A keyframe that
+    /// overtook its decoder config on another stream is kept.
+    pub fn clear_before(&mut self, timestamp: i64) {
+        if self.key.as_ref().map_or(true, |k| k.header.timestamp < timestamp) {
+            self.clear();
+        }
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    pub fn key(&self) -> Option<&Chunk> {
+        self.key.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+
+    fn chunk(chunk_type: ChunkType, timestamp: i64) -> Chunk {
+        let header = ChunkHeader {
+            chunk_type,
+            timestamp,
+            duration: 0,
+        };
+        Chunk::new(header, &[0])
+    }
+
+    fn timestamps(gop: &Gop) -> Vec<i64> {
+        gop.chunks().iter().map(|c| c.header.timestamp).collect()
+    }
+
+    #[test]
+    fn starts_at_keyframe() {
+        let mut gop = Gop::default();
+        gop.push(chunk(ChunkType::Delta, 0));
+        assert!(gop.chunks().is_empty());
+        gop.push(chunk(ChunkType::Key, 1));
+        gop.push(chunk(ChunkType::Delta, 2));
+        assert_eq!(timestamps(&gop), vec![1, 2]);
+        gop.push(chunk(ChunkType::Key, 3));
+        assert_eq!(timestamps(&gop), vec![3]);
+        assert_eq!(gop.key().map(|k| k.header.timestamp), Some(3));
+    }
+
+    #[test]
+    fn long_group_is_dropped() {
+        let mut gop = Gop::default();
+        gop.push(chunk(ChunkType::Key, 0));
+        for t in 1..MAX_GOP_CHUNKS as i64 {
+            gop.push(chunk(ChunkType::Delta, t));
+        }
+        assert_eq!(gop.chunks().len(), MAX_GOP_CHUNKS);
+        gop.push(chunk(ChunkType::Delta, MAX_GOP_CHUNKS as i64));
+        assert!(gop.chunks().is_empty());
+        // the keyframe is still there for snapshots.
+        assert!(gop.key().is_some());
+        gop.clear();
+        assert!(gop.key().is_none());
+    }
+
+    #[test]
+    fn keyframe_before_its_config() {
+        let mut gop = Gop::default();
+        gop.push(chunk(ChunkType::Key, 10));
+        gop.push(chunk(ChunkType::Delta, 11));
+        // the config of the keyframe at 10 arrives after it.
+        gop.clear_before(10);
+        assert_eq!(timestamps(&gop), vec![10, 11]);
+        // a config for a later frame makes the group useless.
+        gop.clear_before(12);
+        assert!(gop.chunks().is_empty());
+        assert!(gop.key().is_none());
+    }
+}
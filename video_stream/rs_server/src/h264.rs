@@ -0,0 +1,433 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! H.264 bitstream helpers: NAL unit types, the `avcC` record, splitting samples into NAL units
+//! and the picture size of a SPS.
+
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+/// RTP aggregation and fragmentation packets (RFC 6184).
+pub const NAL_STAP_A: u8 = 24;
+pub const NAL_FU_A: u8 = 28;
+
+pub fn nal_type(nal: &[u8]) -> u8 {
+    nal.first().map_or(0, |b| b & 0x1f)
+}
+
+/// `avc1.PPCCLL` from the profile, constraint flags and level of a SPS.
+pub fn codec_string(sps: &[u8]) -> Option<String> {
+    if sps.len() < 4 {
+        return None;
+    }
+    Some(format!("avc1.{:02x}{:02x}{:02x}", sps[1], sps[2], sps[3]))
+}
+
+/// AVCDecoderConfigurationRecord with one SPS and one PPS, NAL units length prefixed with 4 bytes.
+pub fn avcc(sps: &[u8], pps: &[u8]) -> Option<Vec<u8>> {
+    if sps.len() < 4 {
+        return None;
+    }
+    let mut out = vec![1, sps[1], sps[2], sps[3], 0xfc | 3, 0xe0 | 1];
+    out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    out.extend_from_slice(sps);
+    out.push(1);
+    out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    out.extend_from_slice(pps);
+    Some(out)
+}
+
+/// The fields of an AVCDecoderConfigurationRecord needed to split and packetize samples.
+pub struct Avcc {
+    // size of the NAL unit lengths of the samples.
+    pub length_size: usize,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+pub fn parse_avcc(avcc: &[u8]) -> Option<Avcc> {
+    if avcc.len() < 6 || avcc[0] != 1 {
+        return None;
+    }
+    let length_size = usize::from(avcc[4] & 3) + 1;
+    let mut pos = 6;
+    let sps = parameter_sets(avcc, &mut pos, usize::from(avcc[5] & 0x1f))?;
+    let count = usize::from(*avcc.get(pos)?);
+    pos += 1;
+    let pps = parameter_sets(avcc, &mut pos, count)?;
+    Some(Avcc {
+        length_size,
+        sps,
+        pps,
+    })
+}
+
+fn parameter_sets(avcc: &[u8], pos: &mut usize, count: usize) -> Option<Vec<Vec<u8>>> {
+    let mut sets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = usize::from(u16::from_be_bytes([*avcc.get(*pos)?, *avcc.get(*pos + 1)?]));
+        sets.push(avcc.get(*pos + 2..*pos + 2 + len)?.to_vec());
+        *pos += 2 + len;
+    }
+    Some(sets)
+}
+
+/// NAL units of a sample in the `avcC` format, each prefixed with its length in `length_size` bytes.
+pub fn split_length_prefixed(data: &[u8], length_size: usize) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut pos = 0;
+    while pos + length_size <= data.len() {
+        let len = data[pos..pos + length_size]
+            .iter()
+            .fold(0, |len, &b| (len << 8) | usize::from(b));
+        pos += length_size;
+        match data.get(pos..pos + len) {
+            Some(nal) => nals.push(nal),
+            None => break,
+        }
+        pos += len;
+    }
+    nals
+}
+
+/// NAL units of an Annex B byte stream, separated by start codes.
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            if let Some(s) = start {
+                nals.push(&data[s..i]);
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        nals.push(&data[s..]);
+    }
+    nals.into_iter()
+        // the leading zero of a 4 byte start code.
+        .map(|nal| &nal[..nal.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1)])
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// Reads the exp-Golomb coded fields of a RBSP.
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    /// `nal` without its header byte; emulation prevention bytes are removed.
+    fn new(nal: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(nal.len());
+        let mut zeros = 0;
+        for &b in nal {
+            if zeros >= 2 && b == 3 {
+                zeros = 0;
+                continue;
+            }
+            zeros = if b == 0 { zeros + 1 } else { 0 };
+            data.push(b);
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(u32::from(bit))
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut v = 0;
+        for _ in 0..n {
+            v = (v << 1) | self.bit()?;
+        }
+        Some(v)
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i32> {
+        let v = self.ue()?;
+        Some(if v % 2 == 1 {
+            ((v + 1) / 2) as i32
+        } else {
+            -((v / 2) as i32)
+        })
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let (mut last, mut next) = (8, 8);
+        for _ in 0..size {
+            if next != 0 {
+                let delta = self.se()?;
+                if !(-128..=127).contains(&delta) {
+                    return None;
+                }
+                next = (last + delta + 256) % 256;
+            }
+            if next != 0 {
+                last = next;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Picture size in pixels, after cropping, of a SPS NAL unit.
+pub fn sps_size(sps: &[u8]) -> Option<(u32, u32)> {
+    let mut r = BitReader::new(sps.get(1..)?);
+    let profile = r.bits(8)?;
+    r.bits(16)?; // constraint flags, level
+    r.ue()?; // seq_parameter_set_id
+    let mut chroma_format = 1;
+    let mut separate_planes = 0;
+    if matches!(
+        profile,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format = r.ue()?;
+        if chroma_format == 3 {
+            separate_planes = r.bit()?;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bit()?; // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? == 1 {
+            let lists = if chroma_format == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? == 1 {
+                    r.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bit()?;
+            r.se()?;
+            r.se()?;
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bit()?; // gaps_in_frame_num_value_allowed_flag
+    let width_mbs = r.ue()? + 1;
+    let height_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bit()?;
+    if frame_mbs_only == 0 {
+        r.bit()?; // mb_adaptive_frame_field_flag
+    }
+    r.bit()?; // direct_8x8_inference_flag
+    let (mut left, mut right, mut top, mut bottom) = (0, 0, 0, 0);
+    if r.bit()? == 1 {
+        left = r.ue()?;
+        right = r.ue()?;
+        top = r.ue()?;
+        bottom = r.ue()?;
+    }
+    let (crop_x, crop_y) = if chroma_format == 0 || separate_planes == 1 {
+        (1, 2 - frame_mbs_only)
+    } else {
+        let sub_width = if chroma_format == 3 { 1 } else { 2 };
+        let sub_height = if chroma_format == 1 { 2 } else { 1 };
+        (sub_width, sub_height * (2 - frame_mbs_only))
+    };
+    // the fields are up to 32 bits, so a crafted SPS can overflow any of these.
+    let width = width_mbs
+        .checked_mul(16)?
+        .checked_sub(left.checked_add(right)?.checked_mul(crop_x)?)?;
+    let height = (2 - frame_mbs_only)
+        .checked_mul(height_map_units)?
+        .checked_mul(16)?
+        .checked_sub(top.checked_add(bottom)?.checked_mul(crop_y)?)?;
+    Some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes exp-Golomb coded fields, for building parameter sets.
+    #[derive(Default)]
+    struct BitWriter {
+        bits: Vec<u8>,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, n: u32, v: u64) -> &mut Self {
+            for i in (0..n).rev() {
+                self.bits.push((v >> i) as u8 & 1);
+            }
+            self
+        }
+
+        fn ue(&mut self, v: u64) -> &mut Self {
+            let n = 64 - (v + 1).leading_zeros() - 1;
+            self.bits(n, 0).bits(n + 1, v + 1)
+        }
+
+        fn se(&mut self, v: i64) -> &mut Self {
+            self.ue(if v > 0 {
+                2 * v as u64 - 1
+            } else {
+                2 * (-v) as u64
+            })
+        }
+
+        /// A NAL unit of `nal_type`, with the stop bit and no emulation prevention.
+        fn nal(&mut self, nal_type: u8) -> Vec<u8> {
+            self.bits.push(1);
+            while self.bits.len() % 8 != 0 {
+                self.bits.push(0);
+            }
+            let mut out = vec![0x60 | nal_type];
+            out.extend(
+                self.bits
+                    .chunks(8)
+                    .map(|b| b.iter().fold(0, |v, &bit| v << 1 | bit)),
+            );
+            out
+        }
+    }
+
+    /// A SPS of `profile` up to the picture size, with POC type 0.
+    fn sps_start(profile: u64) -> BitWriter {
+        let mut w = BitWriter::default();
+        w.bits(8, profile).bits(16, 0x001f).ue(0);
+        w
+    }
+
+    fn sps_end(
+        w: &mut BitWriter,
+        width_mbs: u64,
+        height_mbs: u64,
+        crop: Option<[u64; 4]>,
+    ) -> Vec<u8> {
+        w.ue(0).ue(0).ue(0); // log2_max_frame_num, poc type 0, log2_max_poc_lsb
+        w.ue(1).bits(1, 0); // max_num_ref_frames, gaps
+        w.ue(width_mbs - 1).ue(height_mbs - 1);
+        w.bits(1, 1).bits(1, 1); // frame_mbs_only, direct_8x8
+        match crop {
+            Some(crop) => {
+                w.bits(1, 1);
+                for c in &crop {
+                    w.ue(*c);
+                }
+            }
+            None => {
+                w.bits(1, 0);
+            }
+        }
+        w.bits(1, 0); // vui
+        w.nal(NAL_SPS)
+    }
+
+    #[test]
+    fn baseline_size() {
+        let sps = sps_end(&mut sps_start(66), 80, 45, None);
+        assert_eq!(sps_size(&sps), Some((1280, 720)));
+        assert_eq!(codec_string(&sps).unwrap(), "avc1.42001f");
+    }
+
+    #[test]
+    fn cropped_size() {
+        // 1088 lines cropped by 4 chroma lines at the bottom.
+        let sps = sps_end(&mut sps_start(66), 120, 68, Some([0, 0, 0, 4]));
+        assert_eq!(sps_size(&sps), Some((1920, 1080)));
+        // more cropping than picture.
+        let sps = sps_end(&mut sps_start(66), 1, 1, Some([5, 5, 0, 0]));
+        assert_eq!(sps_size(&sps), None);
+    }
+
+    #[test]
+    fn high_profile_scaling_lists() {
+        let mut w = sps_start(100);
+        w.ue(1).ue(0).ue(0).bits(1, 0); // 4:2:0, 8 bits
+        w.bits(1, 1); // seq_scaling_matrix_present_flag
+                      // the first list goes down to 0 at once, the rest of it is implicit.
+        w.bits(1, 1).se(-8);
+        for _ in 1..8 {
+            w.bits(1, 0);
+        }
+        let sps = sps_end(&mut w, 40, 30, None);
+        assert_eq!(sps_size(&sps), Some((640, 480)));
+
+        // delta_scale is -128..=127.
+        let mut w = sps_start(100);
+        w.ue(1)
+            .ue(0)
+            .ue(0)
+            .bits(1, 0)
+            .bits(1, 1)
+            .bits(1, 1)
+            .se(1 << 30);
+        assert_eq!(sps_size(&sps_end(&mut w, 40, 30, None)), None);
+    }
+
+    #[test]
+    fn overflowing_size() {
+        let max = u64::from(u32::MAX) - 1;
+        assert_eq!(sps_size(&sps_end(&mut sps_start(66), max, 45, None)), None);
+        assert_eq!(sps_size(&sps_end(&mut sps_start(66), 80, max, None)), None);
+        assert_eq!(
+            sps_size(&sps_end(&mut sps_start(66), 80, 45, Some([max, max, 0, 0]))),
+            None
+        );
+        // truncated.
+        let sps = sps_end(&mut sps_start(66), 80, 45, None);
+        assert_eq!(sps_size(&sps[..4]), None);
+    }
+
+    #[test]
+    fn avcc_round_trip() {
+        let sps = sps_end(&mut sps_start(66), 80, 45, None);
+        let pps = [0x68, 0xce, 0x38, 0x80];
+        let avcc = parse_avcc(&avcc(&sps, &pps).unwrap()).unwrap();
+        assert_eq!(avcc.length_size, 4);
+        assert_eq!(avcc.sps, vec![sps]);
+        assert_eq!(avcc.pps, vec![pps.to_vec()]);
+        assert!(parse_avcc(&[1, 0x42, 0, 0x1f, 0xff, 0xe1, 0, 9]).is_none());
+    }
+
+    #[test]
+    fn split_nal_units() {
+        let annexb = [
+            0, 0, 0, 1, 0x67, 1, 0, 0, 1, 0x68, 2, 0, 0, 0, 1, 0x65, 3, 0,
+        ];
+        let nals = split_annexb(&annexb);
+        assert_eq!(nals, vec![&[0x67, 1][..], &[0x68, 2], &[0x65, 3]]);
+        let prefixed = [0, 0, 0, 2, 0x67, 1, 0, 0, 0, 1, 0x65, 0, 0, 0, 9, 0x41];
+        assert_eq!(
+            split_length_prefixed(&prefixed, 4),
+            vec![&[0x67, 1][..], &[0x65]]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `/healthz` and `/readyz` for container orchestration.
+
+/// State reported by `/readyz`.
+#[derive(Debug, Default)]
+pub struct Health {
+    pub sockets_bound: bool,
+    pub cert_loaded: bool,
+    pub draining: bool,
+}
+
+impl Health {
+    pub fn ready(&self) -> bool {
+        self.sockets_bound && self.cert_loaded && !self.draining
+    }
+
+    /// Status code and body for a probe path, or `None` if `path` is not a probe.
+    pub fn respond(&self, path: &str) -> Option<(u16, String)> {
+        match path {
+            "/healthz" => Some((200, "ok\n".to_string())),
+            "/readyz" => {
+                let body = format!(
+                    "sockets_bound: {}\ncert_loaded: {}\ndraining: {}\n",
+                    self.sockets_bound, self.cert_loaded, self.draining
+                );
+                Some((if self.ready() { 200 } else { 503 }, body))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,675 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Low-latency HLS of a live channel, served on `GET /{channel}/hls/...`.
+//!
+//! The chunks of the default layer are muxed into fMP4 parts of at most `PART_TARGET`,
+//! and a segment is closed at the first keyframe after `SEGMENT_TARGET`, so every segment
+//! (the concatenation of its parts) starts with a keyframe. A segment reaching
+//! `SEGMENT_MAX` without a keyframe is closed and the frames are dropped until the next
+//! keyframe. The last `WINDOW` segments are kept, and the target duration is the longest
+//! of them. A decoder config change starts a new init segment and a discontinuity.
+//!
+//! - `playlist.m3u8` : the media playlist. With `_HLS_msn` (and `_HLS_part`) the response
+//!   is held until that segment (or part) is available (blocking playlist reload).
+//! - `init{id}.mp4` : an init segment, of the current decoder config or of an older one
+//!   still used by a segment of the playlist.
+//! - `seg{msn}.m4s` : a complete segment.
+//! - `part{msn}.{part}.m4s` : a part. A request for the next part, the preload hint of the
+//!   playlist, is held until the part is complete.
+//!
+//! Requests held longer than three target durations are answered with 503.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use neqo_common::{qdebug, qwarn};
+use neqo_http3::Http3OrWebTransportStream;
+
+use crate::chunk::{Chunk, DecoderConfig};
+use crate::fmp4;
+use crate::response::Responses;
+
+/// Media durations in microseconds.
+const PART_TARGET: i64 = 500_000;
+const SEGMENT_TARGET: i64 = 2_000_000;
+const SEGMENT_MAX: i64 = 5 * SEGMENT_TARGET;
+
+/// Complete segments kept in the playlist.
+const WINDOW: usize = 6;
+
+const PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+const INIT_TYPE: &str = "video/mp4";
+const SEGMENT_TYPE: &str = "video/iso.segment";
+
+struct Part {
+    data: Vec<u8>,
+    duration: i64,
+    // starts with a keyframe.
+    independent: bool,
+}
+
+struct Segment {
+    msn: u64,
+    init_id: u32,
+    // first segment after a decoder config change.
+    discontinuity: bool,
+    parts: Vec<Part>,
+    closed: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> i64 {
+        self.parts.iter().map(|p| p.duration).sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Wait {
+    Playlist { msn: u64, part: Option<usize> },
+    Part { msn: u64, part: usize },
+}
+
+/// A request held until what it asks for exists.
+struct Pending {
+    stream: Http3OrWebTransportStream,
+    wait: Wait,
+    deadline: Instant,
+}
+
+pub struct Hls {
+    name: &'static str,
+    init_id: u32,
+    init: Option<Vec<u8>>,
+    // init segments of the previous decoder configs still used by segments of the window.
+    previous_inits: BTreeMap<u32, Vec<u8>>,
+    // the window of complete segments, then the one being written.
+    segments: VecDeque<Segment>,
+    next_msn: u64,
+    discontinuity_sequence: u64,
+    // the next segment follows a config change.
+    discontinuity: bool,
+    // samples of the part being built.
+    samples: Vec<Chunk>,
+    // interval between the last two samples, to close a part before it gets too long.
+    interval: i64,
+    fragment_sequence: u32,
+    pending: Vec<Pending>,
+    responses: Responses,
+}
+
+impl Hls {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            init_id: 0,
+            init: None,
+            previous_inits: BTreeMap::new(),
+            segments: VecDeque::new(),
+            next_msn: 0,
+            discontinuity_sequence: 0,
+            discontinuity: false,
+            samples: Vec::new(),
+            interval: 0,
+            fragment_sequence: 0,
+            pending: Vec::new(),
+            responses: Responses::default(),
+        }
+    }
+
+    /// Add a chunk of the default layer.
+    pub fn push(&mut self, chunk: &Chunk) {
+        if chunk.is_config() {
+            self.configure(chunk);
+            return;
+        }
+        if self.init.is_none() {
+            return;
+        }
+        let timestamp = chunk.header.timestamp;
+        if let Some(start) = self.samples.first().map(|c| c.header.timestamp) {
+            let open = self.open().map_or(0, Segment::duration);
+            if chunk.is_key() && open + timestamp - start >= SEGMENT_TARGET {
+                self.flush_part(timestamp);
+                self.close_segment();
+            } else if open + timestamp - start >= SEGMENT_MAX {
+                // no keyframe for too long, wait for the next one.
+                qwarn!("{}: no keyframe in {} us", self.name, SEGMENT_MAX);
+                self.flush_part(timestamp);
+                self.close_segment();
+            } else if timestamp - start + self.interval > PART_TARGET {
+                self.flush_part(timestamp);
+            }
+        }
+        if self.samples.is_empty() && self.open().is_none() && !chunk.is_key() {
+            // a segment starts with a keyframe.
+            return;
+        }
+        if let Some(last) = self.samples.last() {
+            self.interval = (timestamp - last.header.timestamp).max(0);
+        }
+        self.samples.push(chunk.clone());
+    }
+
+    fn configure(&mut self, chunk: &Chunk) {
+        // what was muxed with the previous config ends here.
+        self.samples.clear();
+        self.close_segment();
+        if let Some(init) = self.init.take() {
+            self.previous_inits.insert(self.init_id, init);
+            self.drop_previous_inits();
+        }
+        self.init = DecoderConfig::parse(chunk)
+            .ok()
+            .and_then(|config| fmp4::init_segment(&config));
+        if self.init.is_some() {
+            self.init_id += 1;
+            self.discontinuity = !self.segments.is_empty();
+        }
+    }
+
+    fn open(&self) -> Option<&Segment> {
+        self.segments.back().filter(|s| !s.closed)
+    }
+
+    /// Mux the samples into a part ending at `end`.
+    fn flush_part(&mut self, end: i64) {
+        let mut samples = mem::take(&mut self.samples);
+        let start = match samples.first() {
+            Some(first) => first.header.timestamp,
+            None => return,
+        };
+        if let Some(last) = samples.last_mut() {
+            last.header.duration = (end - last.header.timestamp).max(0) as u64;
+        }
+        self.fragment_sequence += 1;
+        let part = Part {
+            data: fmp4::fragment(self.fragment_sequence, &samples),
+            duration: (end - start).max(0),
+            independent: samples[0].is_key(),
+        };
+        if self.open().is_none() {
+            self.segments.push_back(Segment {
+                msn: self.next_msn,
+                init_id: self.init_id,
+                discontinuity: mem::take(&mut self.discontinuity),
+                parts: Vec::new(),
+                closed: false,
+            });
+            self.next_msn += 1;
+        }
+        if let Some(segment) = self.segments.back_mut() {
+            segment.parts.push(part);
+        }
+        self.answer();
+    }
+
+    fn close_segment(&mut self) {
+        let segment = match self.segments.back_mut() {
+            Some(segment) if !segment.closed => segment,
+            _ => return,
+        };
+        segment.closed = true;
+        while self.segments.len() > WINDOW {
+            if let Some(old) = self.segments.pop_front() {
+                if old.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+        self.drop_previous_inits();
+        self.answer();
+    }
+
+    /// Forget the init segments no segment of the window uses anymore.
+    fn drop_previous_inits(&mut self) {
+        // the init ids of the segments never decrease.
+        match self.segments.front().map(|s| s.init_id) {
+            Some(first) => self.previous_inits.retain(|&id, _| id >= first),
+            None => self.previous_inits.clear(),
+        }
+    }
+
+    fn init_segment(&self, id: u64) -> Option<&Vec<u8>> {
+        if id == u64::from(self.init_id) {
+            return self.init.as_ref();
+        }
+        self.previous_inits.get(&u32::try_from(id).ok()?)
+    }
+
+    fn segment(&self, msn: u64) -> Option<&Segment> {
+        let first = self.segments.front()?.msn;
+        self.segments.get(msn.checked_sub(first)? as usize)
+    }
+
+    fn part(&self, msn: u64, part: usize) -> Option<&Part> {
+        self.segment(msn)?.parts.get(part)
+    }
+
+    /// The part the preload hint points to.
+    fn next_part(&self) -> (u64, usize) {
+        match self.open() {
+            Some(segment) => (segment.msn, segment.parts.len()),
+            None => (self.next_msn, 0),
+        }
+    }
+
+    fn ready(&self, wait: Wait) -> bool {
+        match wait {
+            Wait::Playlist { msn, part: None } => {
+                msn < self.next_msn && self.segment(msn).map_or(true, |s| s.closed)
+            }
+            Wait::Playlist {
+                msn,
+                part: Some(part),
+            } => match self.segment(msn) {
+                Some(s) if part < s.parts.len() => true,
+                // asking for a part after the last one of a segment means the next segment.
+                Some(s) if s.closed => self.ready(Wait::Playlist {
+                    msn: msn + 1,
+                    part: Some(0),
+                }),
+                Some(_) => false,
+                None => msn < self.next_msn,
+            },
+            Wait::Part { msn, part } => self.next_part() != (msn, part),
+        }
+    }
+
+    /// Target duration in seconds, the longest segment of the window rounded up.
+    fn target_duration(&self) -> i64 {
+        let longest = self
+            .segments
+            .iter()
+            .filter(|s| s.closed)
+            .map(Segment::duration)
+            .fold(SEGMENT_TARGET, i64::max);
+        (longest + 999_999) / 1_000_000
+    }
+
+    fn playlist(&self) -> String {
+        let secs = |us: i64| us as f64 / 1_000_000.0;
+        let mut out = String::new();
+        let _ = writeln!(out, "#EXTM3U");
+        let _ = writeln!(out, "#EXT-X-VERSION:9");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", self.target_duration());
+        let _ = writeln!(
+            out,
+            "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+            secs(3 * PART_TARGET)
+        );
+        let _ = writeln!(out, "#EXT-X-PART-INF:PART-TARGET={:.3}", secs(PART_TARGET));
+        let _ = writeln!(
+            out,
+            "#EXT-X-MEDIA-SEQUENCE:{}",
+            self.segments.front().map_or(self.next_msn, |s| s.msn)
+        );
+        let _ = writeln!(
+            out,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            self.discontinuity_sequence
+        );
+        // parts are listed for the segments within three target durations of the live edge.
+        let mut from_edge = 0;
+        let mut with_parts = self.segments.len();
+        for (i, segment) in self.segments.iter().enumerate().rev() {
+            if from_edge >= 3 * self.target_duration() * 1_000_000 {
+                break;
+            }
+            from_edge += segment.duration();
+            with_parts = i;
+        }
+        let mut init_id = None;
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.discontinuity {
+                let _ = writeln!(out, "#EXT-X-DISCONTINUITY");
+            }
+            if init_id != Some(segment.init_id) {
+                let _ = writeln!(out, "#EXT-X-MAP:URI=\"init{}.mp4\"", segment.init_id);
+                init_id = Some(segment.init_id);
+            }
+            if i >= with_parts {
+                for (n, part) in segment.parts.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "#EXT-X-PART:DURATION={:.3},URI=\"part{}.{}.m4s\"{}",
+                        secs(part.duration),
+                        segment.msn,
+                        n,
+                        if part.independent {
+                            ",INDEPENDENT=YES"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            if segment.closed {
+                let _ = writeln!(out, "#EXTINF:{:.3},", secs(segment.duration()));
+                let _ = writeln!(out, "seg{}.m4s", segment.msn);
+            }
+        }
+        let (msn, part) = self.next_part();
+        let _ = writeln!(
+            out,
+            "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part{}.{}.m4s\"",
+            msn, part
+        );
+        out
+    }
+
+    /// Serve `path`, relative to `/{channel}/hls/`.
+    pub fn get(&mut self, stream: Http3OrWebTransportStream, path: &str, now: Instant) {
+        let (name, query) = path.split_once('?').unwrap_or((path, ""));
+        qdebug!("{} hls: GET {}", self.name, path);
+        if name == "playlist.m3u8" {
+            if self.segments.is_empty() {
+                self.respond(stream, "404", "text/plain", b"no broadcast\n".to_vec());
+                return;
+            }
+            let msn = query_param(query, "_HLS_msn");
+            let part = query_param(query, "_HLS_part").map(|p| p as usize);
+            match msn {
+                None if part.is_some() => self.respond(
+                    stream,
+                    "400",
+                    "text/plain",
+                    b"_HLS_part without _HLS_msn\n".to_vec(),
+                ),
+                None => self.respond_with(stream, Wait::Playlist { msn: 0, part: None }),
+                // more than two segments ahead of the live edge.
+                Some(msn) if msn > self.next_msn + 1 => self.respond(
+                    stream,
+                    "400",
+                    "text/plain",
+                    b"_HLS_msn too far ahead\n".to_vec(),
+                ),
+                Some(msn) => self.wait(stream, Wait::Playlist { msn, part }, now),
+            }
+        } else if let Some(id) = parse_name(name, "init", ".mp4") {
+            match self.init_segment(id) {
+                Some(init) => {
+                    let init = init.clone();
+                    self.respond(stream, "200", INIT_TYPE, init);
+                }
+                None => self.respond(stream, "404", "text/plain", b"not found\n".to_vec()),
+            }
+        } else if let Some(msn) = parse_name(name, "seg", ".m4s") {
+            match self.segment(msn).filter(|s| s.closed) {
+                Some(segment) => {
+                    let body = segment
+                        .parts
+                        .iter()
+                        .flat_map(|p| p.data.iter().copied())
+                        .collect();
+                    self.respond(stream, "200", SEGMENT_TYPE, body);
+                }
+                None => self.respond(stream, "404", "text/plain", b"not found\n".to_vec()),
+            }
+        } else if let Some((msn, part)) = parse_part(name) {
+            self.wait(stream, Wait::Part { msn, part }, now);
+        } else {
+            self.respond(stream, "404", "text/plain", b"not found\n".to_vec());
+        }
+    }
+
+    fn wait(&mut self, stream: Http3OrWebTransportStream, wait: Wait, now: Instant) {
+        if self.ready(wait) {
+            self.respond_with(stream, wait);
+            return;
+        }
+        let deadline = now + Duration::from_secs(3 * self.target_duration() as u64);
+        self.pending.push(Pending {
+            stream,
+            wait,
+            deadline,
+        });
+    }
+
+    fn respond_with(&mut self, stream: Http3OrWebTransportStream, wait: Wait) {
+        match wait {
+            Wait::Playlist { .. } => {
+                let playlist = self.playlist().into_bytes();
+                self.respond(stream, "200", PLAYLIST_TYPE, playlist);
+            }
+            Wait::Part { msn, part } => match self.part(msn, part) {
+                Some(part) => {
+                    let data = part.data.clone();
+                    self.respond(stream, "200", SEGMENT_TYPE, data);
+                }
+                None => self.respond(stream, "404", "text/plain", b"not found\n".to_vec()),
+            },
+        }
+    }
+
+    /// Answer the held requests that can be answered now.
+    fn answer(&mut self) {
+        let pending = mem::take(&mut self.pending);
+        for p in pending {
+            if self.ready(p.wait) {
+                self.respond_with(p.stream, p.wait);
+            } else {
+                self.pending.push(p);
+            }
+        }
+    }
+
+    /// When the next held request times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| p.deadline).min()
+    }
+
+    /// Answer the held requests whose deadline passed with 503.
+    /// Returns true when anything was sent.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let (expired, pending): (Vec<_>, Vec<_>) = mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.deadline <= now);
+        self.pending = pending;
+        let sent = !expired.is_empty();
+        for p in expired {
+            qwarn!("{} hls: {:?} timed out", self.name, p.wait);
+            self.respond(p.stream, "503", "text/plain", b"timed out\n".to_vec());
+        }
+        sent
+    }
+
+    fn respond(
+        &mut self,
+        stream: Http3OrWebTransportStream,
+        status: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) {
+        self.responses.send(stream, status, content_type, body);
+    }
+
+    /// Continue the response on `stream` when it has room again.
+    pub fn writable(&mut self, stream: &Http3OrWebTransportStream) {
+        self.responses.writable(stream);
+    }
+
+    /// Forget what was held or being sent on `stream`, reset or stopped by the peer.
+    pub fn stopped(&mut self, stream: &Http3OrWebTransportStream) {
+        self.pending.retain(|p| p.stream != *stream);
+        self.responses.stopped(stream);
+    }
+}
+
+/// `{prefix}{number}{suffix}`.
+fn parse_name(name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+/// `part{msn}.{part}.m4s`.
+fn parse_part(name: &str) -> Option<(u64, usize)> {
+    let (msn, part) = name
+        .strip_prefix("part")?
+        .strip_suffix(".m4s")?
+        .split_once('.')?;
+    Some((msn.parse().ok()?, part.parse().ok()?))
+}
+
+fn query_param(query: &str, key: &str) -> Option<u64> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| v.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+    use serde_json::json;
+
+    fn config(width: u32, timestamp: i64) -> Chunk {
+        let json = json!({"codec": "vp8", "codedWidth": width, "codedHeight": 240});
+        DecoderConfig::new(json, Vec::new()).to_chunk(timestamp)
+    }
+
+    /// A frame every 100 ms in `from..to`, a keyframe every 2 seconds.
+    fn feed(hls: &mut Hls, from: i64, to: i64) {
+        feed_keys(hls, from, to, |timestamp| timestamp % SEGMENT_TARGET == 0);
+    }
+
+    fn feed_keys(hls: &mut Hls, from: i64, to: i64, key: impl Fn(i64) -> bool) {
+        for timestamp in (from..to).step_by(100_000) {
+            let chunk_type = if key(timestamp) {
+                ChunkType::Key
+            } else {
+                ChunkType::Delta
+            };
+            let header = ChunkHeader {
+                chunk_type,
+                timestamp,
+                duration: 100_000,
+            };
+            hls.push(&Chunk::new(header, b"frame"));
+        }
+    }
+
+    #[test]
+    fn parts_and_segments() {
+        let mut hls = Hls::new("video");
+        // nothing before a config, and a segment starts at a keyframe.
+        feed(&mut hls, 0, 1_000_000);
+        hls.push(&config(320, 1_000_000));
+        feed(&mut hls, 1_000_000, 4_100_000);
+        // the frames before the keyframe at 2 s are dropped.
+        assert_eq!(hls.segments.len(), 1);
+        let playlist = hls.playlist();
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:2\n"));
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"init1.mp4\"\n"));
+        assert!(
+            playlist.contains("#EXT-X-PART:DURATION=0.500,URI=\"part0.0.m4s\",INDEPENDENT=YES\n")
+        );
+        assert!(playlist.contains("#EXT-X-PART:DURATION=0.500,URI=\"part0.3.m4s\"\n"));
+        assert!(playlist.contains("#EXTINF:2.000,\nseg0.m4s\n"));
+        assert!(playlist.ends_with("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part1.0.m4s\"\n"));
+        assert!(!playlist.contains("DISCONTINUITY\n"));
+    }
+
+    #[test]
+    fn blocking_reload() {
+        let mut hls = Hls::new("video");
+        hls.push(&config(320, 0));
+        feed(&mut hls, 0, 2_100_000);
+        assert!(hls.ready(Wait::Playlist { msn: 0, part: None }));
+        assert!(!hls.ready(Wait::Playlist {
+            msn: 1,
+            part: Some(0)
+        }));
+        // the part after the last one of a closed segment is the first of the next.
+        assert!(!hls.ready(Wait::Playlist {
+            msn: 0,
+            part: Some(4)
+        }));
+        assert!(!hls.ready(Wait::Part { msn: 1, part: 0 }));
+        feed(&mut hls, 2_100_000, 2_600_000);
+        assert!(hls.ready(Wait::Playlist {
+            msn: 0,
+            part: Some(4)
+        }));
+        assert!(hls.ready(Wait::Part { msn: 1, part: 0 }));
+        assert!(!hls.ready(Wait::Playlist { msn: 1, part: None }));
+    }
+
+    #[test]
+    fn previous_init_segments_are_kept() {
+        let mut hls = Hls::new("video");
+        hls.push(&config(320, 0));
+        feed(&mut hls, 0, 2_000_000);
+        hls.push(&config(640, 2_000_000));
+        feed(&mut hls, 2_000_000, 4_100_000);
+        let playlist = hls.playlist();
+        assert!(playlist.contains(
+            "#EXT-X-MAP:URI=\"init1.mp4\"\n#EXT-X-PART:DURATION=0.500,URI=\"part0.0.m4s\""
+        ));
+        assert!(playlist.contains("#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init2.mp4\"\n"));
+        let first = hls.init_segment(1).cloned().unwrap();
+        assert_ne!(Some(&first), hls.init_segment(2));
+        assert!(hls.init_segment(3).is_none());
+
+        // until the first segment leaves the window.
+        let full = WINDOW as i64 * SEGMENT_TARGET + 100_000;
+        feed(&mut hls, 4_100_000, full);
+        assert_eq!(hls.segments.front().map(|s| s.msn), Some(0));
+        assert_eq!(hls.init_segment(1), Some(&first));
+        feed(&mut hls, full, full + SEGMENT_TARGET);
+        assert_eq!(hls.segments.front().map(|s| s.msn), Some(1));
+        assert!(hls.init_segment(1).is_none());
+        assert!(hls.init_segment(2).is_some());
+        assert!(!hls.playlist().contains("init1.mp4"));
+    }
+
+    #[test]
+    fn segment_without_keyframe() {
+        let mut hls = Hls::new("video");
+        hls.push(&config(320, 0));
+        // a keyframe at 0 only.
+        feed_keys(&mut hls, 0, 15_000_000, |t| t == 0);
+        assert_eq!(hls.segments.len(), 1);
+        let segment = &hls.segments[0];
+        assert!(segment.closed);
+        assert_eq!(segment.duration(), SEGMENT_MAX);
+        assert_eq!(segment.parts.len(), (SEGMENT_MAX / PART_TARGET) as usize);
+        // the frames after it wait for a keyframe.
+        assert!(hls.samples.is_empty());
+        assert!(hls.open().is_none());
+        assert_eq!(hls.target_duration(), 10);
+
+        feed(&mut hls, 16_000_000, 18_100_000);
+        assert_eq!(hls.segments.len(), 2);
+        assert_eq!(hls.segments[1].duration(), SEGMENT_TARGET);
+        // the long segment sets the target duration while it is in the window.
+        let mut end = 18_100_000;
+        while hls.segments.front().map(|s| s.msn) == Some(0) {
+            assert_eq!(hls.target_duration(), 10);
+            feed(&mut hls, end, end + SEGMENT_TARGET);
+            end += SEGMENT_TARGET;
+        }
+        assert_eq!(hls.target_duration(), 2);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse_name("init3.mp4", "init", ".mp4"), Some(3));
+        assert_eq!(parse_name("init.mp4", "init", ".mp4"), None);
+        assert_eq!(parse_name("seg12.m4s", "seg", ".m4s"), Some(12));
+        assert_eq!(parse_part("part4.2.m4s"), Some((4, 2)));
+        assert_eq!(parse_part("part4.m4s"), None);
+        assert_eq!(query_param("_HLS_msn=5&_HLS_part=1", "_HLS_part"), Some(1));
+        assert_eq!(query_param("_HLS_msn=x", "_HLS_msn"), None);
+    }
+}
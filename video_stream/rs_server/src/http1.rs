@@ -0,0 +1,382 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A tiny HTTP/1.1 listener for tools that can't speak QUIC.
+//!
+//! Connections are non-blocking and registered with the poll of the server, each one
+//! answering a single request and then closed, so a slow client never holds up QUIC.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Poll, PollOpt, Ready, Token};
+use neqo_common::qwarn;
+
+/// How long a connection has to send its request and take the response.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections served at the same time by a listener.
+pub const MAX_CLIENTS: usize = 64;
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Split a request target into its path and query parameters.
+pub fn split_target(target: &str) -> (&str, Vec<(String, String)>) {
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p, q),
+        None => (target, ""),
+    };
+    let query = query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| match kv.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => (kv.to_string(), String::new()),
+        })
+        .collect();
+    (path, query)
+}
+
+/// Decode the `%XX` escapes and `+` of a query value, keeping invalid escapes as they are.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split(' ');
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = split_target(target);
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+            .collect();
+        Some(Self {
+            method,
+            path: path.to_string(),
+            query,
+            headers,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        Self::new(status, "text/plain", format!("{}\n", body))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// A connection being served: the request read so far, then the response left to write.
+struct Client {
+    stream: TcpStream,
+    peer: SocketAddr,
+    buf: Vec<u8>,
+    out: Option<Vec<u8>>,
+    deadline: Instant,
+}
+
+pub struct HttpListener {
+    listener: TcpListener,
+    token: Token,
+    // tokens of the connections are `clients..clients + MAX_CLIENTS`.
+    clients: usize,
+    conns: HashMap<Token, Client>,
+    next: usize,
+}
+
+impl HttpListener {
+    pub fn bind(addr: &SocketAddr, poll: &Poll, token: Token, clients: usize) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
+        println!("HTTP/1.1 listener on: {:?}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            token,
+            clients,
+            conns: HashMap::new(),
+            next: 0,
+        })
+    }
+
+    /// Whether `token` is the listener's or one of its connections'.
+    pub fn owns(&self, token: Token) -> bool {
+        token == self.token || (self.clients..self.clients + MAX_CLIENTS).contains(&token.0)
+    }
+
+    /// Accept the pending connections or go on with the one of `token`, answering the
+    /// requests complete with `handle`.
+    pub fn ready<F>(&mut self, poll: &Poll, token: Token, mut handle: F)
+    where
+        F: FnMut(&Request) -> Response,
+    {
+        if token != self.token {
+            self.process(poll, token, &mut handle);
+            return;
+        }
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    qwarn!("HTTP accept error: {:?}", err);
+                    return;
+                }
+                Ok(res) => res,
+            };
+            let token = match self.free_token() {
+                Some(token) => token,
+                None => {
+                    qwarn!("too many HTTP connections, drop {}", peer);
+                    continue;
+                }
+            };
+            let interest = Ready::readable() | Ready::writable();
+            if let Err(err) = poll.register(&stream, token, interest, PollOpt::edge()) {
+                qwarn!("HTTP register error: {:?}", err);
+                continue;
+            }
+            let client = Client {
+                stream,
+                peer,
+                buf: Vec::new(),
+                out: None,
+                deadline: Instant::now() + IO_TIMEOUT,
+            };
+            self.conns.insert(token, client);
+            // the request may be there already.
+            self.process(poll, token, &mut handle);
+        }
+    }
+
+    /// When the oldest connection times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.conns.values().map(|c| c.deadline).min()
+    }
+
+    /// Drop the connections that didn't send a request or take the response in time.
+    pub fn expire(&mut self, poll: &Poll, now: Instant) {
+        let expired: Vec<_> = self
+            .conns
+            .iter()
+            .filter(|(_, c)| c.deadline <= now)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in expired {
+            if let Some(client) = self.conns.remove(&token) {
+                qwarn!("HTTP connection from {} timed out", client.peer);
+                let _ = poll.deregister(&client.stream);
+            }
+        }
+    }
+
+    fn free_token(&mut self) -> Option<Token> {
+        for _ in 0..MAX_CLIENTS {
+            let token = Token(self.clients + self.next);
+            self.next = (self.next + 1) % MAX_CLIENTS;
+            if !self.conns.contains_key(&token) {
+                return Some(token);
+            }
+        }
+        None
+    }
+
+    fn process<F>(&mut self, poll: &Poll, token: Token, handle: &mut F)
+    where
+        F: FnMut(&Request) -> Response,
+    {
+        let client = match self.conns.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+        let done = match client.serve(handle) {
+            Ok(done) => done,
+            Err(err) => {
+                qwarn!("HTTP request from {} failed: {:?}", client.peer, err);
+                true
+            }
+        };
+        if done {
+            if let Some(client) = self.conns.remove(&token) {
+                let _ = poll.deregister(&client.stream);
+            }
+        }
+    }
+}
+
+impl Client {
+    /// Read what arrived and write what the socket takes. Returns true once the response
+    /// is written or the peer went away.
+    fn serve<F>(&mut self, handle: &mut F) -> io::Result<bool>
+    where
+        F: FnMut(&Request) -> Response,
+    {
+        if self.out.is_none() {
+            let mut chunk = [0u8; 1024];
+            let head = loop {
+                match self.stream.read(&mut chunk) {
+                    Ok(0) => return Ok(true),
+                    Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+                if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                }
+                if self.buf.len() > MAX_REQUEST_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "request too large",
+                    ));
+                }
+            };
+            let res = match Request::parse(&head) {
+                Some(req) => handle(&req),
+                None => Response::text(400, "bad request"),
+            };
+            let mut out = format!(
+                "HTTP/1.1 {} {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                res.status,
+                res.reason(),
+                res.content_type,
+                res.body.len()
+            )
+            .into_bytes();
+            out.extend_from_slice(&res.body);
+            self.out = Some(out);
+        }
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => return Ok(true),
+        };
+        while !out.is_empty() {
+            match self.stream.write(out) {
+                Ok(0) => return Ok(true),
+                Ok(n) => {
+                    out.drain(..n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let req = Request::parse(
+            "POST /sessions/3/close?code=7&reason=bye HTTP/1.1\r\nAuthorization: Bearer t",
+        )
+        .unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/sessions/3/close");
+        assert_eq!(req.query("code"), Some("7"));
+        assert_eq!(req.query("reason"), Some("bye"));
+        assert_eq!(req.header("authorization"), Some("Bearer t"));
+        assert!(Request::parse("").is_none());
+    }
+
+    #[test]
+    fn split_query() {
+        let (path, query) = split_target("/chat?room=a&flag");
+        assert_eq!(path, "/chat");
+        assert_eq!(
+            query,
+            vec![
+                ("room".to_string(), "a".to_string()),
+                ("flag".to_string(), String::new())
+            ]
+        );
+        assert_eq!(split_target("/").1, Vec::new());
+    }
+
+    #[test]
+    fn decode_percent() {
+        assert_eq!(percent_decode("a+b%20c"), "a b c");
+        assert_eq!(percent_decode("%E3%81%82"), "\u{3042}");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
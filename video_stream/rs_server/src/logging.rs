@@ -0,0 +1,113 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Log output with the context of the session being handled.
+//!
+//! The server runs on a single thread, so the context is kept in a thread local
+//! while an event is handled and is attached to every line logged meanwhile,
+//! including the ones of neqo.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+
+use env_logger::{Builder, Env};
+use neqo_transport::StreamId;
+use serde_json::json;
+
+/// Filter used when neither `--log-filter` nor `RUST_LOG` is set.
+const DEFAULT_FILTER: &str = "warn,rs_server=info";
+
+#[derive(Debug, Clone, Default)]
+pub struct LogContext {
+    pub session: Option<u64>,
+    pub remote: Option<SocketAddr>,
+    pub path: Option<String>,
+    pub stream: Option<StreamId>,
+}
+
+impl LogContext {
+    pub fn stream(mut self, stream: StreamId) -> Self {
+        self.stream = Some(stream);
+        self
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(s) = self.session {
+            write!(f, " session={}", s)?;
+        }
+        if let Some(r) = self.remote {
+            write!(f, " remote={}", r)?;
+        }
+        if let Some(p) = &self.path {
+            write!(f, " path={}", p)?;
+        }
+        if let Some(s) = self.stream {
+            write!(f, " stream={}", s)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<LogContext>> = RefCell::new(None);
+}
+
+/// Restores the previous context when dropped.
+pub struct ContextGuard {
+    prev: Option<LogContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CONTEXT.with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Attach `ctx` to the log lines until the returned guard is dropped.
+pub fn enter(ctx: LogContext) -> ContextGuard {
+    let prev = CONTEXT.with(|c| c.borrow_mut().replace(ctx));
+    ContextGuard { prev }
+}
+
+/// `filter` has the `RUST_LOG` syntax, e.g. `info,neqo_transport=debug`.
+pub fn init(json: bool, filter: Option<&str>) {
+    let mut builder = Builder::from_env(Env::default().default_filter_or(DEFAULT_FILTER));
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    builder.format(move |buf, record| {
+        let ctx = CONTEXT.with(|c| c.borrow().clone()).unwrap_or_default();
+        if json {
+            let line = json!({
+                "ts": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+                "session": ctx.session,
+                "remote": ctx.remote.map(|r| r.to_string()),
+                "path": ctx.path,
+                "stream": ctx.stream.map(StreamId::as_u64),
+            });
+            writeln!(buf, "{}", line)
+        } else {
+            writeln!(
+                buf,
+                "{} {:5} {}{} {}",
+                buf.timestamp_millis(),
+                record.level(),
+                record.target(),
+                ctx,
+                record.args()
+            )
+        }
+    });
+    builder.init();
+}
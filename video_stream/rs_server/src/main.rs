@@ -7,8 +7,34 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
+mod abr;
+mod admin;
+mod av;
+mod base64;
+mod chunk;
+mod datagram;
+mod fmp4;
+mod gop;
+mod h264;
+mod health;
+mod hls;
+mod http1;
+mod logging;
+mod metrics;
+mod presence;
+mod priority;
+mod publisher;
+mod pubsub;
+mod qlog_events;
+mod recorder;
+mod relay;
+mod response;
+mod rtp;
+mod rtp_egress;
+mod vod;
+
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::mem;
 use std::net::{SocketAddr, ToSocketAddrs};
@@ -19,12 +45,15 @@ use std::time::{Duration, Instant};
 use mio::net::UdpSocket;
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::{Builder, Timeout, Timer};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook_mio::v0_6::Signals;
+use serde_json::json;
 use structopt::StructOpt;
 
-use neqo_common::{hex, qdebug, qinfo, qerror, Datagram, Header};
+use neqo_common::{hex, qdebug, qinfo, qerror, qwarn, Datagram, Header};
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256},
-    generate_ech_keys, init_db, random, AntiReplay, Cipher,
+    generate_ech_keys, init_db, random, AntiReplay, Cipher, Server as TlsServer,
 };
 use neqo_http3::{
     Error, Http3Parameters, Http3Server, Http3ServerEvent, WebTransportRequest,
@@ -33,11 +62,43 @@ use neqo_http3::{
 use neqo_transport::{
     server::{ActiveConnectionRef, ValidateAddress},
     tparams::PreferredAddress,
-    CongestionControlAlgorithm, ConnectionParameters, Output, RandomConnectionIdGenerator,
-    StreamId, StreamType,
+    CongestionControlAlgorithm, ConnectionParameters, Output, RandomConnectionIdGenerator, State,
+    StreamType,
 };
 
+use av::AvChannel;
+use datagram::DatagramConfig;
+use health::Health;
+use http1::{HttpListener, Response};
+use logging::LogContext;
+use metrics::{Metrics, PathStats};
+use priority::Media;
+use publisher::{Publisher, Source, DEFAULT_LAYER};
+use presence::{Presence, DEFAULT_ROOM, TOPIC_PREFIXES};
+use pubsub::{Delivery, Topics};
+use recorder::RecordConfig;
+use relay::Relay;
+use response::Responses;
+use rtp::RtpIngest;
+use rtp_egress::RtpEgress;
+use vod::Vod;
+
 const TIMER_TOKEN: Token = Token(0xffff_ffff);
+const ADMIN_TOKEN: Token = Token(0xffff_fffe);
+const PROBE_TOKEN: Token = Token(0xffff_fffd);
+const SIGNAL_TOKEN: Token = Token(0xffff_fffc);
+const RELAY_TOKEN: Token = Token(0xffff_fffb);
+const RTP_VIDEO_TOKEN: Token = Token(0xffff_fffa);
+const RTP_AUDIO_TOKEN: Token = Token(0xffff_fff9);
+/// How long the connections closed when draining ends have to send CONNECTION_CLOSE.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// first tokens of the connections of the HTTP/1.1 listeners.
+const ADMIN_CLIENTS: usize = 0x1000_0000;
+const PROBE_CLIENTS: usize = 0x2000_0000;
+
+/// Largest DATAGRAM frame accepted, for the datagram viewers.
+pub const DATAGRAM_FRAME_SIZE: u64 = 1500;
 const ANTI_REPLAY_WINDOW: Duration = Duration::from_secs(10);
 
 #[derive(Debug, StructOpt)]
@@ -99,6 +160,100 @@ struct Args {
     /// This generates a new set of ECH keys when it is invoked.
     /// The resulting configuration is printed to stdout in hexadecimal format.
     ech: bool,
+
+    #[structopt(name = "admin-addr", long)]
+    /// IP:port of the admin API (HTTP/1.1). The API is disabled when not set.
+    admin_addr: Option<SocketAddr>,
+
+    #[structopt(name = "admin-token", long, default_value = "")]
+    /// Bearer token required by the admin API.
+    admin_token: String,
+
+    #[structopt(name = "log-json", long)]
+    /// Write logs as JSON lines.
+    log_json: bool,
+
+    #[structopt(name = "log-filter", long)]
+    /// Log level filters with the RUST_LOG syntax, e.g. `info,neqo_transport=debug`.
+    /// Overrides RUST_LOG.
+    log_filter: Option<String>,
+
+    #[structopt(name = "probe-addr", long)]
+    /// IP:port of an HTTP/1.1 listener for /healthz and /readyz probes.
+    probe_addr: Option<SocketAddr>,
+
+    #[structopt(name = "max-chunk-size", long, default_value = "4194304")]
+    /// Largest media chunk accepted from a publisher, in bytes.
+    max_chunk_size: usize,
+
+    #[structopt(name = "drain-timeout", long, default_value = "10")]
+    /// Seconds to wait for sessions to end after SIGTERM before closing them.
+    drain_timeout: u64,
+
+    #[structopt(name = "record-dir", long)]
+    /// Record every published broadcast to this directory.
+    record_dir: Option<PathBuf>,
+
+    #[structopt(name = "record-segment-secs", long, default_value = "10")]
+    /// Media duration of a recorded segment, in seconds.
+    record_segment_secs: u64,
+
+    #[structopt(name = "record-segment-size", long, default_value = "67108864")]
+    /// Size of a recorded segment, in bytes.
+    record_segment_size: u64,
+
+    #[structopt(name = "relay", long)]
+    /// host:port of an upstream server whose video channel is relayed to the local one.
+    /// Local video publishers are refused while relaying.
+    relay: Option<String>,
+
+    #[structopt(name = "relay-path", long, default_value = "/video/view?layer=0")]
+    /// Path of the upstream session to subscribe to.
+    relay_path: String,
+
+    #[structopt(name = "relay-cert", long, parse(from_os_str))]
+    /// PEM certificate the upstream must present.
+    relay_cert: Option<PathBuf>,
+
+    #[structopt(name = "relay-insecure", long)]
+    /// Accept any upstream certificate instead of requiring --relay-cert.
+    relay_insecure: bool,
+
+    #[structopt(name = "rtp-video", long)]
+    /// Receive H.264 over RTP on this address and publish it to the video channel.
+    rtp_video: Option<SocketAddr>,
+
+    #[structopt(name = "rtp-audio", long)]
+    /// Receive Opus over RTP on this address and publish it to the audio channel.
+    rtp_audio: Option<SocketAddr>,
+
+    #[structopt(name = "rtp-out-video", long)]
+    /// Send the video channel over RTP to this address.
+    rtp_out_video: Option<SocketAddr>,
+
+    #[structopt(name = "rtp-out-audio", long)]
+    /// Send the audio channel over RTP to this address.
+    rtp_out_audio: Option<SocketAddr>,
+
+    #[structopt(name = "rtp-out-dir", long, default_value = ".")]
+    /// Directory of the SDP files describing the RTP output, `{channel}.sdp`.
+    rtp_out_dir: PathBuf,
+
+    #[structopt(name = "datagram-fec", long, default_value = "0")]
+    /// Parity fragments per 100 media fragments sent to datagram viewers, 0 disables FEC.
+    datagram_fec: u32,
+
+    #[structopt(name = "datagram-loss", long, default_value = "0")]
+    /// Drop this percentage of the fragments sent to datagram viewers, to simulate a lossy link.
+    datagram_loss: u32,
+
+    #[structopt(name = "datagram-deadline-video", long, default_value = "300")]
+    /// Milliseconds after which a video frame sent in datagrams isn't sent again on NACK, 0 disables retransmissions.
+    datagram_deadline_video: u64,
+
+    #[structopt(name = "datagram-deadline-audio", long, default_value = "100")]
+    /// Milliseconds after which an audio frame sent in datagrams isn't sent again on NACK, 0 disables retransmissions.
+    datagram_deadline_audio: u64,
 }
 
 impl Args {
@@ -133,6 +288,26 @@ impl Args {
         addr
     }
 
+    fn record_config(&self) -> Option<RecordConfig> {
+        self.record_dir.as_ref().map(|dir| RecordConfig {
+            dir: dir.clone(),
+            segment_duration: Duration::from_secs(self.record_segment_secs),
+            segment_size: self.record_segment_size,
+        })
+    }
+
+    fn datagram_config(&self, media: Media) -> DatagramConfig {
+        let deadline = match media {
+            Media::Video => self.datagram_deadline_video,
+            Media::Audio => self.datagram_deadline_audio,
+        };
+        DatagramConfig {
+            fec_overhead: self.datagram_fec.min(100),
+            simulated_loss: self.datagram_loss.min(100),
+            deadline: Duration::from_millis(deadline),
+        }
+    }
+
     fn preferred_address_v4(&self) -> Option<SocketAddr> {
         Self::get_sock_addr(&self.preferred_address_v4, "IPv4", |addr| addr.is_ipv4())
     }
@@ -190,130 +365,464 @@ impl QuicParameters {
     }
 }
 
-fn emit_packet(socket: &mut UdpSocket, out_dgram: Datagram) {
+fn emit_packet(socket: &mut UdpSocket, out_dgram: Datagram, metrics: &RefCell<Metrics>) {
     let sent = socket
         .send_to(&out_dgram, &out_dgram.destination())
         .expect("Error sending datagram");
     if sent != out_dgram.len() {
-        eprintln!("Unable to send all {} bytes of datagram", out_dgram.len());
+        qwarn!("Unable to send all {} bytes of datagram", out_dgram.len());
+        metrics.borrow_mut().datagram_dropped();
     }
 }
 
-struct Publisher {
-    // members identified by connection_id.
-    members: HashMap<ActiveConnectionRef, WebTransportRequest>,
-
-    // buffer data with stream_id.
-    buf: HashMap<StreamId, Vec<u8>>,
+pub enum MyHandler {
+    PublishVideo,
+    PublishAudio,
+    PublishAv,
+    SubscribeVideo,
+    SubscribeAudio,
+    WarpVideo,
+    WarpAudio,
+    DatagramVideo,
+    DatagramAudio,
+    SubscribeAv,
+    Chat,
+    PubSub,
 }
-impl Publisher {
-    pub fn new() -> Self {
-        Self {
-            members: HashMap::new(),
-            buf: HashMap::new(),
+impl MyHandler {
+    pub fn path(&self) -> &'static str {
+        match self {
+            Self::PublishVideo => "/video/stream",
+            Self::PublishAudio => "/audio/stream",
+            Self::PublishAv => "/av/stream",
+            Self::SubscribeVideo => "/video/view",
+            Self::SubscribeAudio => "/audio/view",
+            Self::WarpVideo => "/video/warp",
+            Self::WarpAudio => "/audio/warp",
+            Self::DatagramVideo => "/video/datagram",
+            Self::DatagramAudio => "/audio/datagram",
+            Self::SubscribeAv => "/av/view",
+            Self::Chat => "/chat",
+            Self::PubSub => "/pubsub",
         }
     }
-    pub fn subscribe(&mut self, handler: WebTransportRequest) {
-        self.members.insert(handler.conn.clone(), handler);
+    pub fn role(&self) -> &'static str {
+        match self {
+            Self::PublishVideo | Self::PublishAudio | Self::PublishAv => "publisher",
+            Self::SubscribeVideo
+            | Self::SubscribeAudio
+            | Self::WarpVideo
+            | Self::WarpAudio
+            | Self::DatagramVideo
+            | Self::DatagramAudio
+            | Self::SubscribeAv => "viewer",
+            Self::Chat => "chatter",
+            Self::PubSub => "subscriber",
+        }
     }
-    pub fn leave(&mut self, conn: &ActiveConnectionRef) {
-        self.members.remove(conn);
+    /// The room of the publishers and viewers of a channel.
+    pub fn room(&self) -> Option<&'static str> {
+        match self {
+            Self::PublishVideo
+            | Self::SubscribeVideo
+            | Self::WarpVideo
+            | Self::DatagramVideo => Some("video"),
+            Self::PublishAudio
+            | Self::SubscribeAudio
+            | Self::WarpAudio
+            | Self::DatagramAudio => Some("audio"),
+            Self::PublishAv | Self::SubscribeAv => Some("av"),
+            Self::Chat | Self::PubSub => None,
+        }
     }
+}
 
-    pub fn publish(&mut self, stream_id: StreamId, data: Vec<u8>, fin: bool) {
-        if fin {
-            let data = self.buf.remove(&stream_id).unwrap();
-            println!("send {} bytes data.", data.len());
+/// Topic where the server announces the publishers joining and leaving the channels.
+const CHANNELS_TOPIC: &str = "channels";
 
-            // send data with new stream.
-            for (_conn, handler) in self.members.iter_mut() {
-                match handler.create_stream(StreamType::UniDi) {
-                    Ok(mut stream) => {
-                        stream.send_data(data.as_slice());
-                        stream.stream_close_send();
-                    },
-                    Err(err) => {
-                        qerror!("create stream error. {}", err)
-                    },
-                }
-            }
-        } else {
-            // add buffer
-            match self.buf.get_mut(&stream_id) {
-                Some(b) => { b.extend(data); },
-                None => {
-                    self.buf.insert(stream_id, data);
-                },
-            };
-        }
-    }
-    pub fn stop(&mut self, stream_id: &StreamId) {
-        self.buf.remove(stream_id);
-    }
+fn announce(topics: &mut Topics, channel: &str, event: &str) {
+    let message = json!({ "channel": channel, "publisher": event }).to_string();
+    topics.publish(CHANNELS_TOPIC, message.as_bytes());
 }
 
-pub enum MyHandler {
-    PublishVideo,
-    PublishAudio,
-    SubscribeVideo,
-    SubscribeAudio,
+pub struct SessionInfo {
+    pub id: u64,
+    pub session: WebTransportRequest,
+    pub remote: Option<SocketAddr>,
+    pub since: Instant,
 }
 
 struct WebTransportServer {
     server: Http3Server,
     handler: HashMap<ActiveConnectionRef, MyHandler>,
+    sessions: HashMap<ActiveConnectionRef, SessionInfo>,
+    next_session_id: u64,
+    // source of the datagram being processed.
+    // neqo doesn't expose the peer address of a connection, so it is taken from here.
+    remote: Option<SocketAddr>,
     video_publisher: Publisher,
     audio_publisher: Publisher,
+    av: AvChannel,
+    vod: Vod,
+    topics: Topics,
+    presence: Presence,
+    metrics: Rc<RefCell<Metrics>>,
+    health: Health,
+    // `/metrics`, probes and snapshots being sent.
+    responses: Responses,
+    // the video channel is fed by `--relay`.
+    relaying: bool,
 }
 impl WebTransportServer {
-    pub fn new(server: Http3Server) -> Self {
+    pub fn new(server: Http3Server, args: &Args) -> Self {
+        let metrics = Rc::new(RefCell::new(Metrics::new()));
         Self {
             server,
             handler: HashMap::new(),
-            video_publisher: Publisher::new(),
-            audio_publisher: Publisher::new(),
+            sessions: HashMap::new(),
+            next_session_id: 0,
+            remote: None,
+            video_publisher: Publisher::new(
+                "video",
+                args.max_chunk_size,
+                args.record_config(),
+                metrics.clone(),
+            )
+            .with_datagram(args.datagram_config(Media::Video)),
+            audio_publisher: Publisher::new(
+                "audio",
+                args.max_chunk_size,
+                args.record_config(),
+                metrics.clone(),
+            )
+            .with_datagram(args.datagram_config(Media::Audio)),
+            av: AvChannel::new(args.max_chunk_size, args.record_config(), metrics.clone()),
+            vod: Vod::new(args.record_dir.clone(), metrics.clone()),
+            topics: {
+                let mut topics = Topics::new(metrics.clone());
+                topics.declare(CHANNELS_TOPIC, Delivery::Stream);
+                for prefix in &TOPIC_PREFIXES {
+                    topics.reserve(prefix, Delivery::Stream);
+                }
+                topics
+            },
+            presence: Presence::new(metrics.clone()),
+            metrics,
+            health: Health::default(),
+            responses: Responses::default(),
+            relaying: args.relay.is_some(),
+        }
+    }
+
+    fn channel(&self, name: &str) -> Option<&Publisher> {
+        match name {
+            "video" => Some(&self.video_publisher),
+            "audio" => Some(&self.audio_publisher),
+            "av-video" => Some(&self.av.video),
+            "av-audio" => Some(&self.av.audio),
+            _ => None,
+        }
+    }
+
+    fn remove_session(&mut self, session: &WebTransportRequest) {
+        if let Some(h) = self.handler.get(&session.conn) {
+            self.metrics.borrow_mut().session_closed(h.path());
+            match h {
+                MyHandler::PublishVideo => {
+                    self.video_publisher.end(&session.conn);
+                    announce(&mut self.topics, "video", "left");
+                }
+                MyHandler::PublishAudio => {
+                    self.audio_publisher.end(&session.conn);
+                    announce(&mut self.topics, "audio", "left");
+                }
+                MyHandler::PublishAv => {
+                    self.av.end(&session.conn);
+                    announce(&mut self.topics, "av", "left");
+                }
+                MyHandler::SubscribeVideo => {
+                    self.video_publisher.leave(&session.conn);
+                    self.vod.leave(&session.conn);
+                }
+                MyHandler::SubscribeAudio => {
+                    self.audio_publisher.leave(&session.conn);
+                    self.vod.leave(&session.conn);
+                }
+                MyHandler::WarpVideo => self.video_publisher.leave(&session.conn),
+                MyHandler::WarpAudio => self.audio_publisher.leave(&session.conn),
+                MyHandler::DatagramVideo => self.video_publisher.leave(&session.conn),
+                MyHandler::DatagramAudio => self.audio_publisher.leave(&session.conn),
+                MyHandler::SubscribeAv => self.av.leave(&session.conn),
+                MyHandler::Chat => {}
+                MyHandler::PubSub => self.topics.leave(&session.conn),
+            }
+        }
+        self.presence.leave(&mut self.topics, &session.conn);
+        self.handler.remove(&session.conn);
+        self.sessions.remove(&session.conn);
+    }
+
+    /// Close a WebTransport session with an application error code, leaving the other
+    /// sessions of its connection open.
+    fn close_session(&mut self, conn: &ActiveConnectionRef, code: u32, reason: &str) {
+        if let Some(info) = self.sessions.get(conn) {
+            let mut session = info.session.clone();
+            let _log = logging::enter(self.log_context(conn));
+            qinfo!("close session code={} reason={}", code, reason);
+            self.remove_session(&session);
+            if let Err(err) = session.close_session(code, reason) {
+                qwarn!("close session error. {:?}", err);
+            }
+        }
+    }
+
+    /// Close the connection of a session with an application error code.
+    fn close_connection(&mut self, conn: &ActiveConnectionRef, code: u64, reason: &str) {
+        if let Some(info) = self.sessions.get(conn) {
+            let session = info.session.clone();
+            let _log = logging::enter(self.log_context(conn));
+            qinfo!("close connection code={} reason={}", code, reason);
+            self.remove_session(&session);
+            conn.clone().borrow_mut().close(Instant::now(), code, reason);
+        }
+    }
+
+    /// Prometheus text of the server counters and the neqo stats of live sessions.
+    fn render_metrics(&self) -> String {
+        let mut conns = BTreeMap::new();
+        for (conn, h) in self.handler.iter() {
+            conns
+                .entry(h.path().to_string())
+                .or_insert_with(PathStats::default)
+                .add(&conn.borrow().stats(), conn.borrow().cwnd_avail());
+        }
+        self.metrics.borrow().render(&conns)
+    }
+
+    /// Subscribe a viewer to the live channel, or to a recording when the query has `vod`.
+    /// Returns false when the session was refused.
+    fn subscribe(
+        &mut self,
+        session: &mut WebTransportRequest,
+        channel: &'static str,
+        query: &[(String, String)],
+    ) -> bool {
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        match param("vod") {
+            None => {
+                // without a layer (or with `auto`) the server picks one for the viewer.
+                let layer = param("layer").and_then(|l| l.parse().ok());
+                match channel {
+                    "video" => self.video_publisher.subscribe(session.clone(), layer),
+                    _ => self
+                        .audio_publisher
+                        .subscribe(session.clone(), Some(DEFAULT_LAYER)),
+                }
+                let _ = session.response(true);
+                true
+            }
+            Some(id) => {
+                let reader = match self.vod.open(channel, id) {
+                    Ok(reader) => reader,
+                    Err(err) => {
+                        qwarn!("no recording {} of {}: {}", id, channel, err);
+                        let _ = session.send_headers(&[
+                            Header::new(":status", "404"),
+                            Header::new("sec-webtransport-http3-draft", "draft02"),
+                        ]);
+                        return false;
+                    }
+                };
+                let offset = param("offset").and_then(|o| o.parse().ok()).unwrap_or(0);
+                let _ = session.response(true);
+                match self.vod.subscribe(channel, session.clone(), reader, offset) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        qerror!("replay {} {} failed: {}", channel, id, err);
+                        let _ = session.close_session(0, &err.to_string());
+                        false
+                    }
+                }
+            }
         }
     }
 
+    fn log_context(&self, conn: &ActiveConnectionRef) -> LogContext {
+        let info = self.sessions.get(conn);
+        LogContext {
+            session: info.map(|s| s.id),
+            remote: info.and_then(|s| s.remote),
+            path: self.handler.get(conn).map(|h| h.path().to_string()),
+            stream: None,
+        }
+    }
+
+    fn event_context(&self, event: &Http3ServerEvent) -> LogContext {
+        let (conn, stream) = match event {
+            Http3ServerEvent::Headers { stream, .. }
+            | Http3ServerEvent::Data { stream, .. }
+            | Http3ServerEvent::DataWritable { stream }
+            | Http3ServerEvent::StreamReset { stream, .. }
+            | Http3ServerEvent::StreamStopSending { stream, .. }
+            | Http3ServerEvent::WebTransport(WebTransportServerEvent::NewStream(stream)) => {
+                (Some(&stream.conn), Some(stream.stream_id()))
+            }
+            Http3ServerEvent::WebTransport(WebTransportServerEvent::NewSession {
+                session, ..
+            })
+            | Http3ServerEvent::WebTransport(WebTransportServerEvent::SessionClosed {
+                session,
+                ..
+            })
+            | Http3ServerEvent::WebTransport(WebTransportServerEvent::Datagram {
+                session, ..
+            }) => (Some(&session.conn), Some(session.stream_id())),
+            Http3ServerEvent::StateChange { conn, .. } => (Some(conn), None),
+            _ => (None, None),
+        };
+        let mut ctx = conn.map(|c| self.log_context(c)).unwrap_or_default();
+        ctx.stream = stream;
+        if ctx.remote.is_none() {
+            ctx.remote = self.remote;
+        }
+        ctx
+    }
+
     fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = &dgram {
+            self.remote = Some(d.source());
+        }
         self.server.process(dgram, now)
     }
 
     fn process_events(&mut self, _args: &Args, _now: Instant) {
         while let Some(event) = self.server.next_event() {
             // println!("{:#?}", event);
+            let _log = logging::enter(self.event_context(&event));
             match event {
                 Http3ServerEvent::WebTransport(wt) => match wt {
                     WebTransportServerEvent::NewSession {
                         mut session,
                         headers,
                     } => {
-                        println!("Headers (request={}): {:?}", session, headers);
+                        qinfo!("Headers (request={}): {:?}", session, headers);
+                        if self.health.draining {
+                            let _ = session.send_headers(&[
+                                Header::new(":status", "503"),
+                                Header::new("sec-webtransport-http3-draft", "draft02"),
+                            ]);
+                            continue;
+                        }
                         match headers.iter().find(|&h| h.name() == ":path") {
-                            Some(h) => match h.value() {
-                                "/video/stream" => {
+                            Some(h) => match http1::split_target(h.value()) {
+                                ("/video/stream", _) if self.relaying => {
+                                    qwarn!("refuse a video publisher while relaying");
+                                    let _ = session.send_headers(&[
+                                        Header::new(":status", "409"),
+                                        Header::new("sec-webtransport-http3-draft", "draft02"),
+                                    ]);
+                                }
+                                ("/video/stream", _)
+                                    if !self
+                                        .video_publisher
+                                        .accepts(&Source::Session(session.conn.clone())) =>
+                                {
+                                    qwarn!("refuse a video publisher while RTP publishes");
+                                    let _ = session.send_headers(&[
+                                        Header::new(":status", "409"),
+                                        Header::new("sec-webtransport-http3-draft", "draft02"),
+                                    ]);
+                                }
+                                ("/video/stream", query) => {
                                     self.handler
                                         .insert(session.conn.clone(), MyHandler::PublishVideo);
+                                    if query.iter().any(|(k, _)| k == "simulcast") {
+                                        self.video_publisher.start_simulcast(&session.conn);
+                                    } else {
+                                        self.video_publisher.start(&session.conn);
+                                    }
+                                    announce(&mut self.topics, "video", "joined");
                                     let _ = session.response(true);
                                 }
-                                "/video/view" => {
+                                ("/video/view", query) => {
+                                    if self.subscribe(&mut session, "video", &query) {
+                                        self.handler
+                                            .insert(session.conn.clone(), MyHandler::SubscribeVideo);
+                                    }
+                                }
+                                ("/audio/stream", _)
+                                    if !self
+                                        .audio_publisher
+                                        .accepts(&Source::Session(session.conn.clone())) =>
+                                {
+                                    qwarn!("refuse an audio publisher while RTP publishes");
+                                    let _ = session.send_headers(&[
+                                        Header::new(":status", "409"),
+                                        Header::new("sec-webtransport-http3-draft", "draft02"),
+                                    ]);
+                                }
+                                ("/audio/stream", _) => {
                                     self.handler
-                                        .insert(session.conn.clone(), MyHandler::SubscribeVideo);
-                                    self.video_publisher.subscribe(session.clone());
+                                        .insert(session.conn.clone(), MyHandler::PublishAudio);
+                                    self.audio_publisher.start(&session.conn);
+                                    announce(&mut self.topics, "audio", "joined");
                                     let _ = session.response(true);
                                 }
-                                "/audio/stream" => {
+                                ("/audio/view", query) => {
+                                    if self.subscribe(&mut session, "audio", &query) {
+                                        self.handler
+                                            .insert(session.conn.clone(), MyHandler::SubscribeAudio);
+                                    }
+                                }
+                                ("/video/warp", _) => {
                                     self.handler
-                                        .insert(session.conn.clone(), MyHandler::PublishAudio);
+                                        .insert(session.conn.clone(), MyHandler::WarpVideo);
+                                    let _ = session.response(true);
+                                    self.video_publisher.subscribe_warp(session.clone());
+                                }
+                                ("/audio/warp", _) => {
+                                    self.handler
+                                        .insert(session.conn.clone(), MyHandler::WarpAudio);
+                                    let _ = session.response(true);
+                                    self.audio_publisher.subscribe_warp(session.clone());
+                                }
+                                ("/video/datagram", _) => {
+                                    self.handler
+                                        .insert(session.conn.clone(), MyHandler::DatagramVideo);
                                     let _ = session.response(true);
+                                    self.video_publisher.subscribe_datagram(session.clone());
                                 }
-                                "/audio/view" => {
+                                ("/audio/datagram", _) => {
                                     self.handler
-                                        .insert(session.conn.clone(), MyHandler::SubscribeAudio);
-                                    self.audio_publisher.subscribe(session.clone());
+                                        .insert(session.conn.clone(), MyHandler::DatagramAudio);
+                                    let _ = session.response(true);
+                                    self.audio_publisher.subscribe_datagram(session.clone());
+                                }
+                                ("/av/stream", _) => {
+                                    self.handler
+                                        .insert(session.conn.clone(), MyHandler::PublishAv);
+                                    self.av.start(&session.conn);
+                                    announce(&mut self.topics, "av", "joined");
+                                    let _ = session.response(true);
+                                }
+                                ("/av/view", _) => {
+                                    self.handler
+                                        .insert(session.conn.clone(), MyHandler::SubscribeAv);
+                                    self.av.subscribe(session.clone());
+                                    let _ = session.response(true);
+                                }
+                                ("/chat", _) => {
+                                    self.handler.insert(session.conn.clone(), MyHandler::Chat);
                                     let _ = session.response(true);
                                 }
-                                "/chat" => {
+                                ("/pubsub", _) => {
+                                    self.handler.insert(session.conn.clone(), MyHandler::PubSub);
+                                    self.topics.join(session.clone());
                                     let _ = session.response(true);
                                 }
                                 _ => {
@@ -327,41 +836,192 @@ impl WebTransportServer {
                                 let _ = session.cancel_fetch(Error::HttpRequestIncomplete.code());
                             }
                         }
+                        if let Some(h) = self.handler.get(&session.conn) {
+                            self.metrics.borrow_mut().session_opened(h.path());
+                            self.next_session_id += 1;
+                            self.sessions.insert(
+                                session.conn.clone(),
+                                SessionInfo {
+                                    id: self.next_session_id,
+                                    session: session.clone(),
+                                    remote: self.remote,
+                                    since: Instant::now(),
+                                },
+                            );
+                            let _log = logging::enter(
+                                self.log_context(&session.conn).stream(session.stream_id()),
+                            );
+                            qinfo!("session accepted");
+                            qlog_events::session_accepted(
+                                &session.conn,
+                                session.stream_id(),
+                                self.handler[&session.conn].path(),
+                            );
+                            // chat sessions pick their room, the others are in their channel's.
+                            let h = &self.handler[&session.conn];
+                            let query = headers
+                                .iter()
+                                .find(|&h| h.name() == ":path")
+                                .map_or_else(Vec::new, |h| http1::split_target(h.value()).1);
+                            let param = |name: &str| {
+                                query
+                                    .iter()
+                                    .find(|(k, _)| k == name)
+                                    .map(|(_, v)| http1::percent_decode(v))
+                            };
+                            let room = match h {
+                                MyHandler::Chat => {
+                                    Some(param("room").unwrap_or_else(|| DEFAULT_ROOM.to_string()))
+                                }
+                                h => h.room().map(str::to_string),
+                            };
+                            if let Some(room) = room {
+                                self.presence.join(
+                                    &mut self.topics,
+                                    &room,
+                                    self.next_session_id,
+                                    session.clone(),
+                                    h.role(),
+                                    &param("name").unwrap_or_default(),
+                                );
+                            }
+                        }
                     }
-                    WebTransportServerEvent::NewStream(_stream) => {
+                    WebTransportServerEvent::NewStream(stream) => {
                         // $B%P%C%U%!$N:n@.$O%G!<%?DI2C;~$K9T$&$N$G$3$3$G$OFC$K2?$b$7$J$$(B
+                        self.metrics.borrow_mut().stream_opened("client");
+                        qlog_events::stream_opened(&stream.conn, stream.stream_id(), "client");
                     }
                     WebTransportServerEvent::SessionClosed { session, error: _ } => {
+                        self.remove_session(&session);
+                    }
+                    WebTransportServerEvent::Datagram { session, datagram } => {
+                        // NACKs of the datagram viewers, messages of the pubsub sessions.
                         match self.handler.get(&session.conn) {
-                            Some(h) => match h {
-                                MyHandler::PublishVideo => self.video_publisher.stop(&session.stream_id()),
-                                MyHandler::PublishAudio => self.audio_publisher.stop(&session.stream_id()),
-                                MyHandler::SubscribeVideo => {
-                                    self.video_publisher.leave(&session.conn)
-                                }
-                                MyHandler::SubscribeAudio => {
-                                    self.audio_publisher.leave(&session.conn)
-                                }
-                            },
-                            None => {}
-                        };
-                        self.handler.remove(&session.conn);
+                            Some(MyHandler::DatagramVideo) => {
+                                self.video_publisher.nack(&session.conn, &datagram)
+                            }
+                            Some(MyHandler::DatagramAudio) => {
+                                self.audio_publisher.nack(&session.conn, &datagram)
+                            }
+                            Some(MyHandler::PubSub) => {
+                                self.topics.receive_datagram(&session.conn, &datagram)
+                            }
+                            _ => {}
+                        }
                     }
                 },
-                Http3ServerEvent::Data { stream, data, fin } => {
+                Http3ServerEvent::Data {
+                    mut stream,
+                    data,
+                    fin,
+                } => {
                     match self.handler.get(&stream.conn) {
                         Some(h) => match h {
                             MyHandler::PublishVideo => {
-                                self.video_publisher.publish(stream.stream_id(), data, fin)
+                                self.video_publisher.publish(&mut stream, data, fin);
                             }
                             MyHandler::PublishAudio => {
-                                self.audio_publisher.publish(stream.stream_id(), data, fin)
+                                self.audio_publisher.publish(&mut stream, data, fin);
+                            }
+                            MyHandler::PublishAv => {
+                                self.av.publish(&mut stream, data, fin)
+                            }
+                            MyHandler::SubscribeVideo | MyHandler::SubscribeAudio
+                                if self.vod.contains(&stream.conn) =>
+                            {
+                                // commands on the control stream of a replay.
+                                self.vod.control(stream, &data)
+                            }
+                            MyHandler::SubscribeVideo => {
+                                // layer requests of a live viewer.
+                                self.video_publisher.control(stream, &data)
+                            }
+                            MyHandler::PubSub if stream.stream_id().is_bidi() => {
+                                self.topics.control(stream, &data)
+                            }
+                            MyHandler::PubSub => self.topics.receive(stream, data, fin),
+                            MyHandler::Chat => {
+                                self.presence.receive(&mut self.topics, stream, data, fin)
                             }
                             _ => {}
                         },
                         None => {}
                     };
                 }
+                Http3ServerEvent::Headers {
+                    stream,
+                    headers,
+                    fin: _,
+                } => {
+                    let method = headers.iter().find(|&h| h.name() == ":method");
+                    let path = headers.iter().find(|&h| h.name() == ":path");
+                    match (method.map(Header::value), path.map(Header::value)) {
+                        (Some("GET"), Some("/metrics")) => {
+                            let body = self.render_metrics();
+                            self.responses.send(
+                                stream,
+                                "200",
+                                "text/plain; version=0.0.4",
+                                body.into_bytes(),
+                            );
+                        }
+                        (Some("GET"), Some(p @ ("/healthz" | "/readyz"))) => {
+                            if let Some((status, body)) = self.health.respond(p) {
+                                self.responses.send(
+                                    stream,
+                                    &status.to_string(),
+                                    "text/plain",
+                                    body.into_bytes(),
+                                );
+                            }
+                        }
+                        (Some("GET"), Some(p))
+                            if p.starts_with("/channels/") && p.ends_with("/snapshot") =>
+                        {
+                            let name = &p["/channels/".len()..p.len() - "/snapshot".len()];
+                            match self.channel(name).and_then(Publisher::snapshot) {
+                                // the keyframe in base64 rarely fits into the send buffer at once.
+                                Some(snapshot) => self.responses.send(
+                                    stream,
+                                    "200",
+                                    "application/json",
+                                    snapshot.to_string().into_bytes(),
+                                ),
+                                None => self.responses.send(
+                                    stream,
+                                    "404",
+                                    "text/plain",
+                                    b"no snapshot\n".to_vec(),
+                                ),
+                            }
+                        }
+                        (Some("GET"), Some(p)) if p.starts_with("/video/hls/") => {
+                            let path = &p["/video/hls/".len()..];
+                            self.video_publisher.hls.get(stream, path, Instant::now());
+                        }
+                        (Some("GET"), Some(p)) if p.starts_with("/audio/hls/") => {
+                            let path = &p["/audio/hls/".len()..];
+                            self.audio_publisher.hls.get(stream, path, Instant::now());
+                        }
+                        _ => {
+                            self.responses.send(stream, "404", "text/plain", b"not found\n".to_vec());
+                        }
+                    }
+                }
+                Http3ServerEvent::DataWritable { stream } => {
+                    // the rest of a large response.
+                    self.responses.writable(&stream);
+                    self.video_publisher.hls.writable(&stream);
+                    self.audio_publisher.hls.writable(&stream);
+                }
+                Http3ServerEvent::StreamReset { stream, .. }
+                | Http3ServerEvent::StreamStopSending { stream, .. } => {
+                    self.metrics.borrow_mut().stream_reset();
+                    self.responses.stopped(&stream);
+                    self.video_publisher.hls.stopped(&stream);
+                    self.audio_publisher.hls.stopped(&stream);
+                }
                 _ => {}
             }
         }
@@ -391,23 +1051,26 @@ impl WebTransportServer {
 fn read_dgram(
     socket: &mut UdpSocket,
     local_address: &SocketAddr,
+    metrics: &RefCell<Metrics>,
 ) -> Result<Option<Datagram>, io::Error> {
     let buf = &mut [0u8; 2048];
     let (sz, remote_addr) = match socket.recv_from(&mut buf[..]) {
         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
         Err(err) => {
-            eprintln!("UDP recv error: {:?}", err);
+            qerror!("UDP recv error: {:?}", err);
             return Err(err);
         }
         Ok(res) => res,
     };
 
     if sz == buf.len() {
-        eprintln!("Might have received more than {} bytes", buf.len());
+        qwarn!("Might have received more than {} bytes", buf.len());
+        metrics.borrow_mut().datagram_dropped();
     }
 
     if sz == 0 {
-        eprintln!("zero length datagram received?");
+        qwarn!("zero length datagram received?");
+        metrics.borrow_mut().datagram_dropped();
         Ok(None)
     } else {
         Ok(Some(Datagram::new(remote_addr, *local_address, &buf[..sz])))
@@ -423,6 +1086,22 @@ struct ServersRunner {
     sockets: Vec<UdpSocket>,
     active_sockets: HashSet<usize>,
     timer: Timer<usize>,
+    admin: Option<HttpListener>,
+    probe: Option<HttpListener>,
+    signals: Signals,
+    // set once SIGTERM is received.
+    drain_deadline: Option<Instant>,
+    // connections closed at the drain deadline, until they sent CONNECTION_CLOSE or the
+    // time given to do so passed.
+    closing: Vec<ActiveConnectionRef>,
+    close_deadline: Option<Instant>,
+    // when the next chunk of a replay is due.
+    vod_deadline: Option<Instant>,
+    // when the viewers on automatic layer selection are sampled next.
+    abr_deadline: Option<Instant>,
+    relay: Option<Relay>,
+    rtp_video: Option<RtpIngest>,
+    rtp_audio: Option<RtpIngest>,
 }
 
 impl ServersRunner {
@@ -439,6 +1118,17 @@ impl ServersRunner {
             timer: Builder::default()
                 .tick_duration(Duration::from_millis(1))
                 .build::<usize>(),
+            admin: None,
+            probe: None,
+            signals: Signals::new([SIGTERM, SIGINT])?,
+            drain_deadline: None,
+            closing: Vec::new(),
+            close_deadline: None,
+            vod_deadline: None,
+            abr_deadline: None,
+            relay: None,
+            rtp_video: None,
+            rtp_audio: None,
         };
         runner.init()?;
         Ok(runner)
@@ -493,6 +1183,75 @@ impl ServersRunner {
         self.poll
             .register(&self.timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
 
+        if let Some(addr) = &self.args.admin_addr {
+            if self.args.admin_token.is_empty() {
+                eprintln!("--admin-token is required with --admin-addr");
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "No admin token"));
+            }
+            self.admin = Some(HttpListener::bind(addr, &self.poll, ADMIN_TOKEN, ADMIN_CLIENTS)?);
+        }
+        if let Some(addr) = &self.args.probe_addr {
+            self.probe = Some(HttpListener::bind(addr, &self.poll, PROBE_TOKEN, PROBE_CLIENTS)?);
+        }
+        self.poll.register(
+            &self.signals,
+            SIGNAL_TOKEN,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        if let Some(upstream) = &self.args.relay {
+            let certificate = match (&self.args.relay_cert, self.args.relay_insecure) {
+                (Some(path), _) => Some(relay::read_certificate(path)?),
+                (None, true) => None,
+                (None, false) => {
+                    eprintln!("--relay-cert or --relay-insecure is required with --relay");
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "No relay certificate"));
+                }
+            };
+            let relay = Relay::new(
+                upstream,
+                &self.args.relay_path,
+                certificate,
+                self.server.metrics.clone(),
+            )?;
+            self.poll.register(
+                relay.socket(),
+                RELAY_TOKEN,
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+            self.relay = Some(relay);
+        }
+        if let Some(addr) = &self.args.rtp_video {
+            let ingest = RtpIngest::bind(addr, Media::Video, self.server.metrics.clone())?;
+            self.poll.register(
+                ingest.socket(),
+                RTP_VIDEO_TOKEN,
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+            self.rtp_video = Some(ingest);
+        }
+        if let Some(addr) = &self.args.rtp_audio {
+            let ingest = RtpIngest::bind(addr, Media::Audio, self.server.metrics.clone())?;
+            self.poll.register(
+                ingest.socket(),
+                RTP_AUDIO_TOKEN,
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+            self.rtp_audio = Some(ingest);
+        }
+        if let Some(addr) = self.args.rtp_out_video {
+            self.server.video_publisher.egress =
+                Some(RtpEgress::new("video", addr, &self.args.rtp_out_dir)?);
+        }
+        if let Some(addr) = self.args.rtp_out_audio {
+            self.server.audio_publisher.egress =
+                Some(RtpEgress::new("audio", addr, &self.args.rtp_out_dir)?);
+        }
+        self.server.health.sockets_bound = true;
+
         Ok(())
     }
 
@@ -512,6 +1271,9 @@ impl ServersRunner {
                     .max_table_size_encoder(args.max_table_size_encoder)
                     .max_table_size_decoder(args.max_table_size_decoder)
                     .max_blocked_streams(args.max_blocked_streams)
+                    .connection_parameters(
+                        ConnectionParameters::default().datagram_size(DATAGRAM_FRAME_SIZE),
+                    )
                     .webtransport(true),
                 None,
             )
@@ -520,7 +1282,15 @@ impl ServersRunner {
                 server.set_preferred_address(spa);
             }
             server
-        });
+        }, args);
+        // the certificate and its key are looked up in the NSS database when a TLS server is made.
+        svr.health.cert_loaded = match TlsServer::new(&[args.key.clone()]) {
+            Ok(_) => true,
+            Err(err) => {
+                eprintln!("Unable to load certificate '{}': {:?}", args.key, err);
+                false
+            }
+        };
         svr.set_ciphers(&args.get_ciphers());
         svr.set_qlog_dir(args.qlog_dir.clone());
         if args.retry {
@@ -548,8 +1318,9 @@ impl ServersRunner {
     fn process(&mut self, inx: usize, dgram: Option<Datagram>) -> bool {
         match self.server.process(dgram, self.args.now()) {
             Output::Datagram(dgram) => {
+                let metrics = self.server.metrics.clone();
                 let socket = self.find_socket(dgram.source());
-                emit_packet(socket, dgram);
+                emit_packet(socket, dgram, &metrics);
                 true
             }
             Output::Callback(new_timeout) => {
@@ -557,7 +1328,7 @@ impl ServersRunner {
                     self.timer.cancel_timeout(to);
                 }
 
-                qinfo!("Setting timeout of {:?} for socket {}", new_timeout, inx);
+                qdebug!("Setting timeout of {:?} for socket {}", new_timeout, inx);
                 self.timeout = Some(self.timer.set_timeout(new_timeout, inx));
                 false
             }
@@ -577,11 +1348,13 @@ impl ServersRunner {
             if read_socket {
                 loop {
                     let socket = self.sockets.get_mut(inx).unwrap();
-                    let dgram = read_dgram(socket, &self.hosts[inx])?;
+                    let dgram = read_dgram(socket, &self.hosts[inx], &self.server.metrics)?;
                     if dgram.is_none() {
                         break;
                     }
                     let _ = self.process(inx, dgram);
+                    // handle events while the source of the datagram is known.
+                    self.server.process_events(&self.args, self.args.now());
                 }
             } else {
                 let _ = self.process(inx, None);
@@ -604,28 +1377,202 @@ impl ServersRunner {
 
     fn process_timeout(&mut self) -> Result<(), io::Error> {
         while let Some(inx) = self.timer.poll() {
-            qinfo!("Timer expired for {:?}", inx);
+            qdebug!("Timer expired for {:?}", inx);
             self.process_datagrams_and_events(inx, false)?;
         }
         Ok(())
     }
 
+    fn process_admin(&mut self, token: Token) -> Result<(), io::Error> {
+        if let Some(admin) = &mut self.admin {
+            let (server, admin_token) = (&mut self.server, &self.args.admin_token);
+            admin.ready(&self.poll, token, |req| admin::handle(server, admin_token, req));
+        }
+        // flush the closes of kicked sessions.
+        self.process_datagrams_and_events(0, false)
+    }
+
+    fn process_probe(&mut self, token: Token) {
+        if let Some(probe) = &mut self.probe {
+            let health = &self.server.health;
+            probe.ready(&self.poll, token, |req| match health.respond(&req.path) {
+                Some((status, body)) if req.method == "GET" => {
+                    Response::new(status, "text/plain", body)
+                }
+                Some(_) => Response::text(405, "method not allowed"),
+                None => Response::text(404, "not found"),
+            });
+        }
+    }
+
+    /// Send the chunks of replays that are due.
+    fn process_vod(&mut self) -> Result<(), io::Error> {
+        let (sent, next) = self.server.vod.process(Instant::now());
+        self.vod_deadline = next;
+        if sent {
+            self.process_datagrams_and_events(0, false)?;
+        }
+        Ok(())
+    }
+
+    /// Take the chunks of the upstream and send them to local viewers.
+    fn process_relay(&mut self) -> Result<(), io::Error> {
+        let relay = match &mut self.relay {
+            Some(relay) => relay,
+            None => return Ok(()),
+        };
+        if relay.process(&mut self.server.video_publisher, Instant::now())? {
+            self.process_datagrams_and_events(0, false)?;
+        }
+        Ok(())
+    }
+
+    /// Publish what arrived over RTP.
+    fn process_rtp(&mut self) -> Result<(), io::Error> {
+        let now = Instant::now();
+        let mut published = false;
+        if let Some(ingest) = &mut self.rtp_video {
+            published |= ingest.process(&mut self.server.video_publisher, now)?;
+        }
+        if let Some(ingest) = &mut self.rtp_audio {
+            published |= ingest.process(&mut self.server.audio_publisher, now)?;
+        }
+        if published {
+            self.process_datagrams_and_events(0, false)?;
+        }
+        Ok(())
+    }
+
+    /// Answer the HLS requests held too long.
+    fn process_hls(&mut self) -> Result<(), io::Error> {
+        let now = Instant::now();
+        let video = self.server.video_publisher.hls.expire(now);
+        let audio = self.server.audio_publisher.hls.expire(now);
+        if video || audio {
+            self.process_datagrams_and_events(0, false)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the HTTP/1.1 connections that timed out.
+    fn process_http(&mut self) {
+        let now = Instant::now();
+        for listener in self.admin.iter_mut().chain(self.probe.iter_mut()) {
+            listener.expire(&self.poll, now);
+        }
+    }
+
+    fn process_abr(&mut self) {
+        self.abr_deadline = self.server.video_publisher.adapt(Instant::now());
+    }
+
+    /// Returns true when the server should exit.
+    fn process_signals(&mut self) -> bool {
+        let mut received = false;
+        for sig in self.signals.pending() {
+            qinfo!("received signal {}", sig);
+            received = true;
+        }
+        if !received {
+            return false;
+        }
+        if self.drain_deadline.is_some() {
+            // second signal, stop without waiting.
+            return true;
+        }
+        qinfo!(
+            "start draining {} sessions for {}s",
+            self.server.sessions.len(),
+            self.args.drain_timeout
+        );
+        self.server.health.draining = true;
+        self.drain_deadline = Some(Instant::now() + Duration::from_secs(self.args.drain_timeout));
+        false
+    }
+
+    /// Returns true when every session has ended, or when the connections left at the drain
+    /// deadline are closed and nothing is left to send.
+    fn drained(&mut self) -> Result<bool, io::Error> {
+        let deadline = match self.drain_deadline {
+            Some(d) => d,
+            None => return Ok(false),
+        };
+        let now = Instant::now();
+        if let Some(give_up) = self.close_deadline {
+            // the sockets stay active while CONNECTION_CLOSE frames are being sent.
+            let closed = self.closing.iter().all(|c| {
+                matches!(
+                    c.borrow().state(),
+                    State::Closing { .. } | State::Draining { .. } | State::Closed(_)
+                )
+            });
+            return Ok((closed && self.active_sockets.is_empty()) || now >= give_up);
+        }
+        if self.server.sessions.is_empty() {
+            return Ok(true);
+        }
+        if now < deadline {
+            return Ok(false);
+        }
+        self.closing = self.server.sessions.keys().cloned().collect();
+        for conn in self.closing.clone() {
+            self.server.close_connection(&conn, 0, "server shutting down");
+        }
+        self.close_deadline = Some(now + CLOSE_TIMEOUT);
+        self.process_datagrams_and_events(0, false)?;
+        Ok(false)
+    }
+
     pub fn run(&mut self) -> Result<(), io::Error> {
         let mut events = Events::with_capacity(1024);
         loop {
             // If there are active servers do not block in poll.
+            // While draining wake up regularly to check the deadline,
+            // and wake up for the next chunk of a replay, the next layer selection,
+            // the timers of the relay, the end of an RTP stream or a held HLS request.
+            let vod = [
+                self.vod_deadline,
+                self.abr_deadline,
+                self.relay.as_ref().and_then(Relay::deadline),
+                self.rtp_video.as_ref().and_then(RtpIngest::deadline),
+                self.rtp_audio.as_ref().and_then(RtpIngest::deadline),
+                self.server.video_publisher.hls.deadline(),
+                self.server.audio_publisher.hls.deadline(),
+                self.admin.as_ref().and_then(HttpListener::deadline),
+                self.probe.as_ref().and_then(HttpListener::deadline),
+            ]
+            .iter()
+            .flatten()
+            .min()
+            .map(|d| d.saturating_duration_since(Instant::now()));
+            let drain = self.drain_deadline.map(|_| Duration::from_millis(100));
             self.poll.poll(
                 &mut events,
-                if self.active_sockets.is_empty() {
-                    None
-                } else {
+                if !self.active_sockets.is_empty() {
                     Some(Duration::from_millis(0))
+                } else {
+                    match (vod, drain) {
+                        (Some(v), Some(d)) => Some(v.min(d)),
+                        (v, d) => v.or(d),
+                    }
                 },
             )?;
 
             for event in &events {
                 if event.token() == TIMER_TOKEN {
                     self.process_timeout()?;
+                } else if self.admin.as_ref().map_or(false, |a| a.owns(event.token())) {
+                    self.process_admin(event.token())?;
+                } else if self.probe.as_ref().map_or(false, |p| p.owns(event.token())) {
+                    self.process_probe(event.token());
+                } else if event.token() == RELAY_TOKEN {
+                    self.process_relay()?;
+                } else if event.token() == RTP_VIDEO_TOKEN || event.token() == RTP_AUDIO_TOKEN {
+                    self.process_rtp()?;
+                } else if event.token() == SIGNAL_TOKEN {
+                    if self.process_signals() {
+                        return Ok(());
+                    }
                 } else {
                     if !event.readiness().is_readable() {
                         continue;
@@ -634,15 +1581,25 @@ impl ServersRunner {
                 }
             }
             self.process_active_conns()?;
+            self.process_vod()?;
+            self.process_relay()?;
+            self.process_rtp()?;
+            self.process_hls()?;
+            self.process_http();
+            self.process_abr();
+            if self.drained()? {
+                qinfo!("drained, exit.");
+                return Ok(());
+            }
         }
     }
 }
 
 fn main() -> Result<(), io::Error> {
-    env_logger::init();
     const HQ_INTEROP: &str = "hq-interop";
 
     let args = Args::from_args();
+    logging::init(args.log_json, args.log_filter.as_deref());
     assert!(!args.key.is_empty(), "Need at least one key");
 
     init_db(args.db.clone());
@@ -0,0 +1,366 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Counters and gauges exported in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use neqo_transport::Stats;
+
+/// Upper bounds (in seconds) of the fan-out latency buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, d: Duration) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; LATENCY_BUCKETS.len()];
+        }
+        let v = d.as_secs_f64();
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if v <= *le {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                label,
+                le,
+                self.counts.get(i).copied().unwrap_or(0)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, label, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, label, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, label, self.count);
+    }
+}
+
+/// A gauge of the connections on a path: name, help and value.
+type PathGauge = (&'static str, &'static str, fn(&PathStats) -> f64);
+
+/// Aggregated transport statistics of the connections on one path.
+#[derive(Debug, Default)]
+pub struct PathStats {
+    pub connections: u64,
+    pub rtt_max: Duration,
+    pub rtt_sum: Duration,
+    pub packets_rx: usize,
+    pub packets_tx: usize,
+    pub lost: usize,
+    pub dropped_rx: usize,
+    // congestion window left to fill, smallest and summed over the connections.
+    pub cwnd_avail_min: usize,
+    pub cwnd_avail_sum: usize,
+}
+
+impl PathStats {
+    pub fn add(&mut self, stats: &Stats, cwnd_avail: usize) {
+        self.cwnd_avail_min = if self.connections == 0 {
+            cwnd_avail
+        } else {
+            self.cwnd_avail_min.min(cwnd_avail)
+        };
+        self.cwnd_avail_sum += cwnd_avail;
+        self.connections += 1;
+        self.rtt_max = self.rtt_max.max(stats.rtt);
+        self.rtt_sum += stats.rtt;
+        self.packets_rx += stats.packets_rx;
+        self.packets_tx += stats.packets_tx;
+        self.lost += stats.lost;
+        self.dropped_rx += stats.dropped_rx;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // active sessions by :path.
+    sessions: BTreeMap<String, i64>,
+    // streams opened by "client" or "server".
+    streams_opened: BTreeMap<&'static str, u64>,
+    streams_reset: u64,
+    // bytes by channel name.
+    bytes_in: BTreeMap<String, u64>,
+    bytes_out: BTreeMap<String, u64>,
+    // chunks by (channel, type).
+    chunks_published: BTreeMap<(String, &'static str), u64>,
+    // rejected chunks by (channel, reason).
+    chunks_rejected: BTreeMap<(String, &'static str), u64>,
+    datagrams_dropped: u64,
+    // media fragments sent in datagrams by (channel, kind).
+    datagram_fragments: BTreeMap<(String, &'static str), u64>,
+    // pubsub messages by (delivery, result).
+    pubsub_messages: BTreeMap<(&'static str, &'static str), u64>,
+    // joins, leaves and updates of room members by event.
+    presence_events: BTreeMap<&'static str, u64>,
+    // simulcast layer switches by (channel, direction, mode).
+    layer_switches: BTreeMap<(String, &'static str, &'static str), u64>,
+    fanout_latency: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn session_opened(&mut self, path: &str) {
+        *self.sessions.entry(path.to_string()).or_insert(0) += 1;
+    }
+    pub fn session_closed(&mut self, path: &str) {
+        *self.sessions.entry(path.to_string()).or_insert(0) -= 1;
+    }
+
+    pub fn stream_opened(&mut self, initiator: &'static str) {
+        *self.streams_opened.entry(initiator).or_insert(0) += 1;
+    }
+    pub fn stream_reset(&mut self) {
+        self.streams_reset += 1;
+    }
+
+    pub fn bytes_in(&mut self, channel: &str, n: usize) {
+        *self.bytes_in.entry(channel.to_string()).or_insert(0) += n as u64;
+    }
+    pub fn bytes_out(&mut self, channel: &str, n: usize) {
+        *self.bytes_out.entry(channel.to_string()).or_insert(0) += n as u64;
+    }
+
+    pub fn published(&mut self, channel: &str, chunk_type: &'static str, latency: Duration) {
+        *self
+            .chunks_published
+            .entry((channel.to_string(), chunk_type))
+            .or_insert(0) += 1;
+        self.fanout_latency
+            .entry(channel.to_string())
+            .or_default()
+            .observe(latency);
+    }
+
+    pub fn chunk_rejected(&mut self, channel: &str, reason: &'static str) {
+        *self
+            .chunks_rejected
+            .entry((channel.to_string(), reason))
+            .or_insert(0) += 1;
+    }
+
+    pub fn layer_switched(&mut self, channel: &str, direction: &'static str, mode: &'static str) {
+        *self
+            .layer_switches
+            .entry((channel.to_string(), direction, mode))
+            .or_insert(0) += 1;
+    }
+
+    pub fn datagram_fragment(&mut self, channel: &str, kind: &'static str) {
+        *self
+            .datagram_fragments
+            .entry((channel.to_string(), kind))
+            .or_insert(0) += 1;
+    }
+
+    pub fn pubsub_message(&mut self, delivery: &'static str, result: &'static str) {
+        *self.pubsub_messages.entry((delivery, result)).or_insert(0) += 1;
+    }
+
+    pub fn presence_event(&mut self, event: &'static str) {
+        *self.presence_events.entry(event).or_insert(0) += 1;
+    }
+
+    pub fn datagram_dropped(&mut self) {
+        self.datagrams_dropped += 1;
+    }
+
+    /// Render everything in the Prometheus text exposition format.
+    /// `conns` holds the neqo statistics of the live connections by :path.
+    pub fn render(&self, conns: &BTreeMap<String, PathStats>) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "webtransport_sessions", "gauge", "Active sessions per path.");
+        for (path, n) in &self.sessions {
+            let _ = writeln!(out, "webtransport_sessions{{path=\"{}\"}} {}", path, n);
+        }
+
+        header(&mut out, "webtransport_streams_opened_total", "counter", "Streams opened.");
+        for (initiator, n) in &self.streams_opened {
+            let _ = writeln!(
+                out,
+                "webtransport_streams_opened_total{{initiator=\"{}\"}} {}",
+                initiator, n
+            );
+        }
+        header(&mut out, "webtransport_streams_reset_total", "counter", "Streams reset or stopped by the peer.");
+        let _ = writeln!(out, "webtransport_streams_reset_total {}", self.streams_reset);
+
+        header(&mut out, "channel_bytes_in_total", "counter", "Bytes received from publishers.");
+        for (channel, n) in &self.bytes_in {
+            let _ = writeln!(out, "channel_bytes_in_total{{channel=\"{}\"}} {}", channel, n);
+        }
+        header(&mut out, "channel_bytes_out_total", "counter", "Bytes sent to viewers.");
+        for (channel, n) in &self.bytes_out {
+            let _ = writeln!(out, "channel_bytes_out_total{{channel=\"{}\"}} {}", channel, n);
+        }
+        header(&mut out, "channel_chunks_published_total", "counter", "Chunks fanned out to viewers.");
+        for ((channel, chunk_type), n) in &self.chunks_published {
+            let _ = writeln!(
+                out,
+                "channel_chunks_published_total{{channel=\"{}\",type=\"{}\"}} {}",
+                channel, chunk_type, n
+            );
+        }
+        header(&mut out, "channel_chunks_rejected_total", "counter", "Malformed or out of order chunks.");
+        for ((channel, reason), n) in &self.chunks_rejected {
+            let _ = writeln!(
+                out,
+                "channel_chunks_rejected_total{{channel=\"{}\",reason=\"{}\"}} {}",
+                channel, reason, n
+            );
+        }
+        header(
+            &mut out,
+            "channel_fanout_latency_seconds",
+            "histogram",
+            "Time from the first byte of a chunk to the end of its fan-out.",
+        );
+        for (channel, h) in &self.fanout_latency {
+            h.render(
+                &mut out,
+                "channel_fanout_latency_seconds",
+                &format!("channel=\"{}\"", channel),
+            );
+        }
+
+        header(&mut out, "channel_layer_switches_total", "counter", "Viewers moved to another simulcast layer.");
+        for ((channel, direction, mode), n) in &self.layer_switches {
+            let _ = writeln!(
+                out,
+                "channel_layer_switches_total{{channel=\"{}\",direction=\"{}\",mode=\"{}\"}} {}",
+                channel, direction, mode, n
+            );
+        }
+
+        header(
+            &mut out,
+            "channel_datagram_fragments_total",
+            "counter",
+            "Media fragments for datagram viewers: data, parity, retransmitted, expired (NACKed too late), simulated_loss or failed.",
+        );
+        for ((channel, kind), n) in &self.datagram_fragments {
+            let _ = writeln!(
+                out,
+                "channel_datagram_fragments_total{{channel=\"{}\",kind=\"{}\"}} {}",
+                channel, kind, n
+            );
+        }
+
+        header(
+            &mut out,
+            "pubsub_messages_total",
+            "counter",
+            "Messages delivered to topic subscribers: sent, failed, too_large for a datagram, or refused when a session publishes on a server topic.",
+        );
+        for ((delivery, result), n) in &self.pubsub_messages {
+            let _ = writeln!(
+                out,
+                "pubsub_messages_total{{delivery=\"{}\",result=\"{}\"}} {}",
+                delivery, result, n
+            );
+        }
+
+        header(
+            &mut out,
+            "presence_events_total",
+            "counter",
+            "Room members joining, leaving or updated.",
+        );
+        for (event, n) in &self.presence_events {
+            let _ = writeln!(out, "presence_events_total{{event=\"{}\"}} {}", event, n);
+        }
+
+        header(&mut out, "udp_datagrams_dropped_total", "counter", "UDP datagrams dropped by the server.");
+        let _ = writeln!(out, "udp_datagrams_dropped_total {}", self.datagrams_dropped);
+
+        // every family's samples right after its own HELP and TYPE lines.
+        let quic: [PathGauge; 9] = [
+            ("quic_connections", "Connections per path.", |s| s.connections as f64),
+            ("quic_rtt_max_seconds", "Largest smoothed RTT per path.", |s| {
+                s.rtt_max.as_secs_f64()
+            }),
+            ("quic_rtt_avg_seconds", "Average smoothed RTT per path.", |s| {
+                s.rtt_sum.as_secs_f64() / s.connections.max(1) as f64
+            }),
+            ("quic_cwnd_avail_min_bytes", "Smallest congestion window left to fill per path.", |s| {
+                s.cwnd_avail_min as f64
+            }),
+            ("quic_cwnd_avail_avg_bytes", "Average congestion window left to fill per path.", |s| {
+                s.cwnd_avail_sum as f64 / s.connections.max(1) as f64
+            }),
+            ("quic_packets_rx", "Packets received by live connections.", |s| s.packets_rx as f64),
+            ("quic_packets_tx", "Packets sent by live connections.", |s| s.packets_tx as f64),
+            ("quic_packets_lost", "Packets declared lost by live connections.", |s| s.lost as f64),
+            ("quic_packets_dropped_rx", "Received packets dropped by live connections.", |s| {
+                s.dropped_rx as f64
+            }),
+        ];
+        for (name, help, value) in quic.iter() {
+            header(&mut out, name, "gauge", help);
+            for (path, s) in conns {
+                let _ = writeln!(out, "{}{{path=\"{}\"}} {}", name, path, value(s));
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_follow_their_family() {
+        let mut metrics = Metrics::new();
+        metrics.session_opened("/video/view");
+        metrics.session_opened("/chat");
+        metrics.published("video", "key", Duration::from_millis(3));
+        let mut conns = BTreeMap::new();
+        for path in ["/chat", "/video/view"] {
+            let mut stats = PathStats::default();
+            stats.add(&Stats::default(), 1200);
+            stats.add(&Stats::default(), 300);
+            conns.insert(path.to_string(), stats);
+        }
+        let text = metrics.render(&conns);
+        let mut family = "";
+        for line in text.lines() {
+            if let Some(name) = line.strip_prefix("# TYPE ") {
+                family = name.split(' ').next().unwrap();
+            } else if !line.starts_with('#') {
+                assert!(line.starts_with(family), "{} after {}", line, family);
+            }
+        }
+        assert!(text.contains("quic_cwnd_avail_min_bytes{path=\"/chat\"} 300\n"));
+        assert!(text.contains("quic_cwnd_avail_avg_bytes{path=\"/chat\"} 750\n"));
+    }
+}
@@ -0,0 +1,401 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Who is in each room, and the chat (`/chat`) of the room.
+//!
+//! A room is named after a channel: the publishers and viewers of `video`, `audio` and `av`
+//! are in the room of their channel, and chat sessions join `?room=` (`video` by default).
+//! Every member has a display name (`?name=`, empty if none), a role (`publisher`, `viewer`
+//! or `chatter`) and the time it joined. Chatters send JSON on uni streams:
+//!
+//! - `{"command": "enter", "name": name}` : set the display name
+//! - `{"command": "comment", "comment": text}` : published on `chat/{room}` as
+//!   `{"command": "comment", "name": name, "comment": text}`
+//! - `{"command": "roster"}` : answered `{"command": "roster", "room": room, "members": [member]}`
+//!   on `presence/{room}`
+//!
+//! and `{"command": "presence", "event": "join|leave|update", "member": member}` is published
+//! on `presence/{room}` whenever the room changes, where a member is
+//! `{"id", "name", "role", "joined"}` with `joined` in milliseconds since the Unix epoch.
+//! Both are topics of `Topics`, so the messages are `topic_len(1) + topic + json`. Chatters
+//! are subscribed to the topics of their room, and any `/pubsub` session can subscribe to
+//! them; media sessions can't since their streams carry chunks, the pages follow their room
+//! with a `/chat` session. The topics are reserved (`TOPIC_PREFIXES`): only `Presence`
+//! publishes on them.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use neqo_common::{qinfo, qwarn};
+use neqo_http3::{Error, Http3OrWebTransportStream, WebTransportRequest};
+use neqo_transport::{server::ActiveConnectionRef, StreamId};
+use serde_json::{json, Value};
+
+use crate::metrics::Metrics;
+use crate::pubsub::{Delivery, Incoming, Topics};
+
+/// Largest chat message accepted.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Longest display name kept, in characters.
+const MAX_NAME_LEN: usize = 64;
+
+/// Room of the chat sessions without `?room=`.
+pub const DEFAULT_ROOM: &str = "video";
+
+/// Prefixes of the topics of the rooms, to reserve in `Topics`.
+pub const TOPIC_PREFIXES: [&str; 2] = ["presence/", "chat/"];
+
+pub struct Member {
+    // id of the session, as in the admin API.
+    pub id: u64,
+    pub name: String,
+    pub role: &'static str,
+    // milliseconds since the Unix epoch.
+    pub joined: u64,
+}
+
+/// Topic of the presence diffs and rosters of a room.
+pub fn presence_topic(room: &str) -> String {
+    format!("presence/{}", room)
+}
+
+/// Topic of the comments of a room.
+pub fn chat_topic(room: &str) -> String {
+    format!("chat/{}", room)
+}
+
+fn display_name(name: &str) -> String {
+    name.chars().take(MAX_NAME_LEN).collect()
+}
+
+impl Member {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "role": self.role,
+            "joined": self.joined,
+        })
+    }
+}
+
+fn diff(event: &str, member: &Member) -> Value {
+    json!({ "command": "presence", "event": event, "member": member.to_json() })
+}
+
+/// The members of a room, by session. Changes return the diff to publish.
+struct Room<K> {
+    members: HashMap<K, Member>,
+}
+
+impl<K: Eq + Hash> Room<K> {
+    fn new() -> Self {
+        Self {
+            members: HashMap::new(),
+        }
+    }
+
+    fn join(&mut self, key: K, member: Member) -> Value {
+        let diff = diff("join", &member);
+        self.members.insert(key, member);
+        diff
+    }
+
+    fn leave(&mut self, key: &K) -> Option<(Member, Value)> {
+        let member = self.members.remove(key)?;
+        let diff = diff("leave", &member);
+        Some((member, diff))
+    }
+
+    /// No diff if the name doesn't change.
+    fn rename(&mut self, key: &K, name: &str) -> Option<Value> {
+        let member = self.members.get_mut(key)?;
+        let name = display_name(name);
+        if member.name == name {
+            return None;
+        }
+        member.name = name;
+        Some(diff("update", member))
+    }
+
+    /// The members, in the order they joined.
+    fn roster(&self) -> Vec<&Member> {
+        let mut members: Vec<_> = self.members.values().collect();
+        members.sort_by_key(|m| (m.joined, m.id));
+        members
+    }
+
+    fn roster_message(&self, room: &str) -> Value {
+        let members: Vec<_> = self.roster().iter().map(|m| m.to_json()).collect();
+        json!({ "command": "roster", "room": room, "members": members })
+    }
+}
+
+pub struct Presence {
+    rooms: BTreeMap<String, Room<ActiveConnectionRef>>,
+    // sessions of the members, to subscribe and answer them.
+    sessions: HashMap<ActiveConnectionRef, WebTransportRequest>,
+    // chat messages, until the end of their stream.
+    incoming: Incoming<(ActiveConnectionRef, StreamId)>,
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+impl Presence {
+    pub fn new(metrics: Rc<RefCell<Metrics>>) -> Self {
+        Self {
+            rooms: BTreeMap::new(),
+            sessions: HashMap::new(),
+            incoming: Incoming::new(MAX_MESSAGE_SIZE),
+            metrics,
+        }
+    }
+
+    /// Add a session to a room and tell the room.
+    pub fn join(
+        &mut self,
+        topics: &mut Topics,
+        room: &str,
+        id: u64,
+        session: WebTransportRequest,
+        role: &'static str,
+        name: &str,
+    ) {
+        let joined = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let member = Member {
+            id,
+            name: display_name(name),
+            role,
+            joined,
+        };
+        qinfo!("{} {} joined room {}", role, id, room);
+        for topic in &[presence_topic(room), chat_topic(room)] {
+            let delivery = topics.declare(topic, Delivery::Stream);
+            if delivery != Delivery::Stream {
+                qwarn!("{} stays delivered by {}", topic, delivery);
+            }
+            if role == "chatter" {
+                if let Err(err) = topics.subscribe(&session, topic, Some(Delivery::Stream)) {
+                    qwarn!("chatter {} not subscribed: {}", id, err);
+                }
+            }
+        }
+        let diff = self
+            .rooms
+            .entry(room.to_string())
+            .or_insert_with(Room::new)
+            .join(session.conn.clone(), member);
+        self.sessions.insert(session.conn.clone(), session);
+        self.metrics.borrow_mut().presence_event("join");
+        topics.publish(&presence_topic(room), diff.to_string().as_bytes());
+    }
+
+    /// Remove a session from its room and tell the room.
+    pub fn leave(&mut self, topics: &mut Topics, conn: &ActiveConnectionRef) {
+        self.incoming.retain(|(c, _)| c != conn);
+        self.sessions.remove(conn);
+        let room = match self.room_of(conn) {
+            Some(room) => room,
+            None => return,
+        };
+        let (member, diff) = match self.rooms.get_mut(&room).and_then(|r| r.leave(conn)) {
+            Some(left) => left,
+            None => return,
+        };
+        qinfo!("{} {} left room {}", member.role, member.id, room);
+        self.metrics.borrow_mut().presence_event("leave");
+        for topic in &[presence_topic(&room), chat_topic(&room)] {
+            topics.unsubscribe(conn, topic);
+        }
+        topics.publish(&presence_topic(&room), diff.to_string().as_bytes());
+        if self
+            .rooms
+            .get(&room)
+            .map_or(false, |r| r.members.is_empty())
+        {
+            self.rooms.remove(&room);
+            topics.undeclare(&presence_topic(&room));
+            topics.undeclare(&chat_topic(&room));
+        }
+    }
+
+    /// The members of a room, in the order they joined.
+    pub fn roster(&self, room: &str) -> Vec<&Member> {
+        self.rooms.get(room).map_or_else(Vec::new, Room::roster)
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = &str> {
+        self.rooms.keys().map(String::as_str)
+    }
+
+    /// A chat message on a uni stream.
+    pub fn receive(
+        &mut self,
+        topics: &mut Topics,
+        mut stream: Http3OrWebTransportStream,
+        data: Vec<u8>,
+        fin: bool,
+    ) {
+        let key = (stream.conn.clone(), stream.stream_id());
+        let message = match self.incoming.receive(key, data, fin) {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(len) => {
+                qwarn!(
+                    "chat message of {} bytes, larger than {}",
+                    len,
+                    MAX_MESSAGE_SIZE
+                );
+                if !fin {
+                    let _ = stream.stream_stop_sending(Error::HttpRequestCancelled.code());
+                }
+                return;
+            }
+        };
+        match serde_json::from_slice(&message) {
+            Ok(message) => self.command(topics, &stream.conn, &message),
+            Err(err) => qwarn!("invalid chat message: {}", err),
+        }
+    }
+
+    fn command(&mut self, topics: &mut Topics, conn: &ActiveConnectionRef, message: &Value) {
+        let room = match self.room_of(conn) {
+            Some(room) => room,
+            None => return,
+        };
+        match message["command"].as_str() {
+            Some("enter") => {
+                let name = message["name"].as_str().unwrap_or_default();
+                self.rename(topics, &room, conn, name);
+            }
+            Some("comment") => {
+                let name = self.rooms[&room].members[conn].name.clone();
+                let comment = json!({
+                    "command": "comment",
+                    "name": name,
+                    "comment": message["comment"].as_str().unwrap_or_default(),
+                });
+                topics.publish(&chat_topic(&room), comment.to_string().as_bytes());
+            }
+            Some("roster") => {
+                let roster = self.rooms[&room].roster_message(&room);
+                if let Some(session) = self.sessions.get_mut(conn) {
+                    let topic = presence_topic(&room);
+                    topics.send(session, &topic, roster.to_string().as_bytes());
+                }
+            }
+            command => qwarn!("unknown chat command {:?}", command),
+        }
+    }
+
+    fn rename(&mut self, topics: &mut Topics, room: &str, conn: &ActiveConnectionRef, name: &str) {
+        let diff = match self.rooms.get_mut(room).and_then(|r| r.rename(conn, name)) {
+            Some(diff) => diff,
+            None => return,
+        };
+        self.metrics.borrow_mut().presence_event("update");
+        topics.publish(&presence_topic(room), diff.to_string().as_bytes());
+    }
+
+    fn room_of(&self, conn: &ActiveConnectionRef) -> Option<String> {
+        self.rooms
+            .iter()
+            .find(|(_, r)| r.members.contains_key(conn))
+            .map(|(room, _)| room.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics() {
+        assert_eq!(presence_topic(DEFAULT_ROOM), "presence/video");
+        assert_eq!(chat_topic("av"), "chat/av");
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(display_name("alice"), "alice");
+        let long = "é".repeat(MAX_NAME_LEN + 1);
+        assert_eq!(display_name(&long).chars().count(), MAX_NAME_LEN);
+    }
+
+    fn member(id: u64, name: &str, joined: u64) -> Member {
+        Member {
+            id,
+            name: name.to_string(),
+            role: "chatter",
+            joined,
+        }
+    }
+
+    #[test]
+    fn join_and_leave_diffs() {
+        let mut room = Room::new();
+        assert_eq!(
+            room.join(1, member(7, "alice", 1000)),
+            json!({
+                "command": "presence",
+                "event": "join",
+                "member": { "id": 7, "name": "alice", "role": "chatter", "joined": 1000 },
+            })
+        );
+        let (left, diff) = room.leave(&1).unwrap();
+        assert_eq!(left.id, 7);
+        assert_eq!(diff["event"], "leave");
+        assert_eq!(diff["member"]["name"], "alice");
+        assert!(room.leave(&1).is_none());
+        assert!(room.members.is_empty());
+    }
+
+    #[test]
+    fn rename_diffs() {
+        let mut room = Room::new();
+        room.join(1, member(7, "alice", 1000));
+        assert!(room.rename(&1, "alice").is_none());
+        assert!(room.rename(&2, "bob").is_none());
+        let diff = room.rename(&1, "carol").unwrap();
+        assert_eq!(diff["event"], "update");
+        assert_eq!(diff["member"]["id"], 7);
+        assert_eq!(diff["member"]["name"], "carol");
+        // names are cut like at join.
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        let diff = room.rename(&1, &long).unwrap();
+        assert_eq!(diff["member"]["name"], "x".repeat(MAX_NAME_LEN));
+        assert!(room.rename(&1, &long).is_none());
+    }
+
+    #[test]
+    fn roster_order() {
+        let mut room = Room::new();
+        room.join(1, member(9, "carol", 3000));
+        room.join(2, member(8, "bob", 1000));
+        // joined in the same millisecond, by id.
+        room.join(3, member(5, "alice", 1000));
+        let ids: Vec<_> = room.roster().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![5, 8, 9]);
+        let roster = room.roster_message("video");
+        assert_eq!(roster["command"], "roster");
+        assert_eq!(roster["room"], "video");
+        let names: Vec<_> = roster["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["alice", "bob", "carol"]);
+        room.leave(&2);
+        let ids: Vec<_> = room.roster().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![5, 9]);
+    }
+}
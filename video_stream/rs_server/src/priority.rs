@@ -0,0 +1,191 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Send priorities of the streams carrying media to viewers.
+//!
+//! On a congested connection audio goes first, then decoder configs and keyframes,
+//! then delta frames. Within the same class the stream of the newest group of pictures
+//! goes first (as in Warp), so a viewer that fell behind catches up with the live edge
+//! instead of draining old frames. Each new stream gets an HTTP/3 urgency (RFC 9218)
+//! and the matching neqo transmission priority and sendorder.
+
+use neqo_common::qdebug;
+use neqo_http3::{Http3OrWebTransportStream, Priority};
+use neqo_transport::{RetransmissionPriority, SendOrder, TransmissionPriority};
+
+use crate::chunk::Chunk;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Media {
+    Video,
+    Audio,
+}
+
+impl Media {
+    /// Media carried by a channel, from its name.
+    pub fn of_channel(name: &str) -> Self {
+        match name {
+            "audio" | "av-audio" => Self::Audio,
+            _ => Self::Video,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamPriority {
+    // HTTP/3 urgency, 0 is the most urgent.
+    urgency: u8,
+    transmission: TransmissionPriority,
+    retransmission: RetransmissionPriority,
+    // streams with a higher sendorder are sent first.
+    sendorder: SendOrder,
+}
+
+impl StreamPriority {
+    /// Priority of a chunk of the group of pictures starting at `group` (a timestamp).
+    pub fn of_chunk(media: Media, chunk: &Chunk, group: i64) -> Self {
+        if media == Media::Audio {
+            return Self::audio(chunk.header.timestamp);
+        }
+        if chunk.is_key() || chunk.is_config() {
+            // a config goes with the keyframe after it, even when it was encoded earlier.
+            Self {
+                urgency: 2,
+                transmission: TransmissionPriority::Normal,
+                retransmission: RetransmissionPriority::Higher,
+                sendorder: group.max(chunk.header.timestamp),
+            }
+        } else {
+            Self {
+                urgency: 4,
+                transmission: TransmissionPriority::Low,
+                retransmission: RetransmissionPriority::Higher,
+                sendorder: group,
+            }
+        }
+    }
+
+    /// Priority of audio and of the clock chunks that align it, `timestamp` being the
+    /// media time carried.
+    pub fn audio(timestamp: i64) -> Self {
+        Self {
+            urgency: 1,
+            transmission: TransmissionPriority::High,
+            retransmission: RetransmissionPriority::MuchHigher,
+            sendorder: timestamp,
+        }
+    }
+
+    /// Priority of an fMP4 fragment, which holds a whole group of pictures.
+    pub fn of_fragment(media: Media, timestamp: i64) -> Self {
+        match media {
+            Media::Audio => Self::audio(timestamp),
+            Media::Video => Self {
+                urgency: 2,
+                transmission: TransmissionPriority::Normal,
+                retransmission: RetransmissionPriority::Higher,
+                sendorder: timestamp,
+            },
+        }
+    }
+
+    /// Set the priority of a new stream, before anything is written to it.
+    /// The stream is sent anyway when it can't be set.
+    pub fn apply(&self, stream: &mut Http3OrWebTransportStream) {
+        if let Err(err) = stream.set_priority(Priority::new(self.urgency, false)) {
+            qdebug!("set priority error. {:?}", err);
+        }
+        let stream_id = stream.stream_id();
+        let mut conn = stream.conn.borrow_mut();
+        if let Err(err) = conn.stream_priority(stream_id, self.transmission, self.retransmission) {
+            qdebug!("stream priority error. {:?}", err);
+        }
+        if let Err(err) = conn.stream_sendorder(stream_id, Some(self.sendorder)) {
+            qdebug!("stream sendorder error. {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+
+    fn chunk(chunk_type: ChunkType, timestamp: i64) -> Chunk {
+        let header = ChunkHeader {
+            chunk_type,
+            timestamp,
+            duration: 0,
+        };
+        Chunk::new(header, &[0])
+    }
+
+    fn transmission_rank(p: TransmissionPriority) -> u8 {
+        match p {
+            TransmissionPriority::Critical => 0,
+            TransmissionPriority::Important => 1,
+            TransmissionPriority::High => 2,
+            TransmissionPriority::Normal => 3,
+            TransmissionPriority::Low => 4,
+        }
+    }
+
+    /// Sorts the streams sent first before the others.
+    fn rank(p: &StreamPriority) -> (u8, u8, std::cmp::Reverse<SendOrder>) {
+        (
+            p.urgency,
+            transmission_rank(p.transmission),
+            std::cmp::Reverse(p.sendorder),
+        )
+    }
+
+    #[test]
+    fn media_of_channel() {
+        assert_eq!(Media::of_channel("audio"), Media::Audio);
+        assert_eq!(Media::of_channel("av-audio"), Media::Audio);
+        assert_eq!(Media::of_channel("video"), Media::Video);
+        assert_eq!(Media::of_channel("av-video"), Media::Video);
+    }
+
+    #[test]
+    fn audio_then_keyframes_then_deltas() {
+        let audio = StreamPriority::of_chunk(Media::Audio, &chunk(ChunkType::Key, 0), 0);
+        let key = StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Key, 1_000), 1_000);
+        let config = StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Config, 900), 0);
+        let delta = StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Delta, 1_033), 1_000);
+        assert_eq!(audio, StreamPriority::audio(0));
+        assert!(rank(&audio) < rank(&key));
+        assert!(rank(&key) < rank(&delta));
+        // an older audio chunk still goes before a new keyframe.
+        assert!(rank(&StreamPriority::audio(-5_000)) < rank(&key));
+        // a config goes with the keyframe of the next group, not its own timestamp.
+        assert_eq!(config.sendorder, 900);
+        let config = StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Config, 900), 2_000);
+        assert_eq!(config.sendorder, 2_000);
+        assert_eq!(rank(&config).0, rank(&key).0);
+    }
+
+    #[test]
+    fn newer_group_first() {
+        let delta = |group| {
+            StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Delta, group + 500), group)
+        };
+        let old = delta(1_000);
+        let new = delta(2_000);
+        assert!(rank(&new) < rank(&old));
+        // deltas of the same group are in no particular order between them.
+        assert_eq!(
+            StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Delta, 1_900), 1_000),
+            old
+        );
+        let key = StreamPriority::of_chunk(Media::Video, &chunk(ChunkType::Key, 2_000), 2_000);
+        assert!(rank(&StreamPriority::of_fragment(Media::Video, 3_000)) < rank(&key));
+        assert_eq!(
+            StreamPriority::of_fragment(Media::Audio, 3_000),
+            StreamPriority::audio(3_000)
+        );
+    }
+}
@@ -0,0 +1,1093 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use neqo_common::{qdebug, qerror, qinfo, qwarn};
+use neqo_crypto::random;
+use neqo_http3::{Error, Http3OrWebTransportStream, WebTransportRequest};
+use neqo_transport::{server::ActiveConnectionRef, StreamId, StreamType};
+
+use serde_json::{json, Value};
+
+use crate::abr::{Decision, Estimator, Sample, SAMPLE_INTERVAL};
+use crate::base64;
+use crate::chunk::{Chunk, ChunkError, DecoderConfig};
+use crate::datagram::{self, DatagramConfig, MAX_DATAGRAM_SIZE};
+use crate::fmp4::Fmp4Muxer;
+use crate::gop::Gop;
+use crate::hls::Hls;
+use crate::metrics::Metrics;
+use crate::priority::{Media, StreamPriority};
+use crate::qlog_events;
+use crate::recorder::{RecordConfig, Recorder};
+use crate::rtp_egress::RtpEgress;
+
+/// Layer of a publisher without simulcast, and of viewers that don't ask for one.
+pub const DEFAULT_LAYER: u8 = 0;
+
+/// Longest line of a layer control stream.
+const MAX_CONTROL_LINE: usize = 1024;
+
+/// Where the chunks of a channel come from.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    // a publishing session of this server.
+    Session(ActiveConnectionRef),
+    // the upstream server of `--relay`.
+    Upstream,
+    // RTP packets received on `--rtp-video` / `--rtp-audio`.
+    Rtp,
+}
+
+impl Source {
+    fn conn(&self) -> Option<&ActiveConnectionRef> {
+        match self {
+            Self::Session(conn) => Some(conn),
+            Self::Upstream | Self::Rtp => None,
+        }
+    }
+}
+
+/// One encoding of the channel. Simulcast publishers send several of them.
+#[derive(Default)]
+struct Layer {
+    // the last decoder config, sent to new members before anything else.
+    config: Option<(Chunk, DecoderConfig)>,
+
+    // the last keyframe and the chunks after it, sent to new members first.
+    gop: Gop,
+
+    // timestamp of the last keyframe, orders the streams of its group.
+    group: i64,
+
+    // bytes published since the last sample, and the smoothed bytes per second.
+    bytes: usize,
+    bitrate: f64,
+}
+
+impl Layer {
+    fn update_bitrate(&mut self, elapsed: Duration) {
+        let rate = mem::take(&mut self.bytes) as f64 / elapsed.as_secs_f64();
+        self.bitrate = if self.bitrate == 0.0 {
+            rate
+        } else {
+            0.75 * self.bitrate + 0.25 * rate
+        };
+    }
+
+    fn config_chunk(&self) -> Option<&Chunk> {
+        self.config.as_ref().map(|(c, _)| c)
+    }
+}
+
+/// The layer a member receives.
+struct MemberLayer {
+    current: u8,
+    // layer to switch to at its next keyframe.
+    pending: Option<u8>,
+    // bidirectional stream of `layer N` requests, answered with the switches.
+    control: Option<Http3OrWebTransportStream>,
+    control_buf: Vec<u8>,
+    // set when the server picks the layer from the connection.
+    auto: Option<Estimator>,
+    // bytes the streams of the member refused since the last sample.
+    queued: usize,
+}
+
+impl MemberLayer {
+    fn notify(&mut self, line: &str) {
+        if let Some(control) = &mut self.control {
+            let _ = control.send_data(format!("{}\n", line).as_bytes());
+        }
+    }
+}
+
+/// A chunk sent to the datagram members, kept until its deadline for retransmissions.
+struct SentFrame {
+    frame: u32,
+    chunk: Chunk,
+    sent_at: Instant,
+}
+
+pub struct Publisher {
+    // channel name used for metrics.
+    pub name: &'static str,
+
+    // track id written before every chunk sent to members of a combined channel.
+    track: Option<u8>,
+
+    // decides the priority of the streams sent to members.
+    media: Media,
+
+    // members identified by connection_id.
+    pub members: HashMap<ActiveConnectionRef, WebTransportRequest>,
+
+    // the layer of each member.
+    member_layers: HashMap<ActiveConnectionRef, MemberLayer>,
+
+    // members receiving fMP4 with Warp framing.
+    pub warp_members: HashMap<ActiveConnectionRef, WebTransportRequest>,
+
+    // members receiving fragmented chunks in datagrams.
+    pub datagram_members: HashMap<ActiveConnectionRef, WebTransportRequest>,
+    datagram: DatagramConfig,
+
+    // id of the next frame sent in datagrams, and the frames that can still be sent again.
+    next_frame: u32,
+    sent_frames: VecDeque<SentFrame>,
+
+    // buffer data with the source and stream_id, stream ids of two sources can be the same.
+    buf: HashMap<(Source, StreamId), Vec<u8>>,
+
+    // when the first byte of each buffered stream arrived.
+    received_at: HashMap<(Source, StreamId), Instant>,
+
+    // streams whose chunk was rejected before its end.
+    rejected: HashSet<(Source, StreamId)>,
+
+    // publishing sessions identified by connection_id.
+    pub publishers: HashSet<Source>,
+
+    // publishers that write a layer id(1byte) before every chunk.
+    simulcast: HashSet<Source>,
+
+    // layer of each stream of a simulcast publisher, known from its first byte.
+    stream_layer: HashMap<(Source, StreamId), u8>,
+
+    // timestamp of the last chunk of each publisher and layer.
+    last_timestamp: HashMap<(Source, u8), i64>,
+
+    // config and keyframe cache of each layer.
+    layers: BTreeMap<u8, Layer>,
+
+    // fMP4 of the default layer.
+    muxer: Fmp4Muxer,
+
+    // when the connections of the members were last sampled for the layer selection.
+    sampled_at: Option<Instant>,
+
+    max_chunk_size: usize,
+
+    // writes every accepted chunk of the default layer to disk when `--record-dir` is set.
+    pub recorder: Option<Recorder>,
+
+    // sends every accepted chunk of the default layer over RTP when `--rtp-out-*` is set.
+    pub egress: Option<RtpEgress>,
+
+    // LL-HLS of the default layer.
+    pub hls: Hls,
+
+    metrics: Rc<RefCell<Metrics>>,
+}
+impl Publisher {
+    pub fn new(
+        name: &'static str,
+        max_chunk_size: usize,
+        record: Option<RecordConfig>,
+        metrics: Rc<RefCell<Metrics>>,
+    ) -> Self {
+        Self {
+            name,
+            track: None,
+            media: Media::of_channel(name),
+            members: HashMap::new(),
+            member_layers: HashMap::new(),
+            warp_members: HashMap::new(),
+            datagram_members: HashMap::new(),
+            datagram: DatagramConfig::default(),
+            next_frame: 0,
+            sent_frames: VecDeque::new(),
+            buf: HashMap::new(),
+            received_at: HashMap::new(),
+            rejected: HashSet::new(),
+            publishers: HashSet::new(),
+            simulcast: HashSet::new(),
+            stream_layer: HashMap::new(),
+            last_timestamp: HashMap::new(),
+            layers: BTreeMap::new(),
+            muxer: Fmp4Muxer::default(),
+            sampled_at: None,
+            max_chunk_size,
+            recorder: record.map(|config| Recorder::new(config, name)),
+            egress: None,
+            hls: Hls::new(name),
+            metrics,
+        }
+    }
+    /// Tag the chunks sent to members with `track`, for a combined channel.
+    pub fn with_track(mut self, track: u8) -> Self {
+        self.track = Some(track);
+        self
+    }
+    /// FEC, retransmission deadline and simulated loss of the datagram members.
+    pub fn with_datagram(mut self, config: DatagramConfig) -> Self {
+        self.datagram = config;
+        self
+    }
+    /// Subscribe a viewer to `layer`. When `None` the viewer starts on the default layer
+    /// and the server moves it between the layers as its connection allows.
+    pub fn subscribe(&mut self, handler: WebTransportRequest, layer: Option<u8>) {
+        let mut handler = handler;
+        let auto = layer.map_or(Some(Estimator::default()), |_| None);
+        let layer = layer.unwrap_or(DEFAULT_LAYER);
+        let cached = self
+            .layers
+            .get(&layer)
+            .map(|l| (l.group, l.config_chunk().into_iter().chain(l.gop.chunks())));
+        if let Some((group, chunks)) = cached {
+            for chunk in chunks {
+                Self::send(
+                    &mut handler,
+                    chunk,
+                    self.track,
+                    StreamPriority::of_chunk(self.media, chunk, group),
+                    self.name,
+                    &mut self.metrics.borrow_mut(),
+                );
+            }
+        }
+        self.member_layers.insert(
+            handler.conn.clone(),
+            MemberLayer {
+                current: layer,
+                pending: None,
+                control: None,
+                control_buf: Vec::new(),
+                auto,
+                queued: 0,
+            },
+        );
+        self.members.insert(handler.conn.clone(), handler);
+    }
+    /// Move a member to `layer` at the next keyframe of that layer.
+    pub fn switch_layer(&mut self, conn: &ActiveConnectionRef, layer: u8) {
+        if let Some(ml) = self.member_layers.get_mut(conn) {
+            if ml.current == layer {
+                ml.pending = None;
+            } else {
+                qdebug!("{} switch to layer {} at the next keyframe", self.name, layer);
+                ml.pending = Some(layer);
+            }
+        }
+    }
+    /// Layers received from the publishers so far.
+    pub fn layer_ids(&self) -> Vec<u8> {
+        self.layers.keys().copied().collect()
+    }
+    /// Data of the control stream of a member: one `layer N` or `layer auto` request per line.
+    pub fn control(&mut self, stream: Http3OrWebTransportStream, data: &[u8]) {
+        let conn = stream.conn.clone();
+        let ml = match self.member_layers.get_mut(&conn) {
+            Some(ml) => ml,
+            None => return,
+        };
+        ml.control = Some(stream);
+        ml.control_buf.extend_from_slice(data);
+        let mut requests = Vec::new();
+        while let Some(eol) = ml.control_buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = ml.control_buf.drain(..=eol).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("layer"), Some("auto")) => requests.push(None),
+                (Some("layer"), Some(layer)) if layer.parse::<u8>().is_ok() => {
+                    requests.push(layer.parse().ok());
+                }
+                _ => {
+                    qwarn!("unknown control command {:?}", line);
+                    ml.notify(&format!("error {}", line));
+                }
+            }
+        }
+        if ml.control_buf.len() > MAX_CONTROL_LINE {
+            qwarn!("layer control line longer than {} bytes", MAX_CONTROL_LINE);
+            ml.control_buf.clear();
+            if let Some(mut control) = ml.control.take() {
+                let _ = control.stream_stop_sending(Error::HttpRequestCancelled.code());
+                let _ = control.stream_reset_send(Error::HttpRequestCancelled.code());
+            }
+        }
+        for layer in requests {
+            let layer = match layer {
+                Some(layer) => layer,
+                None => {
+                    if let Some(ml) = self.member_layers.get_mut(&conn) {
+                        ml.auto.get_or_insert_with(Estimator::default);
+                        ml.notify(&format!("auto {}", ml.current));
+                    }
+                    continue;
+                }
+            };
+            self.switch_layer(&conn, layer);
+            if let Some(ml) = self.member_layers.get_mut(&conn) {
+                // the viewer chose, stop picking for it.
+                ml.auto = None;
+                let reply = match ml.pending {
+                    Some(_) => format!("switching {}", layer),
+                    None => format!("layer {}", ml.current),
+                };
+                ml.notify(&reply);
+            }
+        }
+    }
+    /// Sample the connections of the members on automatic layer selection and move them
+    /// a layer up or down. Returns when to call again, `None` while nobody needs it.
+    pub fn adapt(&mut self, now: Instant) -> Option<Instant> {
+        if !self.member_layers.values().any(|ml| ml.auto.is_some()) {
+            self.sampled_at = None;
+            return None;
+        }
+        let due = self.sampled_at.map_or(now, |t| t + SAMPLE_INTERVAL);
+        if now < due {
+            return Some(due);
+        }
+        let elapsed = self.sampled_at.map_or(SAMPLE_INTERVAL, |t| now - t);
+        self.sampled_at = Some(now);
+        for layer in self.layers.values_mut() {
+            layer.update_bitrate(elapsed);
+        }
+
+        let mut switches = Vec::new();
+        for (conn, ml) in self.member_layers.iter_mut() {
+            let estimator = match &mut ml.auto {
+                Some(estimator) => estimator,
+                None => continue,
+            };
+            let sample = {
+                let c = conn.borrow();
+                Sample {
+                    cwnd_avail: c.cwnd_avail(),
+                    rtt: c.stats().rtt,
+                    queued: mem::take(&mut ml.queued),
+                }
+            };
+            let target = ml.pending.unwrap_or(ml.current);
+            // a lower layer id is a better quality.
+            let better = self.layers.range(..target).next_back().map(|(id, _)| *id);
+            let worse = self
+                .layers
+                .range(target.saturating_add(1)..)
+                .next()
+                .map(|(id, _)| *id);
+            let bitrate = |id: u8| self.layers.get(&id).map_or(0.0, |l| l.bitrate);
+            let extra = better.map(|id| (bitrate(id) - bitrate(target)).max(0.0));
+            let next = match estimator.sample(&sample, extra) {
+                Decision::Up => better,
+                Decision::Down => worse,
+                Decision::Stay => None,
+            };
+            if let Some(layer) = next {
+                estimator.reset();
+                qinfo!(
+                    "{} layer {} -> {} (rtt {:?}, cwnd avail {}, queued {})",
+                    self.name,
+                    target,
+                    layer,
+                    sample.rtt,
+                    sample.cwnd_avail,
+                    sample.queued
+                );
+                switches.push((conn.clone(), layer));
+            }
+        }
+        for (conn, layer) in switches {
+            self.switch_layer(&conn, layer);
+            if let Some(ml) = self.member_layers.get_mut(&conn) {
+                if ml.pending.is_some() {
+                    ml.notify(&format!("switching {} auto", layer));
+                }
+            }
+        }
+        Some(now + SAMPLE_INTERVAL)
+    }
+    /// Subscribe a Warp style player, which gets the init segment and then
+    /// a fragment for every group of pictures.
+    pub fn subscribe_warp(&mut self, handler: WebTransportRequest) {
+        let mut handler = handler;
+        if let Some(init) = &self.muxer.init {
+            let message = format!("{{\"init\":{{\"id\":{}}}}}", self.muxer.init_id);
+            Self::send_warp(
+                &mut handler,
+                &message,
+                init,
+                Self::init_priority(self.media),
+                self.name,
+                &mut self.metrics.borrow_mut(),
+            );
+        }
+        self.warp_members.insert(handler.conn.clone(), handler);
+    }
+    /// Subscribe a viewer that gets the default layer in datagrams, starting from the
+    /// cached config and group of pictures.
+    pub fn subscribe_datagram(&mut self, mut session: WebTransportRequest) {
+        let chunks: Vec<Chunk> = match self.layers.get(&DEFAULT_LAYER) {
+            Some(layer) => layer
+                .config_chunk()
+                .into_iter()
+                .chain(layer.gop.chunks())
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        for chunk in chunks {
+            let frame = self.sent_frame(&chunk);
+            let mut metrics = self.metrics.borrow_mut();
+            Self::send_datagram(&mut session, frame, &chunk, &self.datagram, self.name, &mut metrics);
+        }
+        self.datagram_members.insert(session.conn.clone(), session);
+    }
+    /// Send again the fragments a datagram member reports missing in a NACK datagram,
+    /// when they can still arrive before the deadline of their frame.
+    pub fn nack(&mut self, conn: &ActiveConnectionRef, datagram: &[u8]) {
+        let session = match self.datagram_members.get_mut(conn) {
+            Some(session) => session,
+            None => return,
+        };
+        let missing = match datagram::parse_nack(datagram) {
+            Some(missing) => missing,
+            None => {
+                qwarn!("invalid NACK of {} bytes", datagram.len());
+                return;
+            }
+        };
+        // a fragment sent now arrives after about half a round trip.
+        let arrival = Instant::now() + conn.borrow().stats().rtt / 2;
+        let deadline = self.datagram.deadline;
+        let mut metrics = self.metrics.borrow_mut();
+        for (frame, indexes) in missing {
+            let sent = self
+                .sent_frames
+                .iter()
+                .find(|s| s.frame == frame && s.sent_at + deadline > arrival);
+            let sent = match sent {
+                Some(sent) => sent,
+                None => {
+                    for _ in &indexes {
+                        metrics.datagram_fragment(self.name, "expired");
+                    }
+                    continue;
+                }
+            };
+            let max_size = match Self::max_datagram_size(session) {
+                Some(size) => size,
+                None => continue,
+            };
+            let fragments =
+                datagram::fragment(frame, sent.chunk.as_bytes(), max_size, self.datagram.group());
+            for fragment in &fragments {
+                let asked = datagram::FragmentHeader::parse(fragment)
+                    .map_or(false, |(header, _)| indexes.contains(&header.index));
+                if asked {
+                    Self::send_fragment(session, fragment, "retransmitted", &self.datagram, self.name, &mut metrics);
+                }
+            }
+        }
+    }
+    pub fn leave(&mut self, conn: &ActiveConnectionRef) {
+        self.members.remove(conn);
+        self.member_layers.remove(conn);
+        self.warp_members.remove(conn);
+        self.datagram_members.remove(conn);
+    }
+    pub fn start(&mut self, conn: &ActiveConnectionRef) {
+        self.start_from(Source::Session(conn.clone()));
+    }
+    pub fn start_from(&mut self, source: Source) {
+        self.publishers.insert(source);
+    }
+    /// Whether `source` may publish: RTP and sessions don't publish on a channel together,
+    /// whichever started first keeps it.
+    pub fn accepts(&self, source: &Source) -> bool {
+        let rtp = |s: &Source| *s == Source::Rtp;
+        self.publishers
+            .iter()
+            .all(|s| s == source || rtp(s) == rtp(source))
+    }
+    /// Start a publisher that sends several layers, each chunk prefixed with its layer id.
+    pub fn start_simulcast(&mut self, conn: &ActiveConnectionRef) {
+        self.start(conn);
+        self.simulcast.insert(Source::Session(conn.clone()));
+    }
+    pub fn end(&mut self, conn: &ActiveConnectionRef) {
+        self.end_from(&Source::Session(conn.clone()));
+    }
+    pub fn end_from(&mut self, source: &Source) {
+        self.publishers.remove(source);
+        self.simulcast.remove(source);
+        self.last_timestamp.retain(|(s, _), _| s != source);
+        self.buf.retain(|(s, _), _| s != source);
+        self.received_at.retain(|(s, _), _| s != source);
+        self.rejected.retain(|(s, _)| s != source);
+        self.stream_layer.retain(|(s, _), _| s != source);
+        if self.publishers.is_empty() {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.finish();
+            }
+        }
+    }
+    /// Connections of every publisher and viewer of this channel.
+    pub fn conns(&self) -> Vec<ActiveConnectionRef> {
+        self.publishers
+            .iter()
+            .filter_map(Source::conn)
+            .chain(self.members.keys())
+            .chain(self.warp_members.keys())
+            .chain(self.datagram_members.keys())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the timestamp of the media chunk completed by `data`, if any.
+    /// A stream whose chunk gets too large is stopped.
+    pub fn publish(
+        &mut self,
+        stream: &mut Http3OrWebTransportStream,
+        data: Vec<u8>,
+        fin: bool,
+    ) -> Option<i64> {
+        let key = (Source::Session(stream.conn.clone()), stream.stream_id());
+        let rejected = self.rejected.contains(&key);
+        let timestamp = self.publish_from(&key.0, key.1, data, fin);
+        if !rejected && self.rejected.contains(&key) {
+            // the rest of the chunk would be dropped anyway.
+            let _ = stream.stream_stop_sending(Error::HttpRequestCancelled.code());
+        }
+        timestamp
+    }
+
+    pub fn publish_from(
+        &mut self,
+        source: &Source,
+        stream_id: StreamId,
+        data: Vec<u8>,
+        fin: bool,
+    ) -> Option<i64> {
+        self.metrics.borrow_mut().bytes_in(self.name, data.len());
+        let key = (source.clone(), stream_id);
+        let mut data = data;
+        let layer = if self.simulcast.contains(source) {
+            match self.stream_layer.get(&key) {
+                Some(layer) => *layer,
+                None if data.is_empty() => return None,
+                None => {
+                    let layer = data.remove(0);
+                    self.stream_layer.insert(key.clone(), layer);
+                    layer
+                }
+            }
+        } else {
+            DEFAULT_LAYER
+        };
+        if fin {
+            self.stream_layer.remove(&key);
+        }
+        if self.rejected.contains(&key) {
+            if fin {
+                self.rejected.remove(&key);
+            }
+            return None;
+        }
+
+        // add buffer
+        self.received_at
+            .entry(key.clone())
+            .or_insert_with(Instant::now);
+        let buf = self.buf.entry(key.clone()).or_default();
+        buf.extend(data);
+        if buf.len() > self.max_chunk_size {
+            let err = ChunkError::TooLarge(buf.len());
+            self.buf.remove(&key);
+            self.received_at.remove(&key);
+            if !fin {
+                self.rejected.insert(key);
+            }
+            self.reject(source, &err);
+            return None;
+        }
+        if !fin {
+            return None;
+        }
+
+        let data = self.buf.remove(&key).unwrap_or_default();
+        let received_at = self.received_at.remove(&key);
+        self.accept(source, Some(stream_id), layer, data, received_at)
+    }
+
+    /// Publish a whole chunk that didn't come on a stream.
+    pub fn publish_chunk(&mut self, source: &Source, chunk: &Chunk) -> Option<i64> {
+        self.metrics.borrow_mut().bytes_in(self.name, chunk.len());
+        self.accept(
+            source,
+            None,
+            DEFAULT_LAYER,
+            chunk.as_bytes().to_vec(),
+            Some(Instant::now()),
+        )
+    }
+
+    /// Check a complete chunk and send it to the members.
+    fn accept(
+        &mut self,
+        source: &Source,
+        stream_id: Option<StreamId>,
+        layer: u8,
+        data: Vec<u8>,
+        received_at: Option<Instant>,
+    ) -> Option<i64> {
+        let chunk = match Chunk::parse(data, self.max_chunk_size).and_then(|chunk| {
+            if chunk.is_config() {
+                Ok(chunk)
+            } else {
+                self.check_timestamp(source, layer, chunk)
+            }
+        }) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                self.reject(source, &err);
+                return None;
+            }
+        };
+        if let (Some(conn), Some(stream_id)) = (source.conn(), stream_id) {
+            qlog_events::chunk_published(conn, stream_id, &chunk.header, chunk.len());
+        }
+        if chunk.is_config() {
+            self.configure(source, layer, chunk);
+            return None;
+        }
+        let timestamp = chunk.header.timestamp;
+        if chunk.is_key() {
+            self.layers.entry(layer).or_default().group = timestamp;
+        }
+        self.fan_out(layer, &chunk);
+        if layer == DEFAULT_LAYER {
+            if let Some((timestamp, fragment)) = self.muxer.push(&chunk) {
+                let message = format!(
+                    "{{\"segment\":{{\"init\":{},\"timestamp\":{}}}}}",
+                    self.muxer.init_id, timestamp
+                );
+                let priority = StreamPriority::of_fragment(self.media, timestamp);
+                self.fan_out_warp(&message, &fragment, priority);
+            }
+            self.fan_out_datagram(&chunk);
+            self.output(&chunk);
+        }
+        if let Some(t) = received_at {
+            self.metrics
+                .borrow_mut()
+                .published(self.name, chunk.header.chunk_type.as_str(), t.elapsed());
+        }
+        let cached = self.layers.entry(layer).or_default();
+        cached.bytes += chunk.len();
+        cached.gop.push(chunk);
+        Some(timestamp)
+    }
+
+    /// Forget a stream of a source that won't end.
+    pub fn stop(&mut self, source: &Source, stream_id: StreamId) {
+        let key = (source.clone(), stream_id);
+        self.buf.remove(&key);
+        self.received_at.remove(&key);
+        self.rejected.remove(&key);
+        self.stream_layer.remove(&key);
+    }
+
+    /// Timestamps of a layer of a publisher never go backwards, but for RTP video:
+    /// H.264 RTP timestamps are presentation times, which go back at every B-frame.
+    fn check_timestamp(
+        &mut self,
+        source: &Source,
+        layer: u8,
+        chunk: Chunk,
+    ) -> Result<Chunk, ChunkError> {
+        let key = (source.clone(), layer);
+        if !(*source == Source::Rtp && self.media == Media::Video) {
+            chunk
+                .header
+                .check_after(self.last_timestamp.get(&key).copied())?;
+        }
+        self.last_timestamp.insert(key, chunk.header.timestamp);
+        Ok(chunk)
+    }
+
+    /// Keep the decoder config of a layer and send it to its members when it changed.
+    fn configure(&mut self, source: &Source, layer: u8, chunk: Chunk) {
+        let config = match DecoderConfig::parse(&chunk) {
+            Ok(config) => config,
+            Err(err) => {
+                self.reject(source, &err);
+                return;
+            }
+        };
+        let current = self.layers.entry(layer).or_default();
+        if let Some((_, current)) = &current.config {
+            if *current == config {
+                return;
+            }
+        }
+        qinfo!(
+            "{} layer {} decoder config: {} {}x{}",
+            self.name,
+            layer,
+            config.codec,
+            config.width().unwrap_or(0),
+            config.height().unwrap_or(0)
+        );
+        // chunks encoded with the previous config can't be decoded any more.
+        let cached = self.layers.entry(layer).or_default();
+        cached.gop.clear_before(chunk.header.timestamp);
+        self.fan_out(layer, &chunk);
+        if layer == DEFAULT_LAYER {
+            if let Some(init) = self.muxer.configure(&config).map(<[u8]>::to_vec) {
+                let message = format!("{{\"init\":{{\"id\":{}}}}}", self.muxer.init_id);
+                self.fan_out_warp(&message, &init, Self::init_priority(self.media));
+            }
+            self.fan_out_datagram(&chunk);
+            self.output(&chunk);
+        }
+        self.layers.entry(layer).or_default().config = Some((chunk, config));
+    }
+
+    /// Decoder config of the default layer.
+    pub fn config(&self) -> Option<&DecoderConfig> {
+        self.layers
+            .get(&DEFAULT_LAYER)
+            .and_then(|l| l.config.as_ref())
+            .map(|(_, c)| c)
+    }
+
+    /// The last keyframe of the default layer with its decoder config and timestamp, as JSON
+    /// with the binary fields in base64, so a lobby can decode a thumbnail.
+    /// `None` for audio channels and channels without a publisher.
+    pub fn snapshot(&self) -> Option<Value> {
+        if self.media != Media::Video || self.publishers.is_empty() {
+            return None;
+        }
+        let layer = self.layers.get(&DEFAULT_LAYER)?;
+        let (_, config) = layer.config.as_ref()?;
+        let key = layer.gop.key()?;
+        let mut decoder_config = config.json.clone();
+        if !config.description.is_empty() {
+            decoder_config["description"] = json!(base64::encode(&config.description));
+        }
+        Some(json!({
+            "channel": self.name,
+            "timestamp": key.header.timestamp,
+            "config": decoder_config,
+            "keyframe": base64::encode(key.payload()),
+        }))
+    }
+
+    fn reject(&mut self, source: &Source, err: &ChunkError) {
+        qwarn!("reject chunk of {}: {}", self.name, err);
+        self.metrics
+            .borrow_mut()
+            .chunk_rejected(self.name, err.reason());
+        if let Some(conn) = source.conn() {
+            qlog_events::frame_dropped(conn, None, err.reason());
+        }
+    }
+
+    /// Pass a chunk of the default layer to the recording, the RTP egress and the HLS playlist.
+    fn output(&mut self, chunk: &Chunk) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.write(chunk);
+        }
+        if let Some(egress) = &mut self.egress {
+            egress.send(chunk);
+        }
+        self.hls.push(chunk);
+    }
+
+    /// Send a chunk of `layer` to its members, and move the members waiting for
+    /// this layer over when the chunk is a keyframe.
+    fn fan_out(&mut self, layer: u8, chunk: &Chunk) {
+        qdebug!("send {} bytes data.", chunk.len());
+        let config = if chunk.is_key() {
+            self.layers
+                .get(&layer)
+                .and_then(Layer::config_chunk)
+                .cloned()
+        } else {
+            None
+        };
+        let group = self.layers.get(&layer).map_or(0, |l| l.group);
+        let priority = StreamPriority::of_chunk(self.media, chunk, group);
+        let mut metrics = self.metrics.borrow_mut();
+        for (conn, handler) in self.members.iter_mut() {
+            let ml = match self.member_layers.get_mut(conn) {
+                Some(ml) => ml,
+                None => continue,
+            };
+            if ml.current != layer {
+                if ml.pending != Some(layer) || !chunk.is_key() {
+                    continue;
+                }
+                // the new layer may use another decoder config.
+                if let Some(config) = &config {
+                    // sent along with the keyframe.
+                    ml.queued +=
+                        Self::send(handler, config, self.track, priority, self.name, &mut metrics);
+                }
+                qinfo!("{} switched from layer {} to {}", self.name, ml.current, layer);
+                metrics.layer_switched(
+                    self.name,
+                    if layer > ml.current { "down" } else { "up" },
+                    if ml.auto.is_some() { "auto" } else { "manual" },
+                );
+                ml.current = layer;
+                ml.pending = None;
+                ml.notify(&format!("layer {}", layer));
+            }
+            ml.queued += Self::send(handler, chunk, self.track, priority, self.name, &mut metrics);
+        }
+    }
+
+    fn fan_out_warp(&mut self, message: &str, data: &[u8], priority: StreamPriority) {
+        let mut metrics = self.metrics.borrow_mut();
+        for handler in self.warp_members.values_mut() {
+            Self::send_warp(handler, message, data, priority, self.name, &mut metrics);
+        }
+    }
+
+    fn fan_out_datagram(&mut self, chunk: &Chunk) {
+        if self.datagram_members.is_empty() {
+            return;
+        }
+        let frame = self.sent_frame(chunk);
+        let mut metrics = self.metrics.borrow_mut();
+        for session in self.datagram_members.values_mut() {
+            Self::send_datagram(session, frame, chunk, &self.datagram, self.name, &mut metrics);
+        }
+    }
+
+    // the id of a chunk sent in datagrams, kept for retransmissions until its deadline.
+    fn sent_frame(&mut self, chunk: &Chunk) -> u32 {
+        let frame = self.next_frame;
+        self.next_frame = self.next_frame.wrapping_add(1);
+        let now = Instant::now();
+        let deadline = self.datagram.deadline;
+        while self
+            .sent_frames
+            .front()
+            .map_or(false, |s| s.sent_at + deadline <= now)
+        {
+            self.sent_frames.pop_front();
+        }
+        if !deadline.is_zero() {
+            self.sent_frames.push_back(SentFrame {
+                frame,
+                chunk: chunk.clone(),
+                sent_at: now,
+            });
+        }
+        frame
+    }
+
+    // `None` when the peer didn't negotiate datagrams, or takes too small ones for a fragment.
+    fn max_datagram_size(session: &WebTransportRequest) -> Option<usize> {
+        let size = (session.max_datagram_size().ok()? as usize).min(MAX_DATAGRAM_SIZE);
+        if size > datagram::HEADER_LEN {
+            Some(size)
+        } else {
+            qdebug!("datagrams of {} bytes can't carry fragments", size);
+            None
+        }
+    }
+
+    // send a chunk as one frame of fragments, with parity fragments when FEC is on.
+    fn send_datagram(
+        session: &mut WebTransportRequest,
+        frame: u32,
+        chunk: &Chunk,
+        config: &DatagramConfig,
+        name: &'static str,
+        metrics: &mut Metrics,
+    ) {
+        let max_size = match Self::max_datagram_size(session) {
+            Some(size) => size,
+            None => return,
+        };
+        let fragments = datagram::fragment(frame, chunk.as_bytes(), max_size, config.group());
+        for fragment in &fragments {
+            let kind = match datagram::FragmentHeader::parse(fragment) {
+                Some((header, _)) if header.is_parity() => "parity",
+                _ => "data",
+            };
+            Self::send_fragment(session, fragment, kind, config, name, metrics);
+        }
+    }
+
+    fn send_fragment(
+        session: &mut WebTransportRequest,
+        fragment: &[u8],
+        kind: &'static str,
+        config: &DatagramConfig,
+        name: &'static str,
+        metrics: &mut Metrics,
+    ) {
+        if config.simulated_loss > 0
+            && u32::from(random(1)[0]) * 100 < config.simulated_loss * 256
+        {
+            metrics.datagram_fragment(name, "simulated_loss");
+            return;
+        }
+        match session.send_datagram(fragment, None) {
+            Ok(()) => {
+                metrics.datagram_fragment(name, kind);
+                metrics.bytes_out(name, fragment.len());
+            }
+            Err(err) => {
+                qdebug!("send datagram error. {:?}", err);
+                metrics.datagram_fragment(name, "failed");
+            }
+        }
+    }
+
+    // the init segment goes before any fragment.
+    fn init_priority(media: Media) -> StreamPriority {
+        StreamPriority::of_fragment(media, i64::MAX)
+    }
+
+    // send `msg_len(1) + message + data` with new stream, as the Warp demo expects.
+    fn send_warp(
+        handler: &mut WebTransportRequest,
+        message: &str,
+        data: &[u8],
+        priority: StreamPriority,
+        name: &'static str,
+        metrics: &mut Metrics,
+    ) {
+        match handler.create_stream(StreamType::UniDi) {
+            Ok(mut stream) => {
+                priority.apply(&mut stream);
+                metrics.stream_opened("server");
+                qlog_events::stream_opened(&handler.conn, stream.stream_id(), "server");
+                let sent = Self::write_all(
+                    &mut stream,
+                    &[&[message.len() as u8], message.as_bytes(), data],
+                );
+                metrics.bytes_out(name, sent);
+            }
+            Err(err) => {
+                qerror!("create stream error. {}", err);
+            }
+        }
+    }
+
+    // Write `parts` and end the stream, or reset it when its send buffer can't take them
+    // all so that the viewer never gets a truncated message.
+    // Returns the bytes written.
+    fn write_all(stream: &mut Http3OrWebTransportStream, parts: &[&[u8]]) -> usize {
+        let mut sent = 0;
+        for part in parts {
+            let n = stream.send_data(part).unwrap_or(0);
+            sent += n;
+            if n < part.len() {
+                let _ = stream.stream_reset_send(Error::HttpRequestCancelled.code());
+                return sent;
+            }
+        }
+        let _ = stream.stream_close_send();
+        sent
+    }
+
+    // send data with new stream.
+    // Returns the bytes of the chunk that weren't sent because the send buffer was full,
+    // the stream is reset then.
+    pub fn send(
+        handler: &mut WebTransportRequest,
+        chunk: &Chunk,
+        track: Option<u8>,
+        priority: StreamPriority,
+        name: &'static str,
+        metrics: &mut Metrics,
+    ) -> usize {
+        let timestamp = chunk.header.timestamp;
+        match handler.create_stream(StreamType::UniDi) {
+            Ok(mut stream) => {
+                priority.apply(&mut stream);
+                metrics.stream_opened("server");
+                qlog_events::stream_opened(&handler.conn, stream.stream_id(), "server");
+                let track = track.map(|t| [t]);
+                let prefix: &[u8] = track.as_ref().map_or(&[], |t| &t[..]);
+                let len = prefix.len() + chunk.len();
+                let sent = Self::write_all(&mut stream, &[prefix, chunk.as_bytes()]);
+                metrics.bytes_out(name, sent);
+                if sent < len {
+                    qlog_events::frame_dropped(&handler.conn, Some(timestamp), "send buffer full");
+                    return len - sent;
+                }
+                qlog_events::chunk_delivered(
+                    &handler.conn,
+                    stream.stream_id(),
+                    timestamp,
+                    chunk.len(),
+                );
+                0
+            }
+            Err(err) => {
+                qerror!("create stream error. {}", err);
+                qlog_events::frame_dropped(&handler.conn, Some(timestamp), "create stream error");
+                chunk.len()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publisher() -> Publisher {
+        Publisher::new("video", 1024, None, Rc::new(RefCell::new(Metrics::new())))
+    }
+
+    #[test]
+    fn rtp_does_not_share_a_channel() {
+        let mut p = publisher();
+        assert!(p.accepts(&Source::Rtp));
+        assert!(p.accepts(&Source::Upstream));
+
+        p.start_from(Source::Rtp);
+        assert!(p.accepts(&Source::Rtp));
+        assert!(!p.accepts(&Source::Upstream));
+        p.end_from(&Source::Rtp);
+
+        // another kind of publisher first, RTP waits for it to end.
+        p.start_from(Source::Upstream);
+        assert!(!p.accepts(&Source::Rtp));
+        assert!(p.accepts(&Source::Upstream));
+        p.end_from(&Source::Upstream);
+        assert!(p.accepts(&Source::Rtp));
+    }
+
+    #[test]
+    fn streams_of_sources_are_apart() {
+        let mut p = publisher();
+        p.start_from(Source::Rtp);
+        p.start_from(Source::Upstream);
+        let id = StreamId::from(2);
+        assert_eq!(p.publish_from(&Source::Upstream, id, vec![1, 2], false), None);
+        assert_eq!(p.publish_from(&Source::Rtp, id, vec![3], false), None);
+        assert_eq!(p.buf[&(Source::Upstream, id)], vec![1, 2]);
+        assert_eq!(p.buf[&(Source::Rtp, id)], vec![3]);
+
+        // a chunk too large is dropped until the end of its stream, the other goes on.
+        assert_eq!(p.publish_from(&Source::Upstream, id, vec![0; 1024], false), None);
+        assert!(p.rejected.contains(&(Source::Upstream, id)));
+        assert_eq!(p.publish_from(&Source::Upstream, id, vec![0], true), None);
+        assert!(p.rejected.is_empty());
+        assert_eq!(p.buf[&(Source::Rtp, id)], vec![3]);
+
+        p.end_from(&Source::Rtp);
+        assert!(p.buf.is_empty());
+    }
+}
@@ -0,0 +1,602 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Named topics (`/pubsub`), the fan-out of messages that aren't media chunks.
+//!
+//! A session subscribes with lines on a bidi control stream:
+//!
+//! - `subscribe {topic} [stream|datagram]`, answered `subscribed {topic} {delivery}`
+//! - `unsubscribe {topic}`, answered `unsubscribed {topic}`
+//!
+//! and `error {reason}` otherwise. The delivery belongs to the topic: it is set when server
+//! code declares or reserves the topic, or by its first subscriber (`stream` by default), and
+//! later subscribers asking for another one are refused. Declaring a topic that has
+//! subscribers keeps their delivery. Sessions publish a message on a uni
+//! stream or in a datagram, server code with `Topics::publish`; only server code publishes
+//! on the topics it declared or reserved, messages of sessions there are refused. Each message is delivered
+//! as `topic_len(1) + topic + payload`, on its own uni stream or in one datagram (dropped
+//! when larger than the session accepts). A stream published on is stopped once it carries
+//! more than 64 KiB, a stream delivered on is reset when the send buffer is full, and a
+//! control stream is reset on a line longer than 1 KiB.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use neqo_common::{qdebug, qerror, qinfo, qwarn};
+use neqo_http3::{Error, Http3OrWebTransportStream, WebTransportRequest};
+use neqo_transport::{server::ActiveConnectionRef, StreamId, StreamType};
+
+use crate::metrics::Metrics;
+use crate::qlog_events;
+
+/// Largest message a session can publish on a stream.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Longest command line on a control stream.
+const MAX_CONTROL_LINE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Stream,
+    Datagram,
+}
+
+impl Delivery {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stream => "stream",
+            Self::Datagram => "datagram",
+        }
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(Self::Stream),
+            "datagram" => Ok(Self::Datagram),
+            _ => Err(format!("unknown delivery {}", s)),
+        }
+    }
+}
+
+/// Who can publish on a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Publishers {
+    // server code only, sessions' messages are refused.
+    Server,
+    Any,
+}
+
+/// Messages received on streams, until their end.
+pub struct Incoming<K> {
+    max_size: usize,
+    buf: HashMap<K, Vec<u8>>,
+    // streams whose message was larger than `max_size`, until their end.
+    rejected: HashSet<K>,
+}
+
+impl<K: Clone + Eq + Hash> Incoming<K> {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            buf: HashMap::new(),
+            rejected: HashSet::new(),
+        }
+    }
+
+    /// Data of a stream. Returns the message at the end of the stream, or the size of a
+    /// message larger than `max_size` when it gets rejected: the rest of its stream is
+    /// ignored and the caller should stop it.
+    pub fn receive(&mut self, key: K, data: Vec<u8>, fin: bool) -> Result<Option<Vec<u8>>, usize> {
+        if self.rejected.contains(&key) {
+            if fin {
+                self.rejected.remove(&key);
+            }
+            return Ok(None);
+        }
+        let buf = self.buf.entry(key.clone()).or_default();
+        buf.extend(data);
+        if buf.len() > self.max_size {
+            let len = buf.len();
+            self.buf.remove(&key);
+            if !fin {
+                self.rejected.insert(key);
+            }
+            return Err(len);
+        }
+        if !fin {
+            return Ok(None);
+        }
+        Ok(self.buf.remove(&key))
+    }
+
+    /// Forget the streams `f` returns false for.
+    pub fn retain<F: Fn(&K) -> bool>(&mut self, f: F) {
+        self.buf.retain(|k, _| f(k));
+        self.rejected.retain(|k| f(k));
+    }
+}
+
+/// A message of a topic, `topic_len(1) + topic + payload`.
+pub fn encode(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(1 + topic.len() + payload.len());
+    message.push(topic.len() as u8);
+    message.extend_from_slice(topic.as_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+/// The topic and payload of a message.
+pub fn decode(message: &[u8]) -> Option<(&str, &[u8])> {
+    let len = usize::from(*message.first()?);
+    let topic = std::str::from_utf8(message.get(1..1 + len)?).ok()?;
+    Some((topic, &message[1 + len..]))
+}
+
+struct Topic {
+    delivery: Delivery,
+    publishers: Publishers,
+    // declared by server code, kept without subscribers.
+    declared: bool,
+    subscribers: HashMap<ActiveConnectionRef, WebTransportRequest>,
+}
+
+/// The control stream of a session and the end of a line not received yet.
+#[derive(Default)]
+struct Control {
+    stream: Option<Http3OrWebTransportStream>,
+    buf: Vec<u8>,
+}
+
+pub struct Topics {
+    topics: BTreeMap<String, Topic>,
+    // prefixes of the topics only server code publishes on, with their delivery.
+    reserved: Vec<(String, Delivery)>,
+    // sessions of `/pubsub`.
+    sessions: HashMap<ActiveConnectionRef, WebTransportRequest>,
+    controls: HashMap<ActiveConnectionRef, Control>,
+    // messages published on streams, until their end.
+    incoming: Incoming<(ActiveConnectionRef, StreamId)>,
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+impl Topics {
+    pub fn new(metrics: Rc<RefCell<Metrics>>) -> Self {
+        Self {
+            topics: BTreeMap::new(),
+            reserved: Vec::new(),
+            sessions: HashMap::new(),
+            controls: HashMap::new(),
+            incoming: Incoming::new(MAX_MESSAGE_SIZE),
+            metrics,
+        }
+    }
+
+    /// Create a topic delivered with `delivery`, kept while nobody subscribes. Only server
+    /// code publishes on it from now on. A topic with subscribers keeps its delivery, which
+    /// is returned.
+    pub fn declare(&mut self, topic: &str, delivery: Delivery) -> Delivery {
+        let t = self
+            .topics
+            .entry(topic.to_string())
+            .or_insert_with(|| Topic {
+                delivery,
+                publishers: Publishers::Server,
+                declared: true,
+                subscribers: HashMap::new(),
+            });
+        if t.subscribers.is_empty() {
+            t.delivery = delivery;
+        }
+        t.publishers = Publishers::Server;
+        t.declared = true;
+        t.delivery
+    }
+
+    /// Topics starting with `prefix` are delivered with `delivery` and only server code
+    /// publishes on them, even when a subscriber creates them.
+    pub fn reserve(&mut self, prefix: &str, delivery: Delivery) {
+        self.reserved.push((prefix.to_string(), delivery));
+    }
+
+    fn reserved(&self, topic: &str) -> Option<Delivery> {
+        self.reserved
+            .iter()
+            .find(|(prefix, _)| topic.starts_with(prefix.as_str()))
+            .map(|&(_, delivery)| delivery)
+    }
+
+    /// Let a declared topic go once nobody subscribes. It stays the server's until then.
+    pub fn undeclare(&mut self, topic: &str) {
+        if let Some(t) = self.topics.get_mut(topic) {
+            t.declared = false;
+        }
+        self.topics
+            .retain(|_, t| t.declared || !t.subscribers.is_empty());
+    }
+
+    /// A `/pubsub` session, which can subscribe with its control stream.
+    pub fn join(&mut self, session: WebTransportRequest) {
+        self.sessions.insert(session.conn.clone(), session);
+    }
+
+    /// Subscribe a session to a topic, created with `delivery` (or `stream`) if needed.
+    /// Returns the delivery of the topic.
+    pub fn subscribe(
+        &mut self,
+        session: &WebTransportRequest,
+        topic: &str,
+        delivery: Option<Delivery>,
+    ) -> Result<Delivery, String> {
+        if topic.is_empty() || topic.len() > usize::from(u8::MAX) {
+            return Err(format!("invalid topic {:?}", topic));
+        }
+        let reserved = self.reserved(topic);
+        let t = self
+            .topics
+            .entry(topic.to_string())
+            .or_insert_with(|| Topic {
+                delivery: reserved.or(delivery).unwrap_or(Delivery::Stream),
+                publishers: if reserved.is_some() {
+                    Publishers::Server
+                } else {
+                    Publishers::Any
+                },
+                declared: false,
+                subscribers: HashMap::new(),
+            });
+        if delivery.map_or(false, |d| d != t.delivery) {
+            return Err(format!("{} is delivered by {}", topic, t.delivery));
+        }
+        t.subscribers.insert(session.conn.clone(), session.clone());
+        Ok(t.delivery)
+    }
+
+    pub fn unsubscribe(&mut self, conn: &ActiveConnectionRef, topic: &str) {
+        if let Some(t) = self.topics.get_mut(topic) {
+            t.subscribers.remove(conn);
+        }
+        self.topics
+            .retain(|_, t| t.declared || !t.subscribers.is_empty());
+    }
+
+    /// Forget a session and its subscriptions.
+    pub fn leave(&mut self, conn: &ActiveConnectionRef) {
+        self.sessions.remove(conn);
+        self.controls.remove(conn);
+        self.incoming.retain(|(c, _)| c != conn);
+        for t in self.topics.values_mut() {
+            t.subscribers.remove(conn);
+        }
+        self.topics
+            .retain(|_, t| t.declared || !t.subscribers.is_empty());
+    }
+
+    /// Topics with their delivery and subscriber count.
+    pub fn list(&self) -> Vec<(&str, Delivery, usize)> {
+        self.topics
+            .iter()
+            .map(|(name, t)| (name.as_str(), t.delivery, t.subscribers.len()))
+            .collect()
+    }
+
+    /// Commands on the control stream of a session.
+    pub fn control(&mut self, stream: Http3OrWebTransportStream, data: &[u8]) {
+        let conn = stream.conn.clone();
+        let session = match self.sessions.get(&conn) {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        let control = self.controls.entry(conn.clone()).or_default();
+        control.stream = Some(stream);
+        control.buf.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(eol) = control.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = control.buf.drain(..=eol).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        if control.buf.len() > MAX_CONTROL_LINE {
+            qwarn!("pubsub control line longer than {} bytes", MAX_CONTROL_LINE);
+            control.buf.clear();
+            if let Some(mut stream) = control.stream.take() {
+                let _ = stream.stream_stop_sending(Error::HttpRequestCancelled.code());
+                let _ = stream.stream_reset_send(Error::HttpRequestCancelled.code());
+            }
+        }
+        for line in lines {
+            let mut words = line.split_whitespace();
+            let reply = match (words.next(), words.next(), words.next()) {
+                (Some("subscribe"), Some(topic), delivery) => {
+                    let delivery = delivery.map(str::parse).transpose();
+                    match delivery.and_then(|d| self.subscribe(&session, topic, d)) {
+                        Ok(delivery) => {
+                            qinfo!("subscribed to {} by {}", topic, delivery);
+                            format!("subscribed {} {}", topic, delivery)
+                        }
+                        Err(err) => format!("error {}", err),
+                    }
+                }
+                (Some("unsubscribe"), Some(topic), None) => {
+                    self.unsubscribe(&conn, topic);
+                    format!("unsubscribed {}", topic)
+                }
+                _ => {
+                    qwarn!("unknown pubsub command {:?}", line);
+                    format!("error {}", line)
+                }
+            };
+            if let Some(stream) = self.controls.get_mut(&conn).and_then(|c| c.stream.as_mut()) {
+                let _ = stream.send_data(format!("{}\n", reply).as_bytes());
+            }
+        }
+    }
+
+    /// A message published on a uni stream, `topic_len(1) + topic + payload`.
+    pub fn receive(&mut self, mut stream: Http3OrWebTransportStream, data: Vec<u8>, fin: bool) {
+        let key = (stream.conn.clone(), stream.stream_id());
+        match self.incoming.receive(key, data, fin) {
+            Ok(Some(message)) => {
+                self.receive_message(&message);
+            }
+            Ok(None) => {}
+            Err(len) => {
+                qwarn!(
+                    "pubsub message of {} bytes, larger than {}",
+                    len,
+                    MAX_MESSAGE_SIZE
+                );
+                if !fin {
+                    let _ = stream.stream_stop_sending(Error::HttpRequestCancelled.code());
+                }
+            }
+        }
+    }
+
+    /// A message published in a datagram.
+    pub fn receive_datagram(&mut self, conn: &ActiveConnectionRef, datagram: &[u8]) {
+        if self.sessions.contains_key(conn) {
+            self.receive_message(datagram);
+        }
+    }
+
+    /// A message of a session. Returns false if it was invalid or refused.
+    fn receive_message(&mut self, message: &[u8]) -> bool {
+        let (topic, payload) = match decode(message) {
+            Some(message) => message,
+            None => {
+                qwarn!("invalid pubsub message of {} bytes", message.len());
+                return false;
+            }
+        };
+        let server = match self.topics.get(topic) {
+            Some(t) if t.publishers == Publishers::Server => Some(t.delivery),
+            Some(_) => None,
+            None => self.reserved(topic),
+        };
+        if let Some(delivery) = server {
+            qwarn!("pubsub message of a session on server topic {}", topic);
+            self.metrics
+                .borrow_mut()
+                .pubsub_message(delivery.as_str(), "refused");
+            return false;
+        }
+        let topic = topic.to_string();
+        self.publish(&topic, payload);
+        true
+    }
+
+    /// Send a message to the subscribers of a topic. Returns how many it was sent to.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> usize {
+        let t = match self.topics.get_mut(topic) {
+            Some(t) => t,
+            None => return 0,
+        };
+        let message = encode(topic, payload);
+        let mut metrics = self.metrics.borrow_mut();
+        let mut sent = 0;
+        for session in t.subscribers.values_mut() {
+            let result = match t.delivery {
+                Delivery::Stream => Self::send_stream(session, &message, &mut metrics),
+                Delivery::Datagram => Self::send_datagram(session, &message),
+            };
+            if result == "sent" {
+                sent += 1;
+            }
+            metrics.pubsub_message(t.delivery.as_str(), result);
+        }
+        sent
+    }
+
+    /// Send a message of a topic to one session only, on a stream.
+    pub fn send(&self, session: &mut WebTransportRequest, topic: &str, payload: &[u8]) -> bool {
+        let mut metrics = self.metrics.borrow_mut();
+        let result = Self::send_stream(session, &encode(topic, payload), &mut metrics);
+        metrics.pubsub_message(Delivery::Stream.as_str(), result);
+        result == "sent"
+    }
+
+    fn send_stream(
+        session: &mut WebTransportRequest,
+        message: &[u8],
+        metrics: &mut Metrics,
+    ) -> &'static str {
+        match session.create_stream(StreamType::UniDi) {
+            Ok(mut stream) => {
+                metrics.stream_opened("server");
+                qlog_events::stream_opened(&session.conn, stream.stream_id(), "server");
+                let sent = stream.send_data(message).unwrap_or(0);
+                if sent < message.len() {
+                    // the send buffer is full, a cut message must not look whole.
+                    let _ = stream.stream_reset_send(Error::HttpRequestCancelled.code());
+                    "failed"
+                } else {
+                    let _ = stream.stream_close_send();
+                    "sent"
+                }
+            }
+            Err(err) => {
+                qerror!("create stream error. {}", err);
+                "failed"
+            }
+        }
+    }
+
+    fn send_datagram(session: &mut WebTransportRequest, message: &[u8]) -> &'static str {
+        let max_size = session.max_datagram_size().map_or(0, |size| size as usize);
+        if message.len() > max_size {
+            return "too_large";
+        }
+        match session.send_datagram(message, None) {
+            Ok(()) => "sent",
+            Err(err) => {
+                qdebug!("send datagram error. {:?}", err);
+                "failed"
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delivery_names() {
+        for delivery in [Delivery::Stream, Delivery::Datagram] {
+            assert_eq!(delivery.as_str().parse(), Ok(delivery));
+            assert_eq!(delivery.to_string(), delivery.as_str());
+        }
+        assert!("carrier-pigeon".parse::<Delivery>().is_err());
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = encode("scores", b"1-0");
+        assert_eq!(message, b"\x06scores1-0");
+        assert_eq!(decode(&message), Some(("scores", &b"1-0"[..])));
+        assert_eq!(decode(&encode("empty", b"")), Some(("empty", &b""[..])));
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert_eq!(decode(b""), None);
+        // shorter than its topic.
+        assert_eq!(decode(b"\x06sco"), None);
+        assert_eq!(decode(b"\x02\xff\xfe"), None);
+    }
+
+    #[test]
+    fn message_until_fin() {
+        let mut incoming = Incoming::new(8);
+        assert_eq!(incoming.receive(1, b"abc".to_vec(), false), Ok(None));
+        assert_eq!(
+            incoming.receive(2, b"x".to_vec(), true),
+            Ok(Some(b"x".to_vec()))
+        );
+        assert_eq!(
+            incoming.receive(1, b"def".to_vec(), true),
+            Ok(Some(b"abcdef".to_vec()))
+        );
+        assert_eq!(incoming.receive(3, Vec::new(), true), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn oversized_message_is_rejected_until_fin() {
+        let mut incoming = Incoming::new(8);
+        assert_eq!(incoming.receive(1, b"abcdef".to_vec(), false), Ok(None));
+        assert_eq!(incoming.receive(1, b"ghijkl".to_vec(), false), Err(12));
+        // the tail of the stream isn't taken for a new message.
+        assert_eq!(incoming.receive(1, b"\x01a".to_vec(), false), Ok(None));
+        assert_eq!(incoming.receive(1, b"b".to_vec(), true), Ok(None));
+        // the stream id is free again.
+        assert_eq!(
+            incoming.receive(1, b"ok".to_vec(), true),
+            Ok(Some(b"ok".to_vec()))
+        );
+        // rejected at its end, nothing more to ignore.
+        assert_eq!(incoming.receive(2, vec![0; 9], true), Err(9));
+        assert_eq!(
+            incoming.receive(2, b"ok".to_vec(), true),
+            Ok(Some(b"ok".to_vec()))
+        );
+    }
+
+    #[test]
+    fn sessions_cannot_publish_on_declared_topics() {
+        let metrics = Rc::new(RefCell::new(Metrics::new()));
+        let mut topics = Topics::new(metrics.clone());
+        topics.declare("channels", Delivery::Stream);
+        assert!(!topics.receive_message(&encode("channels", b"{}")));
+        // still the server's once undeclared, until it goes away.
+        topics.undeclare("channels");
+        assert!(topics.receive_message(&encode("channels", b"{}")));
+        assert!(topics.receive_message(&encode("scores", b"1-0")));
+        assert!(!topics.receive_message(b"\x06sco"));
+        let text = metrics.borrow().render(&BTreeMap::new());
+        assert!(text.contains("pubsub_messages_total{delivery=\"stream\",result=\"refused\"} 1"));
+    }
+
+    #[test]
+    fn reserved_topics() {
+        let metrics = Rc::new(RefCell::new(Metrics::new()));
+        let mut topics = Topics::new(metrics.clone());
+        topics.reserve("presence/", Delivery::Datagram);
+        // refused before server code declares the topic, and after.
+        assert!(!topics.receive_message(&encode("presence/video", b"{}")));
+        assert_eq!(
+            topics.declare("presence/video", Delivery::Datagram),
+            Delivery::Datagram
+        );
+        assert!(!topics.receive_message(&encode("presence/video", b"{}")));
+        topics.undeclare("presence/video");
+        assert!(!topics.receive_message(&encode("presence/video", b"{}")));
+        assert!(topics.receive_message(&encode("presences", b"{}")));
+        let text = metrics.borrow().render(&BTreeMap::new());
+        assert!(text.contains("pubsub_messages_total{delivery=\"datagram\",result=\"refused\"} 3"));
+    }
+
+    #[test]
+    fn declare_without_subscribers() {
+        let metrics = Rc::new(RefCell::new(Metrics::new()));
+        let mut topics = Topics::new(metrics);
+        assert_eq!(topics.declare("scores", Delivery::Stream), Delivery::Stream);
+        assert_eq!(
+            topics.declare("scores", Delivery::Datagram),
+            Delivery::Datagram
+        );
+        assert_eq!(topics.list(), vec![("scores", Delivery::Datagram, 0)]);
+        topics.undeclare("scores");
+        assert!(topics.list().is_empty());
+    }
+
+    #[test]
+    fn retain() {
+        let mut incoming = Incoming::new(4);
+        assert_eq!(incoming.receive(1, b"ab".to_vec(), false), Ok(None));
+        assert_eq!(incoming.receive(2, b"abcdef".to_vec(), false), Err(6));
+        incoming.retain(|_| false);
+        assert_eq!(
+            incoming.receive(1, b"c".to_vec(), true),
+            Ok(Some(b"c".to_vec()))
+        );
+        assert_eq!(
+            incoming.receive(2, b"c".to_vec(), true),
+            Ok(Some(b"c".to_vec()))
+        );
+    }
+}
@@ -0,0 +1,89 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Application level WebTransport events written into the qlog of a connection.
+//!
+//! qlog has no event type for these, so they are written as markers whose
+//! `marker_type` is the event name and whose message is a JSON object.
+//! Nothing is written unless the server runs with `--qlog-dir`.
+
+use neqo_transport::server::ActiveConnectionRef;
+use neqo_transport::StreamId;
+use qlog::EventData;
+use serde_json::{json, Value};
+
+use crate::chunk::ChunkHeader;
+
+fn add(conn: &ActiveConnectionRef, name: &str, data: Value) {
+    conn.clone()
+        .borrow_mut()
+        .qlog_mut()
+        .add_event_data(|| {
+            Some(EventData::Marker {
+                marker_type: format!("webtransport:{}", name),
+                message: Some(data.to_string()),
+            })
+        });
+}
+
+pub fn session_accepted(conn: &ActiveConnectionRef, session: StreamId, path: &str) {
+    add(
+        conn,
+        "session_accepted",
+        json!({ "session": session.as_u64(), "path": path }),
+    );
+}
+
+pub fn stream_opened(conn: &ActiveConnectionRef, stream: StreamId, initiator: &str) {
+    add(
+        conn,
+        "stream_opened",
+        json!({ "stream": stream.as_u64(), "initiator": initiator }),
+    );
+}
+
+/// A chunk received from the publisher on this connection.
+pub fn chunk_published(
+    conn: &ActiveConnectionRef,
+    stream: StreamId,
+    header: &ChunkHeader,
+    size: usize,
+) {
+    add(
+        conn,
+        "chunk_published",
+        json!({
+            "stream": stream.as_u64(),
+            "type": header.chunk_type.as_str(),
+            "timestamp": header.timestamp,
+            "duration": header.duration,
+            "size": size,
+        }),
+    );
+}
+
+/// A chunk sent to the viewer on this connection.
+pub fn chunk_delivered(
+    conn: &ActiveConnectionRef,
+    stream: StreamId,
+    timestamp: i64,
+    size: usize,
+) {
+    add(
+        conn,
+        "chunk_delivered",
+        json!({ "stream": stream.as_u64(), "timestamp": timestamp, "size": size }),
+    );
+}
+
+/// A chunk that was not sent to the viewer on this connection.
+pub fn frame_dropped(conn: &ActiveConnectionRef, timestamp: Option<i64>, reason: &str) {
+    add(
+        conn,
+        "frame_dropped",
+        json!({ "timestamp": timestamp, "reason": reason }),
+    );
+}
@@ -0,0 +1,353 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recording of published chunks to disk.
+//!
+//! Each broadcast of a channel is written to its own directory,
+//! `{record-dir}/{channel}/{start}` where `start` is the unix time in milliseconds
+//! when the first chunk arrived:
+//!
+//! - `{segment:05}.seg` : records of `length(4) + received_at(8, unix micros) + chunk`,
+//!   big endian, where `chunk` is the whole chunk as published (header included).
+//! - `index` : one line per config chunk and keyframe,
+//!   `timestamp segment offset type`, so a reader can seek without scanning the segments.
+//!
+//! A segment is closed at the first keyframe after it has reached
+//! `--record-segment-secs` of media or `--record-segment-size` bytes.
+//! Every segment starts with the current decoder config, so each one can be decoded on its own.
+//! The files are flushed when a segment is closed and at most every `FLUSH_INTERVAL`, so the
+//! event loop doesn't wait for the disk on every chunk. After a write error the channel isn't
+//! recorded until its next broadcast.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use neqo_common::{qerror, qinfo};
+
+use crate::chunk::Chunk;
+
+pub const INDEX_FILE: &str = "index";
+
+/// Longest time written records may stay in the buffers.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the `n`th segment file of a broadcast.
+pub fn segment_name(n: u32) -> String {
+    format!("{:05}.seg", n)
+}
+
+fn unix_micros(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    pub dir: PathBuf,
+    pub segment_duration: Duration,
+    pub segment_size: u64,
+}
+
+/// Files of the broadcast being recorded.
+struct Recording {
+    dir: PathBuf,
+    index: BufWriter<File>,
+    segment: BufWriter<File>,
+    segment_no: u32,
+    // bytes written to the current segment.
+    segment_len: u64,
+    // timestamp of the first chunk of the current segment.
+    segment_start: Option<i64>,
+    // when the buffers were last written to the files.
+    flushed_at: Instant,
+}
+
+impl Recording {
+    fn create(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(INDEX_FILE))?;
+        let segment = File::create(dir.join(segment_name(0)))?;
+        Ok(Self {
+            dir,
+            index: BufWriter::new(index),
+            segment: BufWriter::new(segment),
+            segment_no: 0,
+            segment_len: 0,
+            segment_start: None,
+            flushed_at: Instant::now(),
+        })
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.segment_no += 1;
+        let segment = File::create(self.dir.join(segment_name(self.segment_no)))?;
+        self.segment = BufWriter::new(segment);
+        self.segment_len = 0;
+        self.segment_start = None;
+        Ok(())
+    }
+
+    fn write(&mut self, chunk: &Chunk) -> io::Result<()> {
+        let offset = self.segment_len;
+        let received_at = unix_micros(SystemTime::now());
+        self.segment
+            .write_all(&(chunk.len() as u32).to_be_bytes())?;
+        self.segment.write_all(&received_at.to_be_bytes())?;
+        self.segment.write_all(chunk.as_bytes())?;
+        self.segment_len += 4 + 8 + chunk.len() as u64;
+        self.segment_start.get_or_insert(chunk.header.timestamp);
+
+        if chunk.is_key() || chunk.is_config() {
+            writeln!(
+                self.index,
+                "{} {} {} {}",
+                chunk.header.timestamp,
+                self.segment_no,
+                offset,
+                chunk.header.chunk_type.as_str()
+            )?;
+        }
+        if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushed_at = Instant::now();
+        self.segment.flush()?;
+        self.index.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Records the broadcasts of one channel.
+pub struct Recorder {
+    config: RecordConfig,
+    channel: &'static str,
+    recording: Option<Recording>,
+    // the decoder config repeated at the start of every segment.
+    decoder_config: Option<Chunk>,
+    // set after a write error, until the broadcast ends.
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn new(config: RecordConfig, channel: &'static str) -> Self {
+        Self {
+            config,
+            channel,
+            recording: None,
+            decoder_config: None,
+            failed: false,
+        }
+    }
+
+    /// Directory holding the recordings of this channel.
+    pub fn channel_dir(&self) -> PathBuf {
+        self.config.dir.join(self.channel)
+    }
+
+    pub fn write(&mut self, chunk: &Chunk) {
+        if chunk.is_config() {
+            self.decoder_config = Some(chunk.clone());
+        }
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.try_write(chunk) {
+            qerror!(
+                "recording {} failed, stop recording until the next broadcast: {}",
+                self.channel,
+                err
+            );
+            self.recording = None;
+            self.failed = true;
+        }
+    }
+
+    fn try_write(&mut self, chunk: &Chunk) -> io::Result<()> {
+        if self.recording.is_none() {
+            let start = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis());
+            let dir = self.channel_dir().join(start.to_string());
+            qinfo!("start recording {} to {}", self.channel, dir.display());
+            self.recording = Some(Recording::create(dir)?);
+            // a broadcast whose config was sent before the recording started.
+            if let Some(config) = &self.decoder_config {
+                if !chunk.is_config() {
+                    self.recording.as_mut().unwrap().write(config)?;
+                }
+            }
+        }
+        let recording = self.recording.as_mut().unwrap();
+
+        if chunk.is_key() && Self::full(&self.config, recording, chunk.header.timestamp) {
+            recording.roll()?;
+            if let Some(config) = &self.decoder_config {
+                recording.write(config)?;
+            }
+        }
+        recording.write(chunk)
+    }
+
+    fn full(config: &RecordConfig, recording: &Recording, timestamp: i64) -> bool {
+        if recording.segment_len >= config.segment_size {
+            return true;
+        }
+        recording.segment_start.map_or(false, |start| {
+            timestamp.saturating_sub(start) >= config.segment_duration.as_micros() as i64
+        })
+    }
+
+    /// Close the files of the current broadcast. The next chunk starts a new one.
+    pub fn finish(&mut self) {
+        self.failed = false;
+        if let Some(recording) = self.recording.take() {
+            qinfo!(
+                "stop recording {} to {}",
+                self.channel,
+                recording.dir.display()
+            );
+            if let Err(err) = recording.finish() {
+                qerror!("recording {} failed: {}", self.channel, err);
+            }
+        }
+    }
+
+    /// Directory of the broadcast being recorded.
+    pub fn dir(&self) -> Option<&Path> {
+        self.recording.as_ref().map(|r| r.dir.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+    use crate::vod::RecordingReader;
+
+    fn chunk(chunk_type: ChunkType, timestamp: i64) -> Chunk {
+        let header = ChunkHeader {
+            chunk_type,
+            timestamp,
+            duration: 0,
+        };
+        Chunk::new(header, &[chunk_type as u8; 4])
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rs_server_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn config(dir: PathBuf) -> RecordConfig {
+        RecordConfig {
+            dir,
+            segment_duration: Duration::from_secs(2),
+            segment_size: 1 << 20,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let root = temp_dir("round_trip");
+        let mut recorder = Recorder::new(config(root.clone()), "video");
+        let chunks = [
+            chunk(ChunkType::Config, 0),
+            chunk(ChunkType::Key, 0),
+            chunk(ChunkType::Delta, 1_000_000),
+            // not a keyframe, the segment goes on past its duration.
+            chunk(ChunkType::Delta, 2_000_000),
+            chunk(ChunkType::Key, 2_500_000),
+            chunk(ChunkType::Delta, 3_000_000),
+        ];
+        for c in &chunks {
+            recorder.write(c);
+        }
+        let dir = recorder.dir().unwrap().to_path_buf();
+        assert!(dir.starts_with(root.join("video")));
+        recorder.finish();
+        assert!(recorder.dir().is_none());
+
+        assert!(dir.join(segment_name(1)).exists());
+        assert!(!dir.join(segment_name(2)).exists());
+        let index = fs::read_to_string(dir.join(INDEX_FILE)).unwrap();
+        // the second segment starts with the config again.
+        assert_eq!(
+            index.lines().collect::<Vec<_>>(),
+            vec![
+                "0 0 0 config",
+                "0 0 33 key",
+                "0 1 0 config",
+                "2500000 1 33 key"
+            ]
+        );
+
+        let mut reader = RecordingReader::open(dir.clone()).unwrap();
+        let mut read = Vec::new();
+        while let Some(c) = reader.next_chunk().unwrap() {
+            read.push((
+                c.header.chunk_type,
+                c.header.timestamp,
+                c.payload().to_vec(),
+            ));
+        }
+        let mut expected: Vec<_> = chunks
+            .iter()
+            .map(|c| {
+                (
+                    c.header.chunk_type,
+                    c.header.timestamp,
+                    c.payload().to_vec(),
+                )
+            })
+            .collect();
+        expected.insert(4, expected[0].clone());
+        assert_eq!(read, expected);
+
+        let (config, key) = reader.seek(3_000_000).unwrap();
+        assert_eq!(key, 2_500_000);
+        assert!(config.unwrap().is_config());
+        let next = reader.next_chunk().unwrap().unwrap();
+        assert_eq!((next.is_key(), next.header.timestamp), (true, 2_500_000));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stays_stopped_after_error() {
+        let root = temp_dir("stopped");
+        // a file where the channel directory should be.
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("video"), b"").unwrap();
+        let mut recorder = Recorder::new(config(root.clone()), "video");
+        recorder.write(&chunk(ChunkType::Key, 0));
+        assert!(recorder.dir().is_none());
+
+        fs::remove_file(root.join("video")).unwrap();
+        recorder.write(&chunk(ChunkType::Key, 1));
+        assert!(recorder.dir().is_none());
+        assert!(!root.join("video").exists());
+
+        // the next broadcast is recorded.
+        recorder.finish();
+        recorder.write(&chunk(ChunkType::Key, 2));
+        assert!(recorder.dir().map_or(false, Path::exists));
+        recorder.finish();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Relay mode (`--relay host:port`).
+//!
+//! The server connects to an upstream server as a WebTransport client, subscribes to
+//! its `/video/view`, and publishes every chunk received to the local video channel as
+//! if it came from a local publisher. Local viewers, recordings and fMP4 players work
+//! as usual. When the session or the connection to the upstream is lost, the relay
+//! connects again after a delay doubling from `BACKOFF_MIN` up to `BACKOFF_MAX`.
+//!
+//! With `--relay-path /video/datagram` the chunks come in datagrams instead, and the
+//! fragments lost on the way are rebuilt from the parity fragments when the upstream
+//! sends them (`--datagram-fec`), or asked for again with NACK datagrams.
+//!
+//! The upstream must present the certificate of `--relay-cert`, unless `--relay-insecure`
+//! turns the check off.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::net::UdpSocket;
+use neqo_common::{qdebug, qerror, qinfo, qwarn, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Http3Client, Http3ClientEvent, Http3Parameters, Http3State, WebTransportEvent};
+use neqo_transport::{ConnectionParameters, Output, RandomConnectionIdGenerator, StreamId};
+
+use crate::base64;
+use crate::chunk::Chunk;
+use crate::datagram::Reassembler;
+use crate::metrics::Metrics;
+use crate::publisher::{Publisher, Source};
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+pub struct Relay {
+    // `host:port` of the upstream server.
+    upstream: String,
+    server_name: String,
+    path: String,
+    // DER of the certificate the upstream must present, `None` with `--relay-insecure`.
+    certificate: Option<Vec<u8>>,
+    remote: SocketAddr,
+    local: SocketAddr,
+    socket: UdpSocket,
+    client: Option<Http3Client>,
+    // the WebTransport session, set once the upstream accepted it.
+    session: Option<StreamId>,
+    // unidirectional streams of the session.
+    streams: HashSet<StreamId>,
+    // frames of the session when the upstream sends datagrams.
+    reassembler: Reassembler,
+    // when the client wants to be called again.
+    timeout: Option<Instant>,
+    // when to connect again after losing the upstream.
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+impl Relay {
+    pub fn new(
+        upstream: &str,
+        path: &str,
+        certificate: Option<Vec<u8>>,
+        metrics: Rc<RefCell<Metrics>>,
+    ) -> io::Result<Self> {
+        let remote = upstream.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unable to resolve {}", upstream),
+            )
+        })?;
+        let bind = if remote.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(&bind.parse::<SocketAddr>().unwrap())?;
+        let local = socket.local_addr()?;
+        let server_name = upstream
+            .rsplit_once(':')
+            .map_or(upstream, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        Ok(Self {
+            upstream: upstream.to_string(),
+            server_name,
+            path: path.to_string(),
+            certificate,
+            remote,
+            local,
+            socket,
+            client: None,
+            session: None,
+            streams: HashSet::new(),
+            reassembler: Reassembler::default(),
+            timeout: None,
+            retry_at: Some(Instant::now()),
+            backoff: BACKOFF_MIN,
+            metrics,
+        })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// When `process` has to be called even if nothing arrives.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.timeout, self.retry_at) {
+            (Some(t), Some(r)) => Some(t.min(r)),
+            (t, r) => t.or(r),
+        }
+    }
+
+    /// Read what the upstream sent, drive the connection, and publish the chunks received.
+    /// Returns true when anything was published.
+    pub fn process(&mut self, publisher: &mut Publisher, now: Instant) -> io::Result<bool> {
+        if self.retry_at.map_or(false, |t| t <= now) {
+            self.connect(now);
+        }
+        let mut input = Vec::new();
+        while let Some(dgram) = crate::read_dgram(&mut self.socket, &self.local, &self.metrics)? {
+            input.push(dgram);
+        }
+        let due = self.timeout.map_or(false, |t| t <= now);
+        if input.is_empty() && !due && self.client.is_some() {
+            return Ok(false);
+        }
+        for dgram in input {
+            self.drive(Some(dgram), now);
+        }
+        self.drive(None, now);
+        let published = self.process_events(publisher, now);
+        self.drive(None, now);
+        Ok(published)
+    }
+
+    fn connect(&mut self, now: Instant) {
+        self.retry_at = None;
+        let cid_manager = Rc::new(RefCell::new(RandomConnectionIdGenerator::new(10)));
+        match Http3Client::new(
+            &self.server_name,
+            cid_manager,
+            self.local,
+            self.remote,
+            Http3Parameters::default()
+                .connection_parameters(
+                    ConnectionParameters::default().datagram_size(crate::DATAGRAM_FRAME_SIZE),
+                )
+                .webtransport(true),
+            now,
+        ) {
+            Ok(client) => {
+                qinfo!("relay: connecting to {}", self.upstream);
+                self.client = Some(client);
+            }
+            Err(err) => {
+                qerror!("relay: unable to create a client: {:?}", err);
+                self.schedule_retry(now);
+            }
+        }
+    }
+
+    // exchange packets until the client has nothing more to send.
+    fn drive(&mut self, dgram: Option<Datagram>, now: Instant) {
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return,
+        };
+        let mut dgram = dgram;
+        loop {
+            match client.process(dgram.take(), now) {
+                Output::Datagram(out) => crate::emit_packet(&mut self.socket, out, &self.metrics),
+                Output::Callback(t) => {
+                    self.timeout = Some(now + t);
+                    break;
+                }
+                Output::None => {
+                    self.timeout = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn process_events(&mut self, publisher: &mut Publisher, now: Instant) -> bool {
+        let mut published = false;
+        while let Some(event) = self.client.as_mut().and_then(Http3Client::next_event) {
+            qdebug!("relay: event {:?}", event);
+            match event {
+                Http3ClientEvent::AuthenticationNeeded => {
+                    if let Some(client) = &mut self.client {
+                        let status = verify(self.certificate.as_deref(), client);
+                        client.authenticated(status, now);
+                    }
+                }
+                Http3ClientEvent::StateChange(Http3State::Connected) => {
+                    let target = ("https", self.upstream.as_str(), self.path.as_str());
+                    if let Some(client) = &mut self.client {
+                        if let Err(err) = client.webtransport_create_session(now, &target, &[]) {
+                            qerror!("relay: unable to open a session: {:?}", err);
+                            self.lost(publisher, now);
+                        }
+                    }
+                }
+                Http3ClientEvent::StateChange(Http3State::Closing(err))
+                | Http3ClientEvent::StateChange(Http3State::Closed(err)) => {
+                    qwarn!("relay: connection to {} closed: {:?}", self.upstream, err);
+                    self.lost(publisher, now);
+                }
+                Http3ClientEvent::WebTransport(WebTransportEvent::Session {
+                    stream_id,
+                    status,
+                }) => {
+                    if status == 200 {
+                        qinfo!("relay: subscribed to {}{}", self.upstream, self.path);
+                        self.session = Some(stream_id);
+                        self.backoff = BACKOFF_MIN;
+                        publisher.start_from(Source::Upstream);
+                    } else {
+                        qwarn!("relay: upstream refused the session with {}", status);
+                        self.lost(publisher, now);
+                    }
+                }
+                Http3ClientEvent::WebTransport(WebTransportEvent::SessionClosed { .. }) => {
+                    qwarn!("relay: session to {} closed", self.upstream);
+                    self.lost(publisher, now);
+                }
+                Http3ClientEvent::WebTransport(WebTransportEvent::NewStream {
+                    stream_id,
+                    session_id,
+                }) if self.session == Some(session_id) => {
+                    self.streams.insert(stream_id);
+                }
+                Http3ClientEvent::WebTransport(WebTransportEvent::Datagram {
+                    session_id,
+                    datagram,
+                }) if self.session == Some(session_id) => {
+                    published |= self.receive_datagram(publisher, &datagram);
+                }
+                Http3ClientEvent::DataReadable { stream_id } => {
+                    published |= self.read(publisher, stream_id, now);
+                }
+                Http3ClientEvent::Reset { stream_id, .. } if self.streams.remove(&stream_id) => {
+                    publisher.stop(&Source::Upstream, stream_id);
+                }
+                _ => {}
+            }
+        }
+        published
+    }
+
+    fn read(&mut self, publisher: &mut Publisher, stream_id: StreamId, now: Instant) -> bool {
+        if !self.streams.contains(&stream_id) {
+            return false;
+        }
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return false,
+        };
+        let mut published = false;
+        let mut buf = vec![0; 4096];
+        loop {
+            match client.read_data(now, stream_id, &mut buf) {
+                Ok((0, false)) => break,
+                Ok((n, fin)) => {
+                    published |= publisher
+                        .publish_from(&Source::Upstream, stream_id, buf[..n].to_vec(), fin)
+                        .is_some();
+                    if fin {
+                        self.streams.remove(&stream_id);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    qwarn!("relay: read error on {}: {:?}", stream_id.as_u64(), err);
+                    self.streams.remove(&stream_id);
+                    publisher.stop(&Source::Upstream, stream_id);
+                    break;
+                }
+            }
+        }
+        published
+    }
+
+    fn receive_datagram(&mut self, publisher: &mut Publisher, datagram: &[u8]) -> bool {
+        let frame = self.reassembler.push(datagram);
+        self.send_nack();
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return false,
+        };
+        match Chunk::parse(frame, usize::MAX) {
+            Ok(chunk) => publisher.publish_chunk(&Source::Upstream, &chunk).is_some(),
+            Err(err) => {
+                qwarn!("relay: invalid chunk in datagrams: {}", err);
+                false
+            }
+        }
+    }
+
+    // ask the upstream for the fragments lost so far.
+    fn send_nack(&mut self) {
+        let nack = match self.reassembler.nack() {
+            Some(nack) => nack,
+            None => return,
+        };
+        if let (Some(client), Some(session)) = (&mut self.client, self.session) {
+            if let Err(err) = client.webtransport_send_datagram(session, &nack, None) {
+                qdebug!("relay: NACK not sent: {:?}", err);
+            }
+        }
+    }
+
+    fn lost(&mut self, publisher: &mut Publisher, now: Instant) {
+        if self.client.is_none() {
+            // already waiting to reconnect.
+            return;
+        }
+        if let Some(mut client) = self.client.take() {
+            client.close(now, 0, "relay reconnecting");
+            // flush the CONNECTION_CLOSE.
+            self.client = Some(client);
+            self.drive(None, now);
+            self.client = None;
+        }
+        self.timeout = None;
+        for stream_id in self.streams.drain() {
+            publisher.stop(&Source::Upstream, stream_id);
+        }
+        if self.session.take().is_some() {
+            publisher.end_from(&Source::Upstream);
+        }
+        if self.reassembler.recovered > 0 || self.reassembler.lost > 0 {
+            qinfo!(
+                "relay: {} fragments recovered, {} frames lost",
+                self.reassembler.recovered,
+                self.reassembler.lost
+            );
+        }
+        self.reassembler = Reassembler::default();
+        self.schedule_retry(now);
+    }
+
+    fn schedule_retry(&mut self, now: Instant) {
+        qinfo!(
+            "relay: reconnect to {} in {:?}",
+            self.upstream,
+            self.backoff
+        );
+        self.retry_at = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(BACKOFF_MAX);
+    }
+}
+
+/// Check the certificate of the upstream against the pinned one, if any.
+fn verify(pinned: Option<&[u8]>, client: &Http3Client) -> AuthenticationStatus {
+    let pinned = match pinned {
+        Some(pinned) => pinned,
+        None => return AuthenticationStatus::Ok,
+    };
+    // the end-entity certificate comes first.
+    let presented = client
+        .peer_certificate()
+        .and_then(|mut info| (&mut info).next().map(<[u8]>::to_vec));
+    if presented.as_deref() == Some(pinned) {
+        AuthenticationStatus::Ok
+    } else {
+        qerror!("relay: the upstream certificate isn't the one of --relay-cert");
+        AuthenticationStatus::CertUntrusted
+    }
+}
+
+/// DER of the first certificate of a PEM file.
+pub fn read_certificate(path: &Path) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate in {}", path.display()),
+        )
+    };
+    let pem = fs::read_to_string(path)?;
+    let body = pem
+        .split("-----BEGIN CERTIFICATE-----")
+        .nth(1)
+        .and_then(|rest| rest.split("-----END CERTIFICATE-----").next())
+        .ok_or_else(invalid)?;
+    let text: String = body.split_whitespace().collect();
+    base64::decode(&text).ok_or_else(invalid)
+}
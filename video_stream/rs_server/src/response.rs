@@ -0,0 +1,76 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! HTTP/3 responses whose body may not fit into the send buffer of the stream at once:
+//! the rest is sent on `DataWritable`, and the stream ends after the last byte.
+
+use neqo_common::Header;
+use neqo_http3::Http3OrWebTransportStream;
+
+/// A response whose body didn't fit into the send buffer of the stream yet.
+struct Outgoing {
+    stream: Http3OrWebTransportStream,
+    body: Vec<u8>,
+    sent: usize,
+}
+
+#[derive(Default)]
+pub struct Responses {
+    outgoing: Vec<Outgoing>,
+}
+
+impl Responses {
+    pub fn send(
+        &mut self,
+        mut stream: Http3OrWebTransportStream,
+        status: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) {
+        let _ = stream.send_headers(&[
+            Header::new(":status", status),
+            Header::new("content-type", content_type),
+            Header::new("content-length", body.len().to_string()),
+        ]);
+        self.outgoing.push(Outgoing {
+            stream,
+            body,
+            sent: 0,
+        });
+        let last = self.outgoing.len() - 1;
+        self.send_outgoing(last);
+    }
+
+    /// Continue the response on `stream` when it has room again.
+    pub fn writable(&mut self, stream: &Http3OrWebTransportStream) {
+        if let Some(i) = self.position(stream) {
+            self.send_outgoing(i);
+        }
+    }
+
+    /// Forget the response on `stream`, reset or stopped by the peer.
+    pub fn stopped(&mut self, stream: &Http3OrWebTransportStream) {
+        if let Some(i) = self.position(stream) {
+            self.outgoing.swap_remove(i);
+        }
+    }
+
+    fn position(&self, stream: &Http3OrWebTransportStream) -> Option<usize> {
+        self.outgoing.iter().position(|o| o.stream == *stream)
+    }
+
+    fn send_outgoing(&mut self, i: usize) {
+        let o = &mut self.outgoing[i];
+        match o.stream.send_data(&o.body[o.sent..]) {
+            Ok(n) => o.sent += n,
+            Err(_) => o.sent = o.body.len(),
+        }
+        if o.sent == o.body.len() {
+            let mut o = self.outgoing.swap_remove(i);
+            let _ = o.stream.stream_close_send();
+        }
+    }
+}
@@ -0,0 +1,596 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! RTP ingest (`--rtp-video`, `--rtp-audio`).
+//!
+//! H.264 (RFC 6184: single NAL unit, STAP-A and FU-A packets) received on the video
+//! address and Opus (RFC 7587) received on the audio address are turned into chunks and
+//! published to the `video` and `audio` channels, so ffmpeg or GStreamer can publish.
+//!
+//! An H.264 access unit ends at the marker bit or when the RTP timestamp changes. Its NAL
+//! units are sent in the `avcC` format (4 byte lengths) and the SPS / PPS seen in band
+//! become a config chunk (`avc1.PPCCLL`, size and `avcC` description) before the next
+//! keyframe. After a lost packet the frames are dropped until the next keyframe.
+//! Chunk timestamps count from the first packet of the stream (its SSRC). They are the
+//! presentation times of the RTP timestamps, so they go back at B-frames. The channel
+//! ends when no packet arrived for `RTP_TIMEOUT` or the SSRC changes.
+
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::net::UdpSocket;
+use neqo_common::{qinfo, qwarn};
+use serde_json::json;
+
+use crate::chunk::{Chunk, ChunkHeader, ChunkType, DecoderConfig};
+use crate::h264::{self, NAL_FU_A, NAL_IDR, NAL_PPS, NAL_SPS, NAL_STAP_A};
+use crate::metrics::Metrics;
+use crate::priority::Media;
+use crate::publisher::{Publisher, Source};
+
+pub const RTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// RTP clock rates of video (every codec) and Opus.
+pub const VIDEO_CLOCK_RATE: u32 = 90_000;
+pub const OPUS_CLOCK_RATE: u32 = 48_000;
+
+pub struct RtpPacket<'a> {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < 12 || buf[0] >> 6 != 2 {
+            return None;
+        }
+        let csrc_count = usize::from(buf[0] & 0x0f);
+        let mut pos = 12 + 4 * csrc_count;
+        if buf[0] & 0x10 != 0 {
+            // header extension
+            let words = u16::from_be_bytes([*buf.get(pos + 2)?, *buf.get(pos + 3)?]);
+            pos += 4 + 4 * usize::from(words);
+        }
+        let mut end = buf.len();
+        if buf[0] & 0x20 != 0 {
+            end = end.checked_sub(usize::from(buf[buf.len() - 1]))?;
+        }
+        Some(Self {
+            marker: buf[1] & 0x80 != 0,
+            payload_type: buf[1] & 0x7f,
+            sequence: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ssrc: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            payload: buf.get(pos..end)?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(12 + self.payload.len());
+        out.push(0x80);
+        out.push(if self.marker { 0x80 } else { 0 } | self.payload_type);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        out.extend_from_slice(self.payload);
+        out
+    }
+}
+
+/// Microseconds since the first packet, from the 32 bit RTP timestamps.
+struct Clock {
+    rate: u32,
+    // RTP timestamps extended to 64 bits.
+    first: Option<i64>,
+    last: Option<i64>,
+}
+
+impl Clock {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            first: None,
+            last: None,
+        }
+    }
+
+    fn micros(&mut self, timestamp: u32) -> i64 {
+        // the extended timestamp closest to the last one: B-frames are a little before it,
+        // even across a wraparound.
+        let extended = match self.last {
+            Some(last) => last + i64::from(timestamp.wrapping_sub(last as u32) as i32),
+            None => i64::from(timestamp),
+        };
+        self.last = Some(extended);
+        let first = *self.first.get_or_insert(extended);
+        (extended - first) * 1_000_000 / i64::from(self.rate)
+    }
+}
+
+#[derive(Default)]
+struct H264 {
+    // NAL units of the access unit being received.
+    nals: Vec<Vec<u8>>,
+    // the FU-A fragments of a NAL unit put together so far.
+    fragment: Option<Vec<u8>>,
+    timestamp: Option<i64>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    config: Option<DecoderConfig>,
+    // set until a keyframe arrives after a loss (or at the start).
+    broken: bool,
+    last_timestamp: Option<i64>,
+}
+
+impl H264 {
+    fn new() -> Self {
+        Self {
+            broken: true,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, packet: &RtpPacket, timestamp: i64, lost: bool) -> Vec<Chunk> {
+        let mut out = Vec::new();
+        if self.timestamp.map_or(false, |t| t != timestamp) {
+            // the marker bit of the previous access unit was lost.
+            out.extend(self.flush());
+        }
+        if lost {
+            self.fragment = None;
+            self.broken = true;
+        }
+        self.timestamp = Some(timestamp);
+
+        let payload = packet.payload;
+        match h264::nal_type(payload) {
+            1..=23 => self.nals.push(payload.to_vec()),
+            NAL_STAP_A => {
+                let mut pos = 1;
+                while pos + 2 <= payload.len() {
+                    let size = usize::from(u16::from_be_bytes([payload[pos], payload[pos + 1]]));
+                    match payload.get(pos + 2..pos + 2 + size) {
+                        Some(nal) => self.nals.push(nal.to_vec()),
+                        None => break,
+                    }
+                    pos += 2 + size;
+                }
+            }
+            NAL_FU_A if payload.len() > 2 => {
+                let header = payload[1];
+                if header & 0x80 != 0 {
+                    // start: rebuild the NAL header from the indicator and the type.
+                    self.fragment = Some(vec![(payload[0] & 0xe0) | (header & 0x1f)]);
+                }
+                if let Some(fragment) = &mut self.fragment {
+                    fragment.extend_from_slice(&payload[2..]);
+                }
+                if header & 0x40 != 0 {
+                    if let Some(nal) = self.fragment.take() {
+                        self.nals.push(nal);
+                    }
+                }
+            }
+            t => qwarn!("unsupported H.264 packetization {}", t),
+        }
+        if packet.marker {
+            out.extend(self.flush());
+        }
+        out
+    }
+
+    /// Chunks of the access unit received so far.
+    fn flush(&mut self) -> Vec<Chunk> {
+        let mut out = Vec::new();
+        let nals = mem::take(&mut self.nals);
+        self.fragment = None;
+        let timestamp = match self.timestamp.take() {
+            Some(t) => t,
+            None => return out,
+        };
+        let mut key = false;
+        let mut data = Vec::new();
+        for nal in nals {
+            match h264::nal_type(&nal) {
+                NAL_SPS => self.sps = Some(nal),
+                NAL_PPS => self.pps = Some(nal),
+                t => {
+                    key |= t == NAL_IDR;
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(&nal);
+                }
+            }
+        }
+        if data.is_empty() {
+            return out;
+        }
+        if key {
+            if let Some(config) = self.decoder_config() {
+                if self.config.as_ref() != Some(&config) {
+                    out.push(config.to_chunk(timestamp));
+                    self.config = Some(config);
+                }
+            }
+            self.broken = self.config.is_none();
+        }
+        if self.broken {
+            return out;
+        }
+        // the duration of a frame isn't known until the next one, use the last interval.
+        let duration = self
+            .last_timestamp
+            .map_or(0, |last| (timestamp - last).max(0) as u64);
+        self.last_timestamp = Some(timestamp);
+        out.push(Chunk::new(
+            ChunkHeader {
+                chunk_type: if key {
+                    ChunkType::Key
+                } else {
+                    ChunkType::Delta
+                },
+                timestamp,
+                duration,
+            },
+            &data,
+        ));
+        out
+    }
+
+    fn decoder_config(&self) -> Option<DecoderConfig> {
+        let (sps, pps) = (self.sps.as_ref()?, self.pps.as_ref()?);
+        let mut json = json!({ "codec": h264::codec_string(sps)? });
+        if let Some((width, height)) = h264::sps_size(sps) {
+            json["codedWidth"] = json!(width);
+            json["codedHeight"] = json!(height);
+        }
+        Some(DecoderConfig::new(json, h264::avcc(sps, pps)?))
+    }
+}
+
+#[derive(Default)]
+struct Opus {
+    config_sent: bool,
+}
+
+impl Opus {
+    fn push(&mut self, packet: &RtpPacket, timestamp: i64) -> Vec<Chunk> {
+        let mut out = Vec::new();
+        let toc = match packet.payload.first() {
+            Some(toc) => *toc,
+            None => return out,
+        };
+        if !self.config_sent {
+            let channels = if toc & 0x04 != 0 { 2 } else { 1 };
+            let json = json!({
+                "codec": "opus",
+                "sampleRate": OPUS_CLOCK_RATE,
+                "numberOfChannels": channels,
+            });
+            out.push(DecoderConfig::new(json, Vec::new()).to_chunk(timestamp));
+            self.config_sent = true;
+        }
+        out.push(Chunk::new(
+            ChunkHeader {
+                chunk_type: ChunkType::Key,
+                timestamp,
+                duration: Self::duration(packet.payload),
+            },
+            packet.payload,
+        ));
+        out
+    }
+
+    /// Duration in microseconds of an Opus packet, from its TOC byte (RFC 6716 3.1).
+    fn duration(payload: &[u8]) -> u64 {
+        let config = payload[0] >> 3;
+        let frame = match config {
+            0..=11 => [10_000, 20_000, 40_000, 60_000][usize::from(config % 4)],
+            12..=15 => [10_000, 20_000][usize::from(config % 2)],
+            _ => [2_500, 5_000, 10_000, 20_000][usize::from(config % 4)],
+        };
+        let frames = match payload[0] & 0x03 {
+            0 => 1,
+            1 | 2 => 2,
+            _ => payload.get(1).map_or(1, |b| u64::from(b & 0x3f)),
+        };
+        frame * frames
+    }
+}
+
+enum Depacketizer {
+    H264(Box<H264>),
+    Opus(Opus),
+}
+
+/// One RTP stream received on a local UDP address.
+pub struct RtpIngest {
+    media: Media,
+    socket: UdpSocket,
+    depacketizer: Depacketizer,
+    clock: Clock,
+    ssrc: Option<u32>,
+    sequence: Option<u16>,
+    last_packet: Option<Instant>,
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+impl RtpIngest {
+    /// H.264 for video, Opus for audio.
+    pub fn bind(
+        addr: &SocketAddr,
+        media: Media,
+        metrics: Rc<RefCell<Metrics>>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        qinfo!("receive RTP {:?} on {}", media, addr);
+        let (depacketizer, rate) = Self::depacketizer(media);
+        Ok(Self {
+            media,
+            socket,
+            depacketizer,
+            clock: Clock::new(rate),
+            ssrc: None,
+            sequence: None,
+            last_packet: None,
+            metrics,
+        })
+    }
+
+    fn depacketizer(media: Media) -> (Depacketizer, u32) {
+        match media {
+            Media::Video => (Depacketizer::H264(Box::new(H264::new())), VIDEO_CLOCK_RATE),
+            Media::Audio => (Depacketizer::Opus(Opus::default()), OPUS_CLOCK_RATE),
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// When the stream times out if nothing arrives.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.last_packet.map(|t| t + RTP_TIMEOUT)
+    }
+
+    /// Read the packets received and publish the chunks they complete.
+    /// Returns true when anything was sent to the members.
+    pub fn process(&mut self, publisher: &mut Publisher, now: Instant) -> io::Result<bool> {
+        let mut published = false;
+        let mut buf = vec![0; 65536];
+        loop {
+            let len = match self.socket.recv_from(&mut buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+                Ok((len, _)) => len,
+            };
+            let packet = match RtpPacket::parse(&buf[..len]) {
+                Some(packet) => packet,
+                None => {
+                    self.metrics
+                        .borrow_mut()
+                        .chunk_rejected(publisher.name, "invalid_rtp");
+                    continue;
+                }
+            };
+            if !publisher.accepts(&Source::Rtp) {
+                // a session publishes on the channel.
+                self.metrics
+                    .borrow_mut()
+                    .chunk_rejected(publisher.name, "channel_busy");
+                continue;
+            }
+            if self.ssrc != Some(packet.ssrc) {
+                if self.ssrc.is_some() {
+                    publisher.end_from(&Source::Rtp);
+                    self.reset();
+                }
+                qinfo!(
+                    "RTP {:?} stream {:08x} payload type {}",
+                    self.media,
+                    packet.ssrc,
+                    packet.payload_type
+                );
+                self.ssrc = Some(packet.ssrc);
+                publisher.start_from(Source::Rtp);
+            }
+            let lost = match self.sequence {
+                Some(last) => {
+                    let gap = packet.sequence.wrapping_sub(last);
+                    if gap == 0 || gap > 0x8000 {
+                        // duplicated or late, the frame has gone already.
+                        continue;
+                    }
+                    gap != 1
+                }
+                None => false,
+            };
+            self.sequence = Some(packet.sequence);
+            self.last_packet = Some(now);
+
+            let timestamp = self.clock.micros(packet.timestamp);
+            let chunks = match &mut self.depacketizer {
+                Depacketizer::H264(h264) => h264.push(&packet, timestamp, lost),
+                Depacketizer::Opus(opus) => opus.push(&packet, timestamp),
+            };
+            for chunk in chunks {
+                publisher.publish_chunk(&Source::Rtp, &chunk);
+                published = true;
+            }
+        }
+        if self.deadline().map_or(false, |t| t <= now) {
+            qinfo!("RTP {:?} stream timed out", self.media);
+            publisher.end_from(&Source::Rtp);
+            self.reset();
+        }
+        Ok(published)
+    }
+
+    fn reset(&mut self) {
+        let (depacketizer, rate) = Self::depacketizer(self.media);
+        self.depacketizer = depacketizer;
+        self.clock = Clock::new(rate);
+        self.ssrc = None;
+        self.sequence = None;
+        self.last_packet = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload: &[u8], marker: bool) -> RtpPacket<'_> {
+        RtpPacket {
+            marker,
+            payload_type: 96,
+            sequence: 1,
+            timestamp: 0,
+            ssrc: 0x1234_5678,
+            payload,
+        }
+    }
+
+    #[test]
+    fn parse_packet() {
+        let data = RtpPacket {
+            marker: true,
+            payload_type: 111,
+            sequence: 0xfffe,
+            timestamp: 0x8000_0001,
+            ssrc: 7,
+            payload: b"opus",
+        }
+        .to_bytes();
+        let p = RtpPacket::parse(&data).unwrap();
+        assert!(p.marker);
+        assert_eq!(p.payload_type, 111);
+        assert_eq!(p.sequence, 0xfffe);
+        assert_eq!(p.timestamp, 0x8000_0001);
+        assert_eq!(p.ssrc, 7);
+        assert_eq!(p.payload, b"opus");
+    }
+
+    #[test]
+    fn parse_csrc_extension_padding() {
+        let mut data = packet(b"", false).to_bytes();
+        // 2 CSRCs, an extension of one word and 3 bytes of padding.
+        data[0] |= 0x20 | 0x10 | 2;
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0xbe, 0xde, 0, 1, 1, 2, 3, 4]);
+        data.extend_from_slice(b"nal");
+        data.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(RtpPacket::parse(&data).unwrap().payload, b"nal");
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let data = packet(b"x", false).to_bytes();
+        assert!(RtpPacket::parse(&data[..11]).is_none());
+        let mut v1 = data.clone();
+        v1[0] = 0x40;
+        assert!(RtpPacket::parse(&v1).is_none());
+        // padding longer than the payload.
+        let mut padded = data.clone();
+        padded[0] |= 0x20;
+        *padded.last_mut().unwrap() = 200;
+        assert!(RtpPacket::parse(&padded).is_none());
+        // an extension header cut short.
+        let mut extended = data;
+        extended[0] |= 0x10;
+        assert!(RtpPacket::parse(&extended).is_none());
+    }
+
+    #[test]
+    fn clock_wraps_around() {
+        let mut clock = Clock::new(VIDEO_CLOCK_RATE);
+        let start = u32::MAX - 89_999;
+        assert_eq!(clock.micros(start), 0);
+        assert_eq!(clock.micros(u32::MAX), 999_988);
+        assert_eq!(clock.micros(90_000), 2_000_000);
+        // a B-frame from before the wraparound.
+        assert_eq!(clock.micros(u32::MAX - 2_999), 966_666);
+        assert_eq!(clock.micros(180_000), 3_000_000);
+        // and one from before the first packet.
+        let mut clock = Clock::new(VIDEO_CLOCK_RATE);
+        assert_eq!(clock.micros(9_000), 0);
+        assert_eq!(clock.micros(6_000), -33_333);
+    }
+
+    const SPS: &[u8] = &[0x67, 0x42, 0x00, 0x1f, 0xe9];
+    const PPS: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+
+    fn stap_a(nals: &[&[u8]]) -> Vec<u8> {
+        let mut out = vec![0x78];
+        for nal in nals {
+            out.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            out.extend_from_slice(nal);
+        }
+        out
+    }
+
+    #[test]
+    fn keyframe_from_stap_a_and_fu_a() {
+        let mut h264 = H264::new();
+        assert!(h264
+            .push(&packet(&stap_a(&[SPS, PPS]), false), 0, false)
+            .is_empty());
+        // an IDR slice 0x65 aa bb cc in three FU-A fragments.
+        assert!(h264
+            .push(&packet(&[0x7c, 0x85, 0xaa], false), 0, false)
+            .is_empty());
+        assert!(h264
+            .push(&packet(&[0x7c, 0x05, 0xbb], false), 0, false)
+            .is_empty());
+        let chunks = h264.push(&packet(&[0x7c, 0x45, 0xcc], true), 0, false);
+        assert_eq!(chunks.len(), 2);
+        let config = DecoderConfig::parse(&chunks[0]).unwrap();
+        assert_eq!(config.codec, "avc1.42001f");
+        assert_eq!(config.description, h264::avcc(SPS, PPS).unwrap());
+        assert!(chunks[1].is_key());
+        assert_eq!(chunks[1].payload(), &[0, 0, 0, 4, 0x65, 0xaa, 0xbb, 0xcc]);
+
+        let chunks = h264.push(&packet(&[0x41, 1], true), 33_333, false);
+        assert_eq!(chunks.len(), 1);
+        assert!(!chunks[0].is_key());
+        assert_eq!(chunks[0].header.duration, 33_333);
+        assert_eq!(chunks[0].payload(), &[0, 0, 0, 2, 0x41, 1]);
+    }
+
+    #[test]
+    fn dropped_until_keyframe() {
+        let mut h264 = H264::new();
+        // nothing without a config and a keyframe.
+        assert!(h264.push(&packet(&[0x41, 1], true), 0, false).is_empty());
+        let idr = stap_a(&[SPS, PPS, &[0x65, 1]]);
+        assert_eq!(h264.push(&packet(&idr, true), 10, false).len(), 2);
+        assert_eq!(h264.push(&packet(&[0x41, 2], true), 20, false).len(), 1);
+        // a lost packet breaks the frames until the next keyframe, the same config isn't sent again.
+        assert!(h264.push(&packet(&[0x41, 3], true), 30, true).is_empty());
+        assert!(h264.push(&packet(&[0x41, 4], true), 40, false).is_empty());
+        let chunks = h264.push(&packet(&idr, true), 50, false);
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_key());
+    }
+
+    #[test]
+    fn access_unit_ends_at_new_timestamp() {
+        let mut h264 = H264::new();
+        let idr = stap_a(&[SPS, PPS, &[0x65, 1]]);
+        // the marker bit of the keyframe was lost.
+        assert!(h264.push(&packet(&idr, false), 0, false).is_empty());
+        let chunks = h264.push(&packet(&[0x41, 2], false), 33_333, false);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].header.timestamp, 0);
+    }
+}
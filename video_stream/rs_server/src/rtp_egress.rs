@@ -0,0 +1,308 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! RTP egress (`--rtp-out-video`, `--rtp-out-audio`).
+//!
+//! Every chunk of the default layer of a channel is packetized as RTP and sent to a UDP
+//! address: H.264 (RFC 6184, single NAL unit and FU-A packets, with the SPS and PPS sent
+//! again before each keyframe), VP8 (RFC 7741, what browsers publish) or Opus (RFC 7587).
+//! `{rtp-out-dir}/{channel}.sdp` describes the stream and is written again whenever the
+//! decoder config changes, so ffmpeg or GStreamer can receive it without WebTransport.
+//! Chunks published before the first config, or with another codec, are not sent.
+
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+
+use neqo_common::{qerror, qinfo, qwarn};
+use neqo_crypto::random;
+use serde_json::Value;
+
+use crate::base64;
+use crate::chunk::{Chunk, DecoderConfig};
+use crate::h264::{self, NAL_FU_A};
+use crate::priority::Media;
+use crate::rtp::{RtpPacket, OPUS_CLOCK_RATE, VIDEO_CLOCK_RATE};
+
+/// Payload size that fits into a 1500 bytes MTU with room for tunnels.
+const MAX_PAYLOAD: usize = 1200;
+
+const PAYLOAD_TYPE: u8 = 96;
+
+enum Codec {
+    H264 {
+        // `None` when the samples are in Annex B format (no description).
+        length_size: Option<usize>,
+        // SPS and PPS of the description, sent before each keyframe.
+        parameter_sets: Vec<Vec<u8>>,
+        fmtp: String,
+    },
+    Vp8,
+    Opus {
+        channels: u64,
+    },
+}
+
+impl Codec {
+    fn of(config: &DecoderConfig) -> Option<Self> {
+        let codec = config.codec.as_str();
+        if codec.starts_with("avc1.") || codec.starts_with("avc3.") {
+            let (length_size, parameter_sets) = if config.description.is_empty() {
+                (None, Vec::new())
+            } else {
+                let avcc = h264::parse_avcc(&config.description)?;
+                (
+                    Some(avcc.length_size),
+                    avcc.sps.into_iter().chain(avcc.pps).collect(),
+                )
+            };
+            let mut fmtp = format!("packetization-mode=1;profile-level-id={}", &codec[5..]);
+            if !parameter_sets.is_empty() {
+                let sets: Vec<_> = parameter_sets.iter().map(|s| base64::encode(s)).collect();
+                fmtp.push_str(&format!(";sprop-parameter-sets={}", sets.join(",")));
+            }
+            Some(Self::H264 {
+                length_size,
+                parameter_sets,
+                fmtp,
+            })
+        } else if codec == "vp8" {
+            Some(Self::Vp8)
+        } else if codec == "opus" {
+            let channels = config
+                .json
+                .get("numberOfChannels")
+                .and_then(Value::as_u64)
+                .unwrap_or(2);
+            Some(Self::Opus { channels })
+        } else {
+            None
+        }
+    }
+
+    /// The `m=` section of the SDP.
+    fn media(&self, port: u16) -> String {
+        match self {
+            Self::H264 { fmtp, .. } => format!(
+                "m=video {port} RTP/AVP {pt}\r\na=rtpmap:{pt} H264/{rate}\r\na=fmtp:{pt} {fmtp}\r\n",
+                port = port,
+                pt = PAYLOAD_TYPE,
+                rate = VIDEO_CLOCK_RATE,
+                fmtp = fmtp
+            ),
+            Self::Vp8 => format!(
+                "m=video {port} RTP/AVP {pt}\r\na=rtpmap:{pt} VP8/{rate}\r\n",
+                port = port,
+                pt = PAYLOAD_TYPE,
+                rate = VIDEO_CLOCK_RATE
+            ),
+            Self::Opus { channels } => format!(
+                "m=audio {port} RTP/AVP {pt}\r\na=rtpmap:{pt} opus/{rate}/2\r\na=fmtp:{pt} sprop-stereo={stereo}\r\n",
+                port = port,
+                pt = PAYLOAD_TYPE,
+                rate = OPUS_CLOCK_RATE,
+                stereo = u8::from(*channels > 1)
+            ),
+        }
+    }
+
+    /// RTP payloads of a frame, the last one gets the marker bit.
+    fn packetize(&self, chunk: &Chunk) -> Vec<Vec<u8>> {
+        let payload = chunk.payload();
+        match self {
+            Self::H264 {
+                length_size,
+                parameter_sets,
+                ..
+            } => {
+                let mut nals: Vec<&[u8]> = Vec::new();
+                if chunk.is_key() {
+                    nals.extend(parameter_sets.iter().map(Vec::as_slice));
+                }
+                match length_size {
+                    Some(size) => nals.extend(h264::split_length_prefixed(payload, *size)),
+                    None => nals.extend(h264::split_annexb(payload)),
+                }
+                let mut out = Vec::new();
+                for nal in nals {
+                    if nal.len() <= MAX_PAYLOAD {
+                        out.push(nal.to_vec());
+                        continue;
+                    }
+                    let indicator = (nal[0] & 0xe0) | NAL_FU_A;
+                    let parts: Vec<_> = nal[1..].chunks(MAX_PAYLOAD - 2).collect();
+                    for (i, part) in parts.iter().enumerate() {
+                        let mut header = h264::nal_type(nal);
+                        if i == 0 {
+                            header |= 0x80;
+                        }
+                        if i + 1 == parts.len() {
+                            header |= 0x40;
+                        }
+                        let mut packet = vec![indicator, header];
+                        packet.extend_from_slice(part);
+                        out.push(packet);
+                    }
+                }
+                out
+            }
+            Self::Vp8 => payload
+                .chunks(MAX_PAYLOAD - 1)
+                .enumerate()
+                .map(|(i, part)| {
+                    // payload descriptor, S bit on the first packet of the frame.
+                    let mut packet = vec![if i == 0 { 0x10 } else { 0 }];
+                    packet.extend_from_slice(part);
+                    packet
+                })
+                .collect(),
+            Self::Opus { .. } => vec![payload.to_vec()],
+        }
+    }
+}
+
+pub struct RtpEgress {
+    channel: &'static str,
+    media: Media,
+    socket: UdpSocket,
+    dest: SocketAddr,
+    sdp_path: PathBuf,
+    ssrc: u32,
+    sequence: u16,
+    // random offset of the RTP timestamps.
+    base: u32,
+    codec: Option<Codec>,
+}
+
+/// A timestamp in microseconds in ticks of a `rate` Hz clock, wrapping around as RTP
+/// timestamps do. Any `i64` fits: a publisher chooses its timestamps.
+fn rtp_ticks(timestamp: i64, rate: u32) -> u32 {
+    (i128::from(timestamp) * i128::from(rate) / 1_000_000) as u32
+}
+
+impl RtpEgress {
+    pub fn new(channel: &'static str, dest: SocketAddr, sdp_dir: &Path) -> io::Result<Self> {
+        let bind = if dest.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        let sdp_path = sdp_dir.join(format!("{}.sdp", channel));
+        let r = random(10);
+        qinfo!(
+            "send {} over RTP to {}, described by {}",
+            channel,
+            dest,
+            sdp_path.display()
+        );
+        Ok(Self {
+            channel,
+            media: Media::of_channel(channel),
+            socket,
+            dest,
+            sdp_path,
+            ssrc: u32::from_be_bytes([r[0], r[1], r[2], r[3]]),
+            sequence: u16::from_be_bytes([r[4], r[5]]),
+            base: u32::from_be_bytes([r[6], r[7], r[8], r[9]]),
+            codec: None,
+        })
+    }
+
+    /// Send a chunk accepted by the publisher.
+    pub fn send(&mut self, chunk: &Chunk) {
+        if chunk.is_config() {
+            self.configure(chunk);
+            return;
+        }
+        let payloads = match &self.codec {
+            Some(codec) => codec.packetize(chunk),
+            None => return,
+        };
+        let rate = match self.media {
+            Media::Video => VIDEO_CLOCK_RATE,
+            Media::Audio => OPUS_CLOCK_RATE,
+        };
+        let timestamp = self
+            .base
+            .wrapping_add(rtp_ticks(chunk.header.timestamp, rate));
+        let last = payloads.len().saturating_sub(1);
+        for (i, payload) in payloads.iter().enumerate() {
+            let packet = RtpPacket {
+                marker: self.media == Media::Video && i == last,
+                payload_type: PAYLOAD_TYPE,
+                sequence: self.sequence,
+                timestamp,
+                ssrc: self.ssrc,
+                payload,
+            };
+            self.sequence = self.sequence.wrapping_add(1);
+            if let Err(err) = self.socket.send_to(&packet.to_bytes(), self.dest) {
+                // nobody listening, or the socket buffer is full: the packet is lost.
+                qwarn!(
+                    "{}: RTP send to {} failed: {}",
+                    self.channel,
+                    self.dest,
+                    err
+                );
+            }
+        }
+    }
+
+    fn configure(&mut self, chunk: &Chunk) {
+        let config = match DecoderConfig::parse(chunk) {
+            Ok(config) => config,
+            Err(_) => return,
+        };
+        self.codec = Codec::of(&config);
+        let codec = match &self.codec {
+            Some(codec) => codec,
+            None => {
+                qwarn!("{}: {} can't be sent over RTP", self.channel, config.codec);
+                return;
+            }
+        };
+        let ip = self.dest.ip();
+        let family = if ip.is_ipv4() { "IP4" } else { "IP6" };
+        let sdp = format!(
+            "v=0\r\no=- {ssrc} {ssrc} IN {family} {ip}\r\ns={channel}\r\nc=IN {family} {ip}\r\nt=0 0\r\n{media}",
+            ssrc = self.ssrc,
+            family = family,
+            ip = ip,
+            channel = self.channel,
+            media = codec.media(self.dest.port())
+        );
+        if let Err(err) = fs::write(&self.sdp_path, sdp) {
+            qerror!("unable to write {}: {}", self.sdp_path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks() {
+        assert_eq!(rtp_ticks(0, VIDEO_CLOCK_RATE), 0);
+        assert_eq!(rtp_ticks(1_000_000, VIDEO_CLOCK_RATE), 90_000);
+        assert_eq!(rtp_ticks(20_000, OPUS_CLOCK_RATE), 960);
+        // past 2^32 ticks, about 13 hours at 90 kHz.
+        assert_eq!(
+            rtp_ticks(50_000_000_000, VIDEO_CLOCK_RATE),
+            (4_500_000_000u64 - (1 << 32)) as u32
+        );
+        assert_eq!(
+            rtp_ticks(-1_000_000, VIDEO_CLOCK_RATE),
+            90_000u32.wrapping_neg()
+        );
+        // no overflow for the largest timestamps.
+        rtp_ticks(i64::MAX, VIDEO_CLOCK_RATE);
+        rtp_ticks(i64::MIN, OPUS_CLOCK_RATE);
+    }
+}
@@ -0,0 +1,515 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Replay of recorded broadcasts (see `recorder`) to viewers.
+//!
+//! A viewer asks for a recording with `/video/view?vod={start}&offset={ms}`,
+//! where `start` is the directory name of the broadcast (or `latest`) and `offset`
+//! is the position to start from in milliseconds. Chunks are sent at the pace of
+//! their timestamps, starting from the last keyframe at or before the offset.
+//!
+//! The viewer may open a bidirectional stream on the session and send one command per line:
+//! `pause`, `play` or `seek {ms}`. Each command is answered with `{state} {position ms}`,
+//! and `end {position ms}` is written once the recording is over. A control stream sending
+//! a line longer than `MAX_CONTROL_LINE` is reset.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use neqo_common::{qdebug, qerror, qinfo, qwarn};
+use neqo_http3::{Error, Http3OrWebTransportStream, WebTransportRequest};
+use neqo_transport::server::ActiveConnectionRef;
+
+use crate::chunk::Chunk;
+use crate::metrics::Metrics;
+use crate::priority::{Media, StreamPriority};
+use crate::publisher::Publisher;
+use crate::recorder::{segment_name, INDEX_FILE};
+
+/// A recording whose start is this close to the requested one is taken for it,
+/// so that the audio of a broadcast is found from the start of its video.
+const START_TOLERANCE_MS: u64 = 10_000;
+
+/// Largest record read from a segment, far above `--max-chunk-size`: a longer one means a
+/// corrupt file.
+const MAX_RECORD_SIZE: usize = 64 << 20;
+
+/// Longest command line of a control stream.
+const MAX_CONTROL_LINE: usize = 1024;
+
+/// The directory of the recording `id` of a channel, `latest` being the last one.
+pub fn find(channel_dir: &Path, id: &str) -> Option<PathBuf> {
+    let starts: Vec<u64> = fs::read_dir(channel_dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.parse().ok()))
+        .collect();
+    let start = if id == "latest" {
+        starts.iter().max().copied()
+    } else {
+        let id: u64 = id.parse().ok()?;
+        starts
+            .iter()
+            .copied()
+            .filter(|s| (*s as i128 - id as i128).abs() <= i128::from(START_TOLERANCE_MS))
+            .min_by_key(|s| (*s as i128 - id as i128).abs())
+    }?;
+    Some(channel_dir.join(start.to_string()))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    timestamp: i64,
+    segment: u32,
+    offset: u64,
+    key: bool,
+}
+
+fn read_index(dir: &Path) -> io::Result<Vec<IndexEntry>> {
+    let file = File::open(dir.join(INDEX_FILE))?;
+    let mut index = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split(' ').collect();
+        if let [timestamp, segment, offset, kind] = fields[..] {
+            if let (Ok(timestamp), Ok(segment), Ok(offset)) =
+                (timestamp.parse(), segment.parse(), offset.parse())
+            {
+                index.push(IndexEntry {
+                    timestamp,
+                    segment,
+                    offset,
+                    key: kind == "key",
+                });
+                continue;
+            }
+        }
+        qwarn!("{}: broken index line {:?}", dir.display(), line);
+    }
+    Ok(index)
+}
+
+/// Fill `buf`, false when the file ends before.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Read one `length + received_at + chunk` record, `None` at the end of the segment.
+/// A record cut by the end of the file is taken for the end too: the recorder may still be
+/// writing it.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Chunk>> {
+    // length and received_at.
+    let mut head = [0u8; 12];
+    if !read_full(reader, &mut head)? {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record of {} bytes", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    if !read_full(reader, &mut data)? {
+        return Ok(None);
+    }
+    Chunk::parse(data, usize::MAX)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+/// Sequential reader of the segments of a recording.
+pub struct RecordingReader {
+    dir: PathBuf,
+    index: Vec<IndexEntry>,
+    segment_no: u32,
+    segment: Option<BufReader<File>>,
+}
+
+impl RecordingReader {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        let index = read_index(&dir)?;
+        let mut reader = Self {
+            dir,
+            index,
+            segment_no: 0,
+            segment: None,
+        };
+        reader.open_segment(0, 0)?;
+        Ok(reader)
+    }
+
+    fn open_segment(&mut self, n: u32, offset: u64) -> io::Result<()> {
+        let mut file = File::open(self.dir.join(segment_name(n)))?;
+        file.seek(SeekFrom::Start(offset))?;
+        self.segment_no = n;
+        self.segment = Some(BufReader::new(file));
+        Ok(())
+    }
+
+    /// Timestamp of the start of the recording.
+    pub fn origin(&self) -> i64 {
+        self.index.first().map_or(0, |e| e.timestamp)
+    }
+
+    /// Move to the last keyframe at or before `timestamp`.
+    /// Returns the decoder config in effect there and the timestamp of the keyframe.
+    pub fn seek(&mut self, timestamp: i64) -> io::Result<(Option<Chunk>, i64)> {
+        let pos = self
+            .index
+            .iter()
+            .rposition(|e| e.key && e.timestamp <= timestamp)
+            .or_else(|| self.index.iter().position(|e| e.key));
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                self.open_segment(0, 0)?;
+                return Ok((None, self.origin()));
+            }
+        };
+        let key = self.index[pos];
+        let config = match self.index[..pos].iter().rev().find(|e| !e.key) {
+            Some(e) => {
+                let mut file = File::open(self.dir.join(segment_name(e.segment)))?;
+                file.seek(SeekFrom::Start(e.offset))?;
+                read_record(&mut file)?
+            }
+            None => None,
+        };
+        self.open_segment(key.segment, key.offset)?;
+        Ok((config, key.timestamp))
+    }
+
+    /// The next chunk, `None` at the end of the recording.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Chunk>> {
+        loop {
+            let segment = match &mut self.segment {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            if let Some(chunk) = read_record(segment)? {
+                return Ok(Some(chunk));
+            }
+            let next = self.segment_no + 1;
+            if !self.dir.join(segment_name(next)).exists() {
+                self.segment = None;
+                return Ok(None);
+            }
+            self.open_segment(next, 0)?;
+        }
+    }
+}
+
+/// One viewer of a recording.
+struct Replay {
+    channel: &'static str,
+    session: WebTransportRequest,
+    reader: RecordingReader,
+    // timestamp of the last chunk sent.
+    position: i64,
+    // when playing, the instant the chunk at this timestamp was due.
+    clock: Option<(Instant, i64)>,
+    // the chunk read ahead, waiting for its time.
+    pending: Option<Chunk>,
+    // the last decoder config sent, repeated configs of each segment are skipped.
+    config: Option<Chunk>,
+    // timestamp of the last keyframe, orders the streams of its group.
+    group: i64,
+    control: Option<Http3OrWebTransportStream>,
+    control_buf: Vec<u8>,
+    ended: bool,
+    // chunks sent so far.
+    sent: u64,
+}
+
+impl Replay {
+    fn position_ms(&self) -> i64 {
+        self.position.saturating_sub(self.reader.origin()) / 1000
+    }
+
+    fn state(&self) -> &'static str {
+        if self.ended {
+            "end"
+        } else if self.clock.is_some() {
+            "playing"
+        } else {
+            "paused"
+        }
+    }
+
+    fn reply(&mut self) {
+        let line = format!("{} {}\n", self.state(), self.position_ms());
+        if let Some(control) = &mut self.control {
+            let _ = control.send_data(line.as_bytes());
+        }
+    }
+
+    fn seek(&mut self, offset_ms: i64, metrics: &mut Metrics) -> io::Result<()> {
+        let (config, timestamp) = self
+            .reader
+            .seek(self.reader.origin() + offset_ms.saturating_mul(1000))?;
+        self.pending = None;
+        self.ended = false;
+        self.position = timestamp;
+        self.group = timestamp;
+        if self.clock.is_some() {
+            self.clock = Some((Instant::now(), timestamp));
+        }
+        if let Some(config) = config {
+            self.send(config, metrics);
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, chunk: Chunk, metrics: &mut Metrics) {
+        if chunk.is_config() {
+            if self
+                .config
+                .as_ref()
+                .map_or(false, |c| c.as_bytes() == chunk.as_bytes())
+            {
+                return;
+            }
+            self.config = Some(chunk.clone());
+        } else {
+            self.position = chunk.header.timestamp;
+            if chunk.is_key() {
+                self.group = chunk.header.timestamp;
+            }
+        }
+        let priority = StreamPriority::of_chunk(Media::of_channel(self.channel), &chunk, self.group);
+        Publisher::send(&mut self.session, &chunk, None, priority, self.channel, metrics);
+        self.sent += 1;
+    }
+
+    /// Send every chunk that is due and return when the next one is.
+    fn process(&mut self, now: Instant, metrics: &mut Metrics) -> io::Result<Option<Instant>> {
+        let (started, start_ts) = match self.clock {
+            Some(clock) if !self.ended => clock,
+            _ => return Ok(None),
+        };
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.reader.next_chunk()?;
+            }
+            let chunk = match self.pending.take() {
+                Some(chunk) => chunk,
+                None => {
+                    qinfo!("end of recording {}", self.reader.dir.display());
+                    self.ended = true;
+                    self.reply();
+                    return Ok(None);
+                }
+            };
+            let ahead = chunk.header.timestamp.saturating_sub(start_ts).max(0) as u64;
+            let due = started + Duration::from_micros(ahead);
+            if chunk.is_config() || due <= now {
+                self.send(chunk, metrics);
+            } else {
+                self.pending = Some(chunk);
+                return Ok(Some(due));
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, metrics: &mut Metrics) -> io::Result<()> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next().map(str::parse::<i64>)) {
+            (Some("pause"), None) => self.clock = None,
+            (Some("play"), None) => {
+                if self.clock.is_none() {
+                    self.clock = Some((Instant::now(), self.position));
+                }
+            }
+            (Some("seek"), Some(Ok(ms))) => self.seek(ms, metrics)?,
+            _ => {
+                qwarn!("unknown vod command {:?}", line);
+                if let Some(control) = &mut self.control {
+                    let _ = control.send_data(format!("error {}\n", line).as_bytes());
+                }
+                return Ok(());
+            }
+        }
+        qdebug!("vod command {:?}", line);
+        self.reply();
+        Ok(())
+    }
+}
+
+/// Viewers of recorded broadcasts.
+pub struct Vod {
+    record_dir: Option<PathBuf>,
+    replays: HashMap<ActiveConnectionRef, Replay>,
+    metrics: Rc<RefCell<Metrics>>,
+}
+
+impl Vod {
+    pub fn new(record_dir: Option<PathBuf>, metrics: Rc<RefCell<Metrics>>) -> Self {
+        Self {
+            record_dir,
+            replays: HashMap::new(),
+            metrics,
+        }
+    }
+
+    /// Open the recording `id` of `channel`.
+    pub fn open(&self, channel: &str, id: &str) -> io::Result<RecordingReader> {
+        let dir = self
+            .record_dir
+            .as_ref()
+            .and_then(|d| find(&d.join(channel), id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such recording"))?;
+        RecordingReader::open(dir)
+    }
+
+    /// Start replaying a recording of `channel` from `offset_ms`.
+    pub fn subscribe(
+        &mut self,
+        channel: &'static str,
+        session: WebTransportRequest,
+        reader: RecordingReader,
+        offset_ms: i64,
+    ) -> io::Result<()> {
+        qinfo!("replay {} from {}ms", reader.dir.display(), offset_ms);
+        let mut replay = Replay {
+            channel,
+            session: session.clone(),
+            reader,
+            position: 0,
+            clock: Some((Instant::now(), 0)),
+            pending: None,
+            config: None,
+            group: 0,
+            control: None,
+            control_buf: Vec::new(),
+            ended: false,
+            sent: 0,
+        };
+        replay.seek(offset_ms, &mut self.metrics.borrow_mut())?;
+        self.replays.insert(session.conn.clone(), replay);
+        Ok(())
+    }
+
+    pub fn leave(&mut self, conn: &ActiveConnectionRef) {
+        self.replays.remove(conn);
+    }
+
+    /// Whether the session watches a recording rather than the live channel.
+    pub fn contains(&self, conn: &ActiveConnectionRef) -> bool {
+        self.replays.contains_key(conn)
+    }
+
+    /// Data of the control stream of a replay.
+    pub fn control(&mut self, stream: Http3OrWebTransportStream, data: &[u8]) {
+        let replay = match self.replays.get_mut(&stream.conn) {
+            Some(r) => r,
+            None => return,
+        };
+        replay.control = Some(stream);
+        replay.control_buf.extend_from_slice(data);
+        while let Some(eol) = replay.control_buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = replay.control_buf.drain(..=eol).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Err(err) = replay.command(line.trim(), &mut self.metrics.borrow_mut()) {
+                qerror!("vod command failed: {}", err);
+                replay.ended = true;
+                replay.reply();
+            }
+        }
+        if replay.control_buf.len() > MAX_CONTROL_LINE {
+            qwarn!("vod control line longer than {} bytes", MAX_CONTROL_LINE);
+            replay.control_buf.clear();
+            if let Some(mut control) = replay.control.take() {
+                let _ = control.stream_stop_sending(Error::HttpRequestCancelled.code());
+                let _ = control.stream_reset_send(Error::HttpRequestCancelled.code());
+            }
+        }
+    }
+
+    /// Send the chunks that are due.
+    /// Returns whether anything was sent and when the next chunk is due.
+    pub fn process(&mut self, now: Instant) -> (bool, Option<Instant>) {
+        let mut next: Option<Instant> = None;
+        let mut metrics = self.metrics.borrow_mut();
+        let mut sent = false;
+        for replay in self.replays.values_mut() {
+            let before = replay.sent;
+            let result = replay.process(now, &mut metrics);
+            sent |= replay.sent != before;
+            match result {
+                Ok(Some(due)) => next = Some(next.map_or(due, |n| n.min(due))),
+                Ok(None) => {}
+                Err(err) => {
+                    qerror!("replay {} failed: {}", replay.reader.dir.display(), err);
+                    replay.ended = true;
+                    replay.reply();
+                }
+            }
+        }
+        (sent, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkHeader, ChunkType};
+    use std::io::Cursor;
+
+    fn record(chunk: &Chunk) -> Vec<u8> {
+        let mut data = (chunk.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&7u64.to_be_bytes());
+        data.extend_from_slice(chunk.as_bytes());
+        data
+    }
+
+    fn chunk(timestamp: i64) -> Chunk {
+        let header = ChunkHeader {
+            chunk_type: ChunkType::Key,
+            timestamp,
+            duration: 0,
+        };
+        Chunk::new(header, b"frame")
+    }
+
+    #[test]
+    fn records() {
+        let mut data = record(&chunk(1));
+        data.extend(record(&chunk(2)));
+        let mut reader = Cursor::new(data);
+        let timestamps: Vec<_> = std::iter::from_fn(|| read_record(&mut reader).unwrap())
+            .map(|c| c.header.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![1, 2]);
+    }
+
+    #[test]
+    fn truncated_record_ends_the_segment() {
+        let data = record(&chunk(1));
+        for len in [0, 3, 4, 11, 12, data.len() - 1] {
+            let mut reader = Cursor::new(&data[..len]);
+            assert!(read_record(&mut reader).unwrap().is_none(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn oversized_record() {
+        let mut data = u32::MAX.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 8]);
+        let err = read_record(&mut Cursor::new(data)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -60,7 +60,9 @@ async function connect() {
         stream: audioStream,
       },
     };
-    streamWorker.postMessage({ type: "connect", url, media}, [frameStream, audioStream]);
+    const combined = document.getElementById('combined').checked;
+    const layers = parseInt(document.getElementById('layers').value);
+    streamWorker.postMessage({ type: "connect", url, media, combined, layers}, [frameStream, audioStream]);
   ;}
   video.onended = () => {
     streamWorker.postMessage({ type: "stop" });
@@ -74,6 +76,10 @@ async function connect() {
     command: "enter",
     name: document.getElementById('name').value,
   });
+  // 部屋の参加者一覧を要求する。以降は差分が届く
+  sendTextData(wt_chat, {
+    command: "roster",
+  });
 
   // set send comment button.
   document.getElementById('send').onclick = async function sendComment() {
@@ -130,24 +136,37 @@ async function acceptUnidirectionalStreams(transport) {
   }
 }
 
+// Rustサーバーはトピックのメッセージ (topic_len(1) + topic + json) で送ってくる
+function stripTopic(bytes) {
+  const len = bytes[0];
+  const topic = new TextDecoder('utf-8').decode(bytes.subarray(1, 1 + len));
+  if (topic.startsWith('presence/') || topic.startsWith('chat/')) {
+    return bytes.subarray(1 + len);
+  }
+  return bytes;
+}
+
 // データを読み込む
 async function readFromIncomingStream(stream) {
-  let decoder = new TextDecoderStream('utf-8');
-  let reader = stream.pipeThrough(decoder).getReader();
+  let reader = stream.getReader();
   try {
     let buffer = new Uint8Array();
     while (true) {
       const { value, done } = await reader.read();
       if (done) {
-        // ここではvalueは空になる
+        // ここではvalueはundefinedになる
         //
         // jsonでデータをやり取りする
-        let data = JSON.parse(buffer);
-        addCommentViewer(data);
+        let text = new TextDecoder('utf-8').decode(stripTopic(buffer));
+        addToEventLog('Received data on stream : ' + text);
+        let data = JSON.parse(text);
+        handleChatMessage(data);
         return;
       }
-      buffer += value;
-      addToEventLog('Received data on stream : ' + value);
+      let joined = new Uint8Array(buffer.byteLength + value.byteLength);
+      joined.set(buffer, 0);
+      joined.set(new Uint8Array(value), buffer.byteLength);
+      buffer = joined;
     }
   } catch (e) {
     addToEventLog(
@@ -173,6 +192,38 @@ function addToEventLog(text, severity = 'info') {
     entry.scrollIntoView();
   }
 }
+// 部屋の参加者 (id -> {id, name, role, joined})
+const roster = new Map();
+
+// コメント以外に参加者一覧 (roster) と入退室・名前変更の差分 (presence) が届く
+function handleChatMessage(data) {
+  if (data.command === 'roster') {
+    roster.clear();
+    data.members.forEach(member => roster.set(member.id, member));
+    renderRoster();
+  } else if (data.command === 'presence') {
+    if (data.event === 'leave') {
+      roster.delete(data.member.id);
+    } else {
+      roster.set(data.member.id, data.member);
+    }
+    renderRoster();
+  } else {
+    addCommentViewer(data);
+  }
+}
+
+function renderRoster() {
+  let list = document.getElementById('roster');
+  list.innerHTML = '';
+  for (const member of roster.values()) {
+    let entry = document.createElement('li');
+    const joined = new Date(member.joined).toLocaleTimeString();
+    entry.innerText = (member.name || '(' + member.id + ')') + ' : ' + member.role + ' ' + joined;
+    list.appendChild(entry);
+  }
+}
+
 function addCommentViewer(data) {
   let comments = document.getElementById('comment-viewer');
   let mostRecentEntry = comments.lastElementChild;
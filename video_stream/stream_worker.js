@@ -1,16 +1,30 @@
 let stopped = false;
 let wt_video = null;
 let wt_audio = null;
+// 1つのセッションで送るときの track id
+let track_video = null;
+let track_audio = null;
+// サイマルキャストのレイヤー数 (1 ならサイマルキャストしない)
+let layers = 1;
 
 self.addEventListener('message', async (e) => {
   const type = e.data.type;
 
   if (type === "connect") {
     stopped = false;
-    const {media: {video, audio}, url} = e.data;
+    const {media: {video, audio}, url, combined} = e.data;
+    layers = combined ? 1 : (e.data.layers || 1);
 
-    wt_video = new WebTransport(url + '/video/stream');
-    wt_audio = new WebTransport(url + '/audio/stream');
+    if (combined) {
+      // 映像と音声を1つのセッションで送り、先頭の track id で区別する
+      wt_video = wt_audio = new WebTransport(url + '/av/stream');
+      track_video = 1;
+      track_audio = 2;
+    } else {
+      wt_video = new WebTransport(url + '/video/stream' + (layers > 1 ? '?simulcast=1' : ''));
+      wt_audio = new WebTransport(url + '/audio/stream');
+      track_video = track_audio = null;
+    }
     await wt_video.ready;
     await wt_audio.ready;
     wt_video.closed.then(() => {
@@ -34,7 +48,9 @@ self.addEventListener('message', async (e) => {
   if (type === "stop") {
     stopped = true;
     wt_video.close();
-    wt_audio.close();
+    if (wt_audio !== wt_video) {
+      wt_audio.close();
+    }
   }
 
 }, false)
@@ -46,12 +62,59 @@ async function streamVideo(video) {
   self.postMessage('Start video frame encode.');
   
   let encodedFrameCount = 0;
+  // レイヤーごとにエンコーダを作る。レイヤー0が元の解像度で、1つ下がるごとに半分になる
+  let encoders = [];
+  for (let layer = 0; layer < layers; layer++) {
+    encoders.push(createVideoEncoder(video, layer, () => encodedFrameCount++));
+  }
+
+  let frameCount = 0;
+  try {
+    while(true) {
+      if (stopped) {
+        frameReader.close();
+        encoders.forEach((encoder) => encoder.close());
+        self.postMessage("frame stream stopped.");
+        break;
+      }
+      const {value, done} = await frameReader.read();
+      if (done) {
+        self.postMessage("frame stream ended.");
+        break;
+      }
+      var frame = value;
+      // どのレイヤーも同じフレームでキーフレームにして、切り替えられる位置をそろえる
+      encoders.forEach((encoder) => encoder.encode(frame, {keyFrame: (frameCount % 30 == 0 ? true : false)}));
+      frame.close();
+     if (frameCount++ % 150 == 0) {
+        self.postMessage(`Read 150 frames. last frame = ${frameCount - encodedFrameCount}`);
+      }
+    }
+  } catch (e) {
+    self.postMessage('Video frame read failed. ' + e);
+  }
+  wt_video.close();
+}
+
+// 1レイヤー分のエンコーダ。サイマルキャストのときは先頭に layer id(1byte) を付けて送る
+function createVideoEncoder(video, layer, onEncoded) {
+  const layerId = layers > 1 ? layer : null;
+  let encodedFrameCount = 0;
+  // フレームは config を書き終えてから送る (別ストリームなので順序が入れ替わらないように)
+  let configSent = Promise.resolve();
   let encoder = new VideoEncoder({
-      output: (chunk) => {
+      output: (chunk, metadata) => {
         // 1フレーム送信する (分割・結合はQUICにお任せする)
         if (stopped) {
           return;
         }
+        onEncoded();
+        // デコーダ設定が変わったらフレームより先に送る
+        if (metadata && metadata.decoderConfig) {
+          const config = withTrack(track_video, withTrack(layerId, encodeDecoderConfig(metadata.decoderConfig, chunk.timestamp)));
+          configSent = configSent.then(() => sendBinaryData(wt_video, config))
+            .catch((e) => self.postMessage('config send failed. ' + e));
+        }
 
         // header(17) = type(1byte) + timestamp(8) + duration(8)
         let payload = new ArrayBuffer(17 + chunk.byteLength);
@@ -62,49 +125,27 @@ async function streamVideo(video) {
         chunk.copyTo(new DataView(payload, 17));
 
         // フレームを送信する
-        sendBinaryData(wt_video, payload);
+        const data = withTrack(track_video, withTrack(layerId, payload));
+        configSent.then(() => sendBinaryData(wt_video, data));
 
         if (encodedFrameCount++ % 30 == 0) {
-          self.postMessage(`Video Encode 30 frames and send chunk. ${frameCount - encodedFrameCount} ${chunk.type} size ${chunk.byteLength} ${chunk.timestamp} ${chunk.duration}`)
+          self.postMessage(`Video Encode 30 frames and send chunk. layer ${layer} ${chunk.type} size ${chunk.byteLength} ${chunk.timestamp} ${chunk.duration}`)
         }
       },
       error: (e) => {
         self.postMessage("encoding error. " + e.message)
       }
     });
+  // 偶数にしないとエンコーダが受け付けない
+  const scale = 1 << layer;
   encoder.configure({
     codec: 'vp8', // これしか使えない
-    width: video.width,
-    height: video.height,
+    width: Math.floor(video.width / scale / 2) * 2,
+    height: Math.floor(video.height / scale / 2) * 2,
     framerate: 1,
     latencyMode: "realtime",
   });
-
-  let frameCount = 0;
-  try {
-    while(true) {
-      if (stopped) {
-        frameReader.close();
-        encoder.close();
-        self.postMessage("frame stream stopped.");
-        break;
-      }
-      const {value, done} = await frameReader.read();
-      if (done) {
-        self.postMessage("frame stream ended.");
-        break;
-      }
-      var frame = value;
-      encoder.encode(frame, {keyFrame: (frameCount % 30 == 0 ? true : false)});
-      frame.close();
-     if (frameCount++ % 150 == 0) {
-        self.postMessage(`Read 150 frames. last frame = ${frameCount - encodedFrameCount}`);
-      }
-    }
-  } catch (e) {
-    self.postMessage('Video frame read failed. ' + e);
-  }
-  wt_video.close();
+  return encoder;
 }
 
 async function streamAudio(audio) {
@@ -112,12 +153,20 @@ async function streamAudio(audio) {
   self.postMessage('Start audio frame encode.');
   
   let encodedFrameCount = 0;
+  // フレームは config を書き終えてから送る
+  let configSent = Promise.resolve();
   let encoder = new AudioEncoder({
-      output: (chunk) => {
+      output: (chunk, metadata) => {
         // 1フレーム送信する (分割・結合はQUICにお任せする)
         if (stopped) {
           return;
         }
+        // デコーダ設定が変わったらフレームより先に送る
+        if (metadata && metadata.decoderConfig) {
+          const config = withTrack(track_audio, encodeDecoderConfig(metadata.decoderConfig, chunk.timestamp));
+          configSent = configSent.then(() => sendBinaryData(wt_audio, config))
+            .catch((e) => self.postMessage('config send failed. ' + e));
+        }
 
         // header(17) = type(1byte) + timestamp(8) + duration(8)
         let payload = new ArrayBuffer(17 + chunk.byteLength);
@@ -128,7 +177,8 @@ async function streamAudio(audio) {
         chunk.copyTo(new DataView(payload, 17));
 
         // フレームを送信する
-        sendBinaryData(wt_audio, payload);
+        const data = withTrack(track_audio, payload);
+        configSent.then(() => sendBinaryData(wt_audio, data));
 
         if (encodedFrameCount++ % 30 == 0) {
           self.postMessage(`Audio Encode 30 frames and send chunk. ${frameCount - encodedFrameCount} size ${chunk.byteLength} ${chunk.timestamp} ${chunk.duration}`)
@@ -171,6 +221,40 @@ async function streamAudio(audio) {
   wt_audio.close();
 }
 
+// デコーダ設定を config チャンク (type 3) にする。
+// header(17) + json length(2) + json + description
+function encodeDecoderConfig(config, timestamp) {
+  let description = new Uint8Array(0);
+  if (config.description) {
+    description = ArrayBuffer.isView(config.description)
+      ? new Uint8Array(config.description.buffer, config.description.byteOffset, config.description.byteLength)
+      : new Uint8Array(config.description);
+  }
+  const {description: _, ...rest} = config;
+  const json = new TextEncoder().encode(JSON.stringify(rest));
+
+  let payload = new ArrayBuffer(17 + 2 + json.byteLength + description.byteLength);
+  const view = new DataView(payload);
+  view.setUint8(0, 3);
+  view.setBigInt64(1, BigInt(timestamp));
+  view.setBigUint64(9, 0n);
+  view.setUint16(17, json.byteLength);
+  new Uint8Array(payload, 19).set(json);
+  new Uint8Array(payload, 19 + json.byteLength).set(description);
+  return payload;
+}
+
+// track id (サイマルキャストでは layer id) があれば先頭に付ける
+function withTrack(track, payload) {
+  if (track === null) {
+    return payload;
+  }
+  const data = new Uint8Array(1 + payload.byteLength);
+  data[0] = track;
+  data.set(new Uint8Array(payload), 1);
+  return data.buffer;
+}
+
 // バイナリデータを送信する
 async function sendBinaryData(transport, data) {
   let stream = await transport.createUnidirectionalStream();
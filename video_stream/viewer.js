@@ -58,7 +58,18 @@ async function connect() {
         stream: audioStream,
       },
     };
-    viewerWorker.postMessage({type: "connect", url, media}, [frameStream, audioStream]);
+    const vod = document.getElementById('vod').value;
+    const offset = Number(document.getElementById('offset').value) * 1000;
+    const combined = document.getElementById('combined').checked;
+    const datagram = document.getElementById('datagram').checked;
+    // 空ならサーバが回線に合わせてレイヤーを選ぶ
+    const layer = document.getElementById('layer').value.trim() || 'auto';
+    viewerWorker.postMessage({type: "connect", url, media, vod, offset, combined, datagram, layer}, [frameStream, audioStream]);
+    if (vod) {
+      setUIVod();
+    } else if (!combined && !datagram) {
+      setUILayer(layer);
+    }
 
     // ストリームをビデオタグに設定する
     const stream = new MediaStream();
@@ -76,6 +87,10 @@ async function connect() {
     command: "enter",
     name: document.getElementById('name').value,
   });
+  // 部屋の参加者一覧を要求する。以降は差分が届く
+  sendTextData(wt_chat, {
+    command: "roster",
+  });
 
   // set send comment button.
   document.getElementById('send').onclick = async function sendComment() {
@@ -131,24 +146,37 @@ async function acceptUnidirectionalStreams(transport) {
   }
 }
 
+// Rustサーバーはトピックのメッセージ (topic_len(1) + topic + json) で送ってくる
+function stripTopic(bytes) {
+  const len = bytes[0];
+  const topic = new TextDecoder('utf-8').decode(bytes.subarray(1, 1 + len));
+  if (topic.startsWith('presence/') || topic.startsWith('chat/')) {
+    return bytes.subarray(1 + len);
+  }
+  return bytes;
+}
+
 // データを読み込む
 async function readFromIncomingStream(stream) {
-  let decoder = new TextDecoderStream('utf-8');
-  let reader = stream.pipeThrough(decoder).getReader();
+  let reader = stream.getReader();
   try {
     let buffer = new Uint8Array();
     while (true) {
       const { value, done } = await reader.read();
       if (done) {
-        // ここではvalueは空になる
+        // ここではvalueはundefinedになる
         //
         // jsonでデータをやり取りする
-        let data = JSON.parse(buffer);
-        addCommentViewer(data);
+        let text = new TextDecoder('utf-8').decode(stripTopic(buffer));
+        addToEventLog('Received data on stream : ' + text);
+        let data = JSON.parse(text);
+        handleChatMessage(data);
         return;
       }
-      buffer += value;
-      addToEventLog('Received data on stream : ' + value);
+      let joined = new Uint8Array(buffer.byteLength + value.byteLength);
+      joined.set(buffer, 0);
+      joined.set(new Uint8Array(value), buffer.byteLength);
+      buffer = joined;
     }
   } catch (e) {
     addToEventLog(
@@ -174,6 +202,38 @@ function addToEventLog(text, severity = 'info') {
     entry.scrollIntoView();
   }
 }
+// 部屋の参加者 (id -> {id, name, role, joined})
+const roster = new Map();
+
+// コメント以外に参加者一覧 (roster) と入退室・名前変更の差分 (presence) が届く
+function handleChatMessage(data) {
+  if (data.command === 'roster') {
+    roster.clear();
+    data.members.forEach(member => roster.set(member.id, member));
+    renderRoster();
+  } else if (data.command === 'presence') {
+    if (data.event === 'leave') {
+      roster.delete(data.member.id);
+    } else {
+      roster.set(data.member.id, data.member);
+    }
+    renderRoster();
+  } else {
+    addCommentViewer(data);
+  }
+}
+
+function renderRoster() {
+  let list = document.getElementById('roster');
+  list.innerHTML = '';
+  for (const member of roster.values()) {
+    let entry = document.createElement('li');
+    const joined = new Date(member.joined).toLocaleTimeString();
+    entry.innerText = (member.name || '(' + member.id + ')') + ' : ' + member.role + ' ' + joined;
+    list.appendChild(entry);
+  }
+}
+
 function addCommentViewer(data) {
   let comments = document.getElementById('comment-viewer');
   let mostRecentEntry = comments.lastElementChild;
@@ -201,6 +261,29 @@ function addCommentViewer(data) {
     entry.scrollIntoView();
   }
 }
+// 録画再生の操作ボタン
+function setUIVod() {
+  document.getElementById('vod-panel').style.display = 'block';
+  document.getElementById('vod-pause').onclick = () => {
+    viewerWorker.postMessage({ type: "vod", command: "pause" });
+  };
+  document.getElementById('vod-play').onclick = () => {
+    viewerWorker.postMessage({ type: "vod", command: "play" });
+  };
+  document.getElementById('vod-seek').onclick = () => {
+    const ms = Number(document.getElementById('vod-seek-to').value) * 1000;
+    viewerWorker.postMessage({ type: "vod", command: `seek ${ms}` });
+  };
+}
+// サイマルキャストのレイヤー切り替え
+function setUILayer(layer) {
+  document.getElementById('layer-panel').style.display = 'block';
+  document.getElementById('layer-switch-to').value = layer;
+  document.getElementById('layer-switch').onclick = () => {
+    const layer = document.getElementById('layer-switch-to').value.trim() || 'auto';
+    viewerWorker.postMessage({ type: "layer", layer });
+  };
+}
 function setUIConnected() {
   document.getElementById('connection-panel').style.display = 'none';
   document.getElementById('comment-panel').style.display = 'block';
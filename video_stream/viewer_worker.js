@@ -2,6 +2,8 @@ let stopped = false;
 let wait_keyframe = true;
 let wt_video = null, frameWriter = null;
 let wt_audio = null, audioWriter = null;
+let controls = [];
+let layerControl = null;
 
 self.addEventListener('message', async (e) => {
   const type = e.data.type;
@@ -11,12 +13,48 @@ self.addEventListener('message', async (e) => {
 
     stopped = false;
     wait_keyframe = true;
-    const {media: {video, audio}, url} = e.data;
+    const {media: {video, audio}, url, vod, offset, combined, datagram, layer} = e.data;
 
-    wt_video = new WebTransport(url + '/video/view');
-    wt_audio = new WebTransport(url + '/audio/view');
+    if (combined && !vod) {
+      // 映像と音声を1つのセッションで受け取る
+      wt_video = wt_audio = new WebTransport(url + '/av/view');
+      await wt_video.ready;
+      wt_video.closed.then(() => {
+          self.postMessage('av Connection closed normally.');
+        })
+        .catch(() => {
+          self.postMessage('av Connection closed abruptl.');
+        });
+      acceptUnidirectionalStreams(wt_video, demuxTracks(streamVideo(video), streamAudio(audio)));
+      return;
+    }
+
+    if (datagram && !vod) {
+      // ライブを datagram で受け取る (既定のレイヤーのみ)
+      wt_video = new WebTransport(url + '/video/datagram');
+      wt_audio = new WebTransport(url + '/audio/datagram');
+      await wt_video.ready;
+      await wt_audio.ready;
+      readDatagrams(wt_video, 'video', streamVideo(video));
+      readDatagrams(wt_audio, 'audio', streamAudio(audio));
+      return;
+    }
+
+    // 録画を再生するときは vod と再生開始位置を指定する
+    // ライブではサイマルキャストのレイヤーを選べる (0 が最高画質、auto はサーバ任せ)
+    const query = vod ? `?vod=${encodeURIComponent(vod)}&offset=${offset}` : `?layer=${layer}`;
+    wt_video = new WebTransport(url + '/video/view' + query);
+    wt_audio = new WebTransport(url + '/audio/view' + (vod ? query : ''));
     await wt_video.ready;
     await wt_audio.ready;
+    if (vod) {
+      controls = [
+        await openControlStream(wt_video, 'video vod'),
+        await openControlStream(wt_audio, 'audio vod'),
+      ];
+    } else {
+      layerControl = await openControlStream(wt_video, 'video');
+    }
     wt_video.closed.then(() => {
         self.postMessage('video Connection closed normally.');
       })
@@ -30,25 +68,70 @@ self.addEventListener('message', async (e) => {
         self.postMessage('audio Connection closed abruptl.');
       });
 
-    streamVideo(video);
-    streamAudio(audio);
+    acceptUnidirectionalStreams(wt_video, streamVideo(video));
+    acceptUnidirectionalStreams(wt_audio, streamAudio(audio));
 
     return;
   }
+  if (type === "vod") {
+    // pause / play / seek {ms} を映像と音声の両方に送る
+    if (e.data.command.startsWith("seek")) {
+      wait_keyframe = true;
+    }
+    const line = new TextEncoder().encode(e.data.command + "\n");
+    for (const writer of controls) {
+      await writer.write(line);
+    }
+    return;
+  }
+  if (type === "layer") {
+    // 切り替え先のレイヤーのキーフレームからサーバが送り始める
+    if (layerControl) {
+      await layerControl.write(new TextEncoder().encode(`layer ${e.data.layer}\n`));
+    }
+    return;
+  }
   if (type === "stop") {
     self.postMessage('Stop video frame receive.');
     stopped = true;
     frameWriter.close();
     audioWriter.close();
     wt_video.close();
+    if (wt_audio !== wt_video) {
+      wt_audio.close();
+    }
     return;
   }
 }, false)
 
 // 音声を取得してデコードする
 
+// track id(1byte) で映像・音声・時刻情報に振り分ける
+// 0 = clock, 1 = video, 2 = audio
+let clockCount = 0;
+function demuxTracks(onVideo, onAudio) {
+  return (payload) => {
+    const track = new DataView(payload).getUint8(0);
+    const chunk = payload.slice(1);
+    if (track === 1) {
+      onVideo(chunk);
+    } else if (track === 2) {
+      onAudio(chunk);
+    } else if (track === 0) {
+      // clock: timestamp = メディア時刻, payload = そのメディア時刻を受信した壁時計 (unix μs)
+      const view = new DataView(chunk);
+      const media = Number(view.getBigInt64(1));
+      const wall = Number(view.getBigUint64(17));
+      if (clockCount++ % 10 == 0) {
+        self.postMessage(`clock: media ${media} at ${new Date(wall / 1000).toISOString()}, delay ${(Date.now() - wall / 1000).toFixed(0)}ms`);
+      }
+    }
+  };
+}
+
 // ビデオを取得してデコードする
-async function streamVideo(video) {
+// 受信したペイロードを処理する関数を返す
+function streamVideo(video) {
 
     // デコーダーの準備
     frameWriter = video.stream.getWriter();
@@ -64,19 +147,26 @@ async function streamVideo(video) {
           self.postMessage(e)
         }
       });
+    // config チャンクが来るまでの既定値
     decoder.configure({
       codec: 'vp8', // これしか使えない
       optimizeForLatency: true,
     });
     
-    // ストリームを受け付ける
-    acceptUnidirectionalStreams(wt_video, async (payload) => {
+    return (payload) => {
       // 動画をフレームごとに受信する。
 
       // payloadからデータを復元する
       // header(17) = type(1byte) + timestamp(8) + duration(8)
       let view = new DataView(payload, 0);
       const type = view.getUint8(0);
+      if (type === 3) {
+        // デコーダ設定が変わったら次のkey frameから読み直す
+        decoder.configure({...decodeDecoderConfig(payload), optimizeForLatency: true});
+        wait_keyframe = true;
+        self.postMessage(`Received decoder config.`);
+        return;
+      }
       const chunk = new EncodedVideoChunk({
         type: (type === 1 ? 'key' : 'delta'),
         timestamp: Number(view.getBigInt64(1)), // 仕様では long long だが実際はNumber
@@ -95,9 +185,9 @@ async function streamVideo(video) {
       if (!wait_keyframe) {
         decoder.decode(chunk);
       }
-    });
+    };
 }
-async function streamAudio(audio) {
+function streamAudio(audio) {
 
     // デコーダーの準備
     audioWriter = audio.stream.getWriter();
@@ -119,14 +209,17 @@ async function streamAudio(audio) {
       sampleRate: 48000, // audioCtx.sampleRate,
     });
     
-    // ストリームを受け付ける
-    acceptUnidirectionalStreams(wt_audio, async (payload) => {
+    return (payload) => {
       // 音声をフレームごとに受信する。
 
       // payloadからデータを復元する
       // header(17) = type(1byte) + timestamp(8) + duration(8)
       let view = new DataView(payload, 0);
       const type = view.getUint8(0);
+      if (type === 3) {
+        decoder.configure(decodeDecoderConfig(payload));
+        return;
+      }
       const chunk = new EncodedAudioChunk({
         type: (type === 1 ? 'key' : 'delta'),
         timestamp: Number(view.getBigInt64(1)), // 仕様では long long だが実際はNumber
@@ -138,7 +231,192 @@ async function streamAudio(audio) {
         self.postMessage(`Received 30 audio. last frame = ${frameCount - decodedFrameCount}, ${chunk.type}, ${chunk.byteLength} ${chunk.timestamp} ${chunk.duration}`);
       }
       decoder.decode(chunk);
-    });
+    };
+}
+
+// config チャンク (type 3) からデコーダ設定を復元する
+// header(17) + json length(2) + json + description
+function decodeDecoderConfig(payload) {
+  const view = new DataView(payload, 17);
+  const length = view.getUint16(0);
+  const config = JSON.parse(new TextDecoder().decode(new Uint8Array(payload, 19, length)));
+  if (payload.byteLength > 19 + length) {
+    config.description = new Uint8Array(payload, 19 + length);
+  }
+  return config;
+}
+
+// 操作用ストリーム (録画再生・レイヤー切り替え) を開き、サーバからの応答をログに出す
+async function openControlStream(transport, name) {
+  const stream = await transport.createBidirectionalStream();
+  const reader = stream.readable.pipeThrough(new TextDecoderStream()).getReader();
+  (async () => {
+    try {
+      while (true) {
+        const { value, done } = await reader.read();
+        if (done) {
+          return;
+        }
+        self.postMessage(`${name}: ${value.trim()}`);
+      }
+    } catch (e) {
+      self.postMessage(`${name} control closed. ${e}`);
+    }
+  })();
+  return stream.writable.getWriter();
+}
+
+// datagram のフラグメントからチャンクを組み立てる (rs_server/src/datagram.rs と同じ形式)
+// frame(4) + frame_len(4) + index(2) + count(2) + group(1) + payload
+// index >= count はパリティ (group 個のフラグメントの XOR)。1グループで1つまでの欠落を復元できる
+// 欠けたフラグメントは NACK (frame(4) + n(1) + index(2) * n の並び) で再送を頼む
+class Reassembler {
+  constructor() {
+    this.frames = new Map();
+    this.done = [];
+    this.newest = -1;
+    this.recovered = 0;
+    this.lost = 0;
+  }
+
+  push(datagram) {
+    const view = new DataView(datagram.buffer, datagram.byteOffset, datagram.byteLength);
+    if (datagram.byteLength < 13) {
+      return null;
+    }
+    const id = view.getUint32(0), length = view.getUint32(4);
+    const index = view.getUint16(8), count = view.getUint16(10), group = view.getUint8(12);
+    const payload = datagram.subarray(13);
+    if (count === 0 || this.done.includes(id)) {
+      return null;
+    }
+    this.newest = Math.max(this.newest, id);
+    let frame = this.frames.get(id);
+    if (!frame) {
+      frame = {length, count, group, size: Math.ceil(length / count), fragments: new Array(count).fill(null), parity: new Map(), missing: count, last: 0, nacked: new Set()};
+      this.frames.set(id, frame);
+    }
+    if (index >= count) {
+      frame.parity.set(index - count, payload.slice());
+    } else if (frame.fragments[index] === null) {
+      frame.last = Math.max(frame.last, index);
+      frame.fragments[index] = payload.slice();
+      frame.missing--;
+    }
+    if (frame.missing > 0) {
+      this.recover(frame);
+    }
+    if (frame.missing > 0) {
+      // 古い不完全なフレームは諦める
+      while (this.frames.size > 32) {
+        this.frames.delete(this.frames.keys().next().value);
+        this.lost++;
+      }
+      return null;
+    }
+    this.frames.delete(id);
+    this.done.push(id);
+    if (this.done.length > 32) {
+      this.done.shift();
+    }
+    const out = new Uint8Array(frame.length);
+    frame.fragments.forEach((f, i) => out.set(f, i * frame.size));
+    return out.buffer;
+  }
+
+  // 後ろのフラグメントが届いたのに欠けているものを一度だけ NACK する
+  nack() {
+    const parts = [];
+    let size = 0;
+    for (const [id, frame] of this.frames) {
+      const end = id < this.newest ? frame.count : frame.last;
+      const missing = [];
+      for (let i = 0; i < end && missing.length < 255; i++) {
+        if (frame.fragments[i] === null && !frame.nacked.has(i)) {
+          missing.push(i);
+        }
+      }
+      if (missing.length === 0 || size + 5 + missing.length * 2 > 1200) {
+        continue;
+      }
+      const part = new Uint8Array(5 + missing.length * 2);
+      const view = new DataView(part.buffer);
+      view.setUint32(0, id);
+      view.setUint8(4, missing.length);
+      missing.forEach((i, n) => {
+        view.setUint16(5 + n * 2, i);
+        frame.nacked.add(i);
+      });
+      parts.push(part);
+      size += part.byteLength;
+    }
+    if (parts.length === 0) {
+      return null;
+    }
+    const out = new Uint8Array(size);
+    let pos = 0;
+    for (const part of parts) {
+      out.set(part, pos);
+      pos += part.byteLength;
+    }
+    return out;
+  }
+
+  recover(frame) {
+    for (const [n, parity] of frame.parity) {
+      const start = n * frame.group, end = Math.min(start + frame.group, frame.count);
+      const lost = [];
+      for (let i = start; i < end; i++) {
+        if (frame.fragments[i] === null) {
+          lost.push(i);
+        }
+      }
+      if (lost.length !== 1) {
+        continue;
+      }
+      const data = parity.slice();
+      for (let i = start; i < end; i++) {
+        const f = frame.fragments[i];
+        if (f !== null) {
+          f.forEach((b, j) => { data[j] ^= b; });
+        }
+      }
+      const i = lost[0];
+      const size = Math.min(frame.size, frame.length - i * frame.size);
+      frame.fragments[i] = data.subarray(0, size);
+      frame.missing--;
+      this.recovered++;
+    }
+  }
+}
+
+// datagram を受け取り、組み立てたチャンクを渡す
+async function readDatagrams(transport, name, onchunk) {
+  const reader = transport.datagrams.readable.getReader();
+  const writer = transport.datagrams.writable.getWriter();
+  const reassembler = new Reassembler();
+  let frames = 0;
+  try {
+    while (true) {
+      const { value, done } = await reader.read();
+      if (done) {
+        return;
+      }
+      const chunk = reassembler.push(value);
+      const nack = reassembler.nack();
+      if (nack) {
+        writer.write(nack).catch(() => {});
+      }
+      if (chunk) {
+        onchunk(chunk);
+        if (++frames % 300 === 0) {
+          self.postMessage(`${name} datagrams: ${frames} frames, ${reassembler.recovered} fragments recovered, ${reassembler.lost} frames lost`);
+        }
+      }
+    }
+  } catch (e) {
+    self.postMessage(`${name} datagrams closed. ${e}`);
+  }
 }
 
 // ストリームを受け付ける